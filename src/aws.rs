@@ -0,0 +1,12 @@
+use crate::config::Config;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::SdkConfig;
+use aws_types::region::Region;
+
+/// Loads the shared AWS SDK configuration used by every AWS client (S3, CloudWatch, ...).
+/// Uses the default provider chain and falls back to the configured region.
+pub async fn load_aws_config(config: &Config) -> SdkConfig {
+    let region_provider =
+        RegionProviderChain::default_provider().or_else(Region::new(config.aws_region.clone()));
+    aws_config::from_env().region(region_provider).load().await
+}
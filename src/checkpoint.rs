@@ -0,0 +1,219 @@
+use crate::ckan::ResourceUrls;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/// A fetched dataset as kept in the checkpoint, so a resumed run can write it again.
+pub type CheckpointRow = (DatasetMetadata, ResourceUrls);
+
+/// On-disk checkpoint format: the ids of datasets whose metadata has been fetched.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CheckpointFile {
+    completed: Vec<String>,
+}
+
+/// What an interrupted run left behind: the completed ids and the rows fetched for them.
+#[derive(Debug, Default)]
+pub struct SavedCheckpoint {
+    pub completed: HashSet<String>,
+    pub rows: Vec<CheckpointRow>,
+}
+
+/// Path of the file holding the fetched rows, one JSON object per line, next to the checkpoint.
+pub fn rows_path(path: &str) -> String {
+    format!("{path}.rows.ndjson")
+}
+
+/// Loads the completed dataset ids and their rows from a checkpoint.
+/// Returns an empty checkpoint if the file does not exist. Ids without a saved row
+/// (for example after a torn append) are dropped so the dataset is fetched again.
+pub fn load_checkpoint(path: &str) -> Result<SavedCheckpoint, AppError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(SavedCheckpoint::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let file: CheckpointFile = serde_json::from_str(&contents)?;
+    let ids: HashSet<String> = file.completed.into_iter().collect();
+    let rows_text = match std::fs::read_to_string(rows_path(path)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    // A row saved again after a failed save replaces the earlier copy.
+    let mut rows: HashMap<String, CheckpointRow> = HashMap::new();
+    for line in rows_text.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<CheckpointRow>(line) {
+            Ok(row) if ids.contains(&row.0.id) => {
+                rows.insert(row.0.id.clone(), row);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Skipping unreadable checkpoint row: {}", e),
+        }
+    }
+    Ok(SavedCheckpoint {
+        completed: rows.keys().cloned().collect(),
+        rows: rows.into_values().collect(),
+    })
+}
+
+/// Appends `new_rows` to the rows file, then writes the set of completed dataset ids. The ids
+/// file is written to a temporary path and renamed into place so an interrupted write never
+/// leaves a torn checkpoint, and it is only written once its rows are on disk.
+pub fn save_checkpoint(
+    path: &str,
+    completed: &HashSet<String>,
+    new_rows: &[CheckpointRow],
+) -> Result<(), AppError> {
+    if !new_rows.is_empty() {
+        let mut lines = Vec::new();
+        for row in new_rows {
+            serde_json::to_writer(&mut lines, row)?;
+            lines.push(b'\n');
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(rows_path(path))?
+            .write_all(&lines)?;
+    }
+    let mut ids: Vec<String> = completed.iter().cloned().collect();
+    ids.sort();
+    let tmp_path = format!("{path}.tmp");
+    std::fs::write(
+        &tmp_path,
+        serde_json::to_vec(&CheckpointFile { completed: ids })?,
+    )?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Tracks completed datasets during a run and saves them every `interval` completions,
+/// so a run that times out can be resumed with `RESUME=1`. Shared between fetch tasks.
+/// Only the in-memory state is updated under the lock; the files are written by a single
+/// blocking writer task, in the order the snapshots were taken.
+pub struct Checkpoint {
+    interval: usize,
+    path: String,
+    state: Mutex<CheckpointState>,
+    writer: Option<UnboundedSender<Snapshot>>,
+}
+
+struct CheckpointState {
+    completed: HashSet<String>,
+    unsaved: Vec<CheckpointRow>,
+}
+
+/// The state to write, handed from the fetch tasks to the writer task.
+struct Snapshot {
+    completed: HashSet<String>,
+    rows: Vec<CheckpointRow>,
+    /// Signalled once the snapshot (and every one before it) has been handled
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl Checkpoint {
+    /// Creates a checkpoint starting from the given completed ids.
+    /// An interval of zero disables saving; otherwise the writer task is started, so this must
+    /// be called inside the Tokio runtime.
+    pub fn new(path: &str, interval: usize, completed: HashSet<String>) -> Self {
+        let writer = (interval > 0).then(|| {
+            let (tx, rx) = unbounded_channel();
+            let path = path.to_string();
+            tokio::task::spawn_blocking(move || write_snapshots(&path, rx));
+            tx
+        });
+        Self {
+            interval,
+            path: path.to_string(),
+            state: Mutex::new(CheckpointState {
+                completed,
+                unsaved: Vec::new(),
+            }),
+            writer,
+        }
+    }
+
+    /// Returns true if the dataset was completed by a previous run.
+    pub fn is_completed(&self, id: &str) -> bool {
+        self.lock().completed.contains(id)
+    }
+
+    /// Records a completed dataset, saving the checkpoint once `interval` new rows have built up.
+    pub fn record(&self, row: &CheckpointRow) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let snapshot = {
+            let mut state = self.lock();
+            state.completed.insert(row.0.id.clone());
+            state.unsaved.push(row.clone());
+            if state.unsaved.len() < self.interval {
+                return;
+            }
+            Self::snapshot(&mut state, None)
+        };
+        // The writer only stops once every sender is gone, so this cannot fail.
+        let _ = writer.send(snapshot);
+    }
+
+    /// Saves any rows recorded since the last save, e.g. when a deadline or shutdown stops the
+    /// run, and waits until the writer has caught up.
+    pub async fn save(&self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let (done, written) = oneshot::channel();
+        let snapshot = Self::snapshot(&mut self.lock(), Some(done));
+        if writer.send(snapshot).is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Removes the checkpoint files once a run has finished, so the next run starts fresh.
+    pub fn clear(&self) {
+        for path in [self.path.clone(), rows_path(&self.path)] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => info!("Removed checkpoint {}", path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Could not remove checkpoint {}: {}", path, e),
+            }
+        }
+    }
+
+    fn snapshot(state: &mut CheckpointState, done: Option<oneshot::Sender<()>>) -> Snapshot {
+        Snapshot {
+            completed: state.completed.clone(),
+            rows: std::mem::take(&mut state.unsaved),
+            done,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CheckpointState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Writes each snapshot received until every sender is dropped. Save failures are logged
+/// rather than failing the run; their rows stay queued and go out with the next snapshot.
+fn write_snapshots(path: &str, mut rx: UnboundedReceiver<Snapshot>) {
+    let mut pending = Vec::new();
+    while let Some(snapshot) = rx.blocking_recv() {
+        pending.extend(snapshot.rows);
+        if !pending.is_empty() {
+            match save_checkpoint(path, &snapshot.completed, &pending) {
+                Ok(()) => pending.clear(),
+                Err(e) => warn!("Could not save checkpoint to {}: {}", path, e),
+            }
+        }
+        if let Some(done) = snapshot.done {
+            let _ = done.send(());
+        }
+    }
+}
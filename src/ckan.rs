@@ -1,34 +1,151 @@
-use crate::config::Config;
+use crate::config::{Config, DescriptionMode};
 use crate::error::AppError;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::Client;
-use serde::Deserialize;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, ETAG, IF_NONE_MATCH, RETRY_AFTER};
+use reqwest::{Client, NoProxy, Proxy, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, instrument, warn};
+
+/// How many times the dataset list request is retried when CKAN is unavailable or rate limiting.
+/// The list is fetched once per portal and the whole run depends on it, so it gets a few tries.
+const DATASET_LIST_MAX_RETRIES: usize = 3;
+
+/// How long to wait before retrying a request that failed with a transient error (see
+/// `is_transient`), before jitter.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 // Compile regex once and reuse it for HTML tag removal for performance.
-static HTML_TAG_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"<[^>]+>").expect("HTML tag regex should compile"));
+// Only sequences that look like real markup are matched: an opening or closing tag whose name
+// starts with a letter straight after `<` or `</`, a comment, or a declaration such as
+// `<!DOCTYPE html>`. Comparisons like `3 < 5 and x > 1` are left alone.
+static HTML_TAG_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?s)<!--.*?-->|<![a-zA-Z][^<>]*>|</?[a-zA-Z][a-zA-Z0-9-]*(?:\s[^<>]*)?/?>")
+        .expect("HTML tag regex should compile")
+});
+
+// Block-level tags that mark a line break, replaced with a newline before stripping other tags.
+static BLOCK_TAG_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)</p\s*>|<br\s*/?>|</div\s*>|</li\s*>").expect("block tag regex should compile")
+});
+
+// HTML character references: named (`&amp;`), decimal (`&#39;`) or hexadecimal (`&#x27;`).
+static HTML_ENTITY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);")
+        .expect("HTML entity regex should compile")
+});
+
+// Any run of whitespace, collapsed to a single space by `normalize_whitespace`.
+static WHITESPACE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\s+").expect("whitespace regex should compile"));
+
+/// Removes HTML tags from a CKAN description, turning paragraph, line-break, div and list-item
+/// boundaries into newlines so adjacent blocks don't run together.
+pub fn clean_html(html: &str) -> String {
+    let with_breaks = BLOCK_TAG_REGEX.replace_all(html, "\n");
+    HTML_TAG_REGEX
+        .replace_all(&with_breaks, "")
+        .trim()
+        .to_string()
+}
+
+/// Decodes HTML character references: numeric ones and the common named entities.
+/// Unknown named entities are left as they are.
+pub fn decode_html_entities(text: &str) -> String {
+    HTML_ENTITY_REGEX
+        .replace_all(text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity.strip_prefix('#').and_then(|num| {
+                    let code = match num.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => num.parse().ok()?,
+                    };
+                    char::from_u32(code)
+                }),
+            };
+            decoded.map_or_else(|| caps[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// Trims `text` and collapses every internal run of whitespace (including newlines and tabs)
+/// to a single space.
+pub fn normalize_whitespace(text: &str) -> String {
+    WHITESPACE_REGEX.replace_all(text, " ").trim().to_string()
+}
+
+/// Transforms a CKAN description according to the configured `DescriptionMode`.
+pub fn clean_description(notes: &str, mode: DescriptionMode) -> String {
+    match mode {
+        DescriptionMode::Raw => notes.to_string(),
+        DescriptionMode::Strip => clean_html(notes),
+        DescriptionMode::Plain => normalize_whitespace(&decode_html_entities(&clean_html(notes))),
+    }
+}
 
 /// Response from the CKAN package_list API.
 #[derive(Debug, Deserialize)]
 pub struct PackageListResponse {
+    /// Whether CKAN handled the request (treated as true if missing)
+    #[serde(default = "default_true")]
+    pub success: bool,
+    /// CKAN's error details when `success` is false
+    pub error: Option<serde_json::Value>,
     /// List of dataset IDs returned by the CKAN API.
+    #[serde(default)]
     pub result: Vec<String>,
 }
 
 /// Response from the CKAN package_show API.
 #[derive(Debug, Deserialize)]
 pub struct PackageShowResponse {
+    /// Whether CKAN handled the request (treated as true if missing)
+    #[serde(default = "default_true")]
+    pub success: bool,
+    /// CKAN's error details when `success` is false
+    pub error: Option<serde_json::Value>,
     /// The dataset metadata, or None if not found.
     pub result: Option<CkanDataset>,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+/// Turns a CKAN response with `success: false` into an `AppError::Ckan`, logging CKAN's
+/// error object, so API errors aren't mistaken for an empty result.
+fn check_ckan_success(
+    action: &str,
+    success: bool,
+    error: Option<&serde_json::Value>,
+) -> Result<(), AppError> {
+    if success {
+        return Ok(());
+    }
+    let details = error.map_or_else(|| "no error details".to_string(), |e| e.to_string());
+    error!("CKAN {} reported success=false: {}", action, details);
+    Err(AppError::Ckan(format!("{action} failed: {details}")))
+}
+
 /// Strongly-typed struct for CKAN dataset metadata.
 #[derive(Debug, Deserialize)]
 pub struct CkanDataset {
     /// Dataset ID
     pub id: String,
+    /// URL slug of the dataset (empty if missing)
+    #[serde(default)]
+    pub name: String,
     /// Dataset title
     pub title: String,
     /// Dataset description (may contain HTML)
@@ -43,6 +160,40 @@ pub struct CkanDataset {
     pub metadata_modified: String,
     /// List of resources (files, links, etc.)
     pub resources: Vec<CkanResource>,
+    /// Number of resources CKAN reports for the dataset
+    pub num_resources: Option<u32>,
+    /// Author name
+    pub author: Option<String>,
+    /// Author contact email
+    pub author_email: Option<String>,
+    /// Maintainer name
+    pub maintainer: Option<String>,
+    /// Maintainer contact email
+    pub maintainer_email: Option<String>,
+    /// Tags attached to the dataset (missing or null is treated as no tags)
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub tags: Vec<CkanTag>,
+    /// Groups (themes) the dataset belongs to (missing or null is treated as no groups)
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub groups: Vec<CkanGroup>,
+    /// Whether the dataset is private to its organisation
+    pub private: Option<bool>,
+    /// Lifecycle state: `active`, `draft` or `deleted`
+    pub state: Option<String>,
+}
+
+/// Tag attached to a CKAN dataset.
+#[derive(Debug, Deserialize)]
+pub struct CkanTag {
+    /// Tag name
+    pub name: String,
+}
+
+/// Group (theme) a CKAN dataset belongs to, e.g. "Health" or "Transport".
+#[derive(Debug, Deserialize)]
+pub struct CkanGroup {
+    /// Group title
+    pub title: String,
 }
 
 /// Organisation info for a CKAN dataset.
@@ -59,97 +210,714 @@ pub struct CkanResource {
     pub format: Option<String>,
     /// Download URL
     pub url: Option<String>,
+    /// Last modification timestamp of the resource
+    pub last_modified: Option<String>,
+    /// Resource size in bytes (CKAN returns this as either a number or a string)
+    #[serde(default, deserialize_with = "deserialize_optional_string_or_number")]
+    pub size: Option<String>,
+    /// MIME type of the resource, e.g. `text/csv`
+    pub mimetype: Option<String>,
 }
 
-/// Extracts resource formats as a comma-separated string and URLs as a Vec<String> from a CKAN dataset.
+/// Deserialises a JSON string or number (or null) into an `Option<String>`.
+/// CKAN portals are inconsistent about the type of numeric fields such as `size`.
+fn deserialize_optional_string_or_number<'de, D>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(s)) => Some(s),
+            Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        },
+    )
+}
+
+/// Deserialises a JSON null as the type's default value (e.g. an empty Vec).
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Download URLs for a dataset, with the size and last-modified value of each resource
+/// held in parallel vectors (empty strings where CKAN has no value).
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUrls {
+    /// Download URLs
+    pub urls: Vec<String>,
+    /// Resource sizes in bytes, one per URL
+    pub sizes: Vec<String>,
+    /// Resource last-modified timestamps, one per URL
+    pub last_modified: Vec<String>,
+    /// HTTP status of each URL from a HEAD check, or `error` if the check failed
+    /// (empty unless `VALIDATE_URLS` is enabled)
+    pub statuses: Vec<String>,
+    /// Format of every resource that has one, as CKAN reports it and not limited to the URLs
+    /// kept above; used for the run's per-resource format counts
+    pub formats: Vec<String>,
+}
+
+/// Per-dataset data for the companion outputs, carried on `DatasetMetadata` but never written
+/// as output columns.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DatasetExtras {
+    /// Every resource that has a URL, in CKAN order and regardless of `URL_FORMAT_FILTER` or
+    /// `INCLUDE_URLS`; written to the resources file when `WRITE_RESOURCES` is set
+    pub resources: Vec<ResourceDetail>,
+    /// The unparsed CKAN `result` object, captured only when `DUMP_RAW_JSON` is set
+    pub raw_json: Option<serde_json::Value>,
+}
+
+/// One resource's row in the resources file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceDetail {
+    /// Download URL
+    pub url: String,
+    /// File format as CKAN reports it
+    pub format: String,
+    /// Size in bytes
+    pub size: String,
+    /// Last modification timestamp
+    pub last_modified: String,
+    /// MIME type
+    pub mimetype: String,
+}
+
+impl ResourceUrls {
+    /// Returns true if any resource carries a size or last-modified value.
+    pub fn has_details(&self) -> bool {
+        self.sizes.iter().any(|s| !s.is_empty()) || self.last_modified.iter().any(|m| !m.is_empty())
+    }
+
+    /// Returns true if the URLs have been checked and carry a status.
+    pub fn has_statuses(&self) -> bool {
+        !self.statuses.is_empty()
+    }
+}
+
+/// Extracts resource formats as a comma-separated string and the download URLs (with their
+/// sizes and last-modified timestamps) from a CKAN dataset.
+/// Duplicate URLs are dropped, keeping the first occurrence; formats are kept as-is.
+/// When `format_filter` is non-empty, only URLs of resources whose format is in the list
+/// (case-insensitive) are kept; the formats string still lists every resource's format.
+/// When `include_urls` is false no URLs are collected at all.
+/// When `dedupe_formats` is true the formats string lists each format once, uppercased and
+/// sorted (`CSV, JSON`); otherwise every resource's format is listed as CKAN reports it.
 /// This is used to flatten the resource info for CSV output.
-pub fn extract_resource_formats_and_urls(dataset: &CkanDataset) -> (String, Vec<String>) {
-    let formats = dataset
+pub fn extract_resource_formats_and_urls(
+    dataset: &CkanDataset,
+    format_filter: &[String],
+    include_urls: bool,
+    dedupe_formats: bool,
+) -> (String, ResourceUrls) {
+    let raw_formats = dataset
         .resources
         .iter()
-        .filter_map(|res| res.format.as_deref())
-        .collect::<Vec<&str>>()
-        .join(", ");
-    let urls = dataset
+        .filter_map(|res| res.format.as_deref());
+    let formats = if dedupe_formats {
+        raw_formats
+            .clone()
+            .map(|format| format.trim().to_uppercase())
+            .filter(|format| !format.is_empty())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>()
+            .join(", ")
+    } else {
+        raw_formats.clone().collect::<Vec<&str>>().join(", ")
+    };
+    let mut resource_urls = ResourceUrls {
+        formats: raw_formats.map(String::from).collect(),
+        ..Default::default()
+    };
+    if !include_urls {
+        return (formats, resource_urls);
+    }
+    let mut seen = HashSet::new();
+    let format_allowed = |res: &CkanResource| {
+        format_filter.is_empty()
+            || res.format.as_deref().is_some_and(|format| {
+                format_filter
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(format.trim()))
+            })
+    };
+    for res in dataset.resources.iter().filter(|res| format_allowed(res)) {
+        if let Some(url) = res.url.as_ref().filter(|url| seen.insert(url.as_str())) {
+            resource_urls.urls.push(url.clone());
+            resource_urls
+                .sizes
+                .push(res.size.clone().unwrap_or_default());
+            resource_urls
+                .last_modified
+                .push(res.last_modified.clone().unwrap_or_default());
+        }
+    }
+    (formats, resource_urls)
+}
+
+/// Returns every resource of a CKAN dataset that has a URL, in CKAN order, for the resources
+/// file. Unlike the download URLs, these are not filtered or deduplicated.
+pub fn resource_details(dataset: &CkanDataset) -> Vec<ResourceDetail> {
+    dataset
         .resources
         .iter()
-        .filter_map(|res| res.url.clone())
-        .collect::<Vec<String>>();
-    (formats, urls)
+        .filter_map(|res| {
+            Some(ResourceDetail {
+                url: res.url.clone()?,
+                format: res.format.clone().unwrap_or_default(),
+                size: res.size.clone().unwrap_or_default(),
+                last_modified: res.last_modified.clone().unwrap_or_default(),
+                mimetype: res.mimetype.clone().unwrap_or_default(),
+            })
+        })
+        .collect()
 }
 
 /// Creates an optimised HTTP client with connection pooling and timeouts for efficient API access.
+/// Pool size, idle timeout and connect timeout come from the config so they can be tuned per
+/// CKAN deployment.
+/// If a CKAN API key is configured it is sent as the `Authorization` header on every request.
+/// Every request identifies the client with the configured `User-Agent`.
+/// Requests go through `CKAN_PROXY_URL` when it is set (hosts in `NO_PROXY` bypass it);
+/// otherwise reqwest picks up `HTTP_PROXY`/`HTTPS_PROXY` from the environment.
+/// Requests advertise `Accept-Encoding: gzip` and gzipped responses are decoded transparently
+/// (their size is then counted after decompression in `bytes_downloaded`).
 pub fn create_http_client(config: &Config) -> Result<Client, AppError> {
-    Ok(Client::builder()
-        .pool_max_idle_per_host(10) // Increased from 5 for better concurrency
-        .pool_idle_timeout(std::time::Duration::from_secs(90)) // Keep connections alive longer
+    let mut headers = HeaderMap::new();
+    if let Some(api_key) = &config.ckan_api_key {
+        let mut value = HeaderValue::from_str(api_key).map_err(|_| {
+            AppError::Config("CKAN API key contains invalid header characters".to_string())
+        })?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    let user_agent = HeaderValue::from_str(&config.user_agent).map_err(|_| {
+        AppError::Config("USER_AGENT contains invalid header characters".to_string())
+    })?;
+    let mut builder = Client::builder()
+        .default_headers(headers)
+        .user_agent(user_agent)
+        .pool_max_idle_per_host(config.http_pool_max_idle)
+        .pool_idle_timeout(std::time::Duration::from_secs(
+            config.http_pool_idle_timeout_secs,
+        ))
         .timeout(std::time::Duration::from_secs(config.http_timeout_secs)) // Configurable timeout
-        .connect_timeout(std::time::Duration::from_secs(10)) // Add connection timeout
+        .connect_timeout(std::time::Duration::from_secs(
+            config.http_connect_timeout_secs,
+        ))
         .tcp_keepalive(Some(std::time::Duration::from_secs(60))) // Enable TCP keepalive
-        .build()?)
+        .gzip(true);
+    if let Some(proxy_url) = &config.ckan_proxy_url {
+        let proxy = Proxy::all(proxy_url.as_str())
+            .map_err(|e| AppError::Config(format!("CKAN_PROXY_URL is not a valid proxy URL: {e}")))?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build()?)
+}
+
+/// Returns how long to wait before retrying, based on a `Retry-After` header (either a number of
+/// seconds or an HTTP date). Defaults to one second if the header is missing or unparseable,
+/// and never exceeds `max_wait`.
+pub fn retry_after_delay(headers: &HeaderMap, max_wait: Duration) -> Duration {
+    let value = headers.get(RETRY_AFTER).and_then(|v| v.to_str().ok());
+    let delay = value
+        .and_then(|v| v.trim().parse::<u64>().ok().map(Duration::from_secs))
+        .or_else(|| {
+            let date = chrono::DateTime::parse_from_rfc2822(value?.trim()).ok()?;
+            (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .ok()
+        })
+        .unwrap_or(Duration::from_secs(1));
+    delay.min(max_wait)
+}
+
+/// Returns true if a failed request is worth retrying: timeouts, connection failures (DNS,
+/// refused, reset) and 5xx or 429 statuses. Deterministic failures such as a body that does
+/// not decode, a redirect loop, an invalid request or a 4xx status are not retried.
+pub fn is_transient(error: &reqwest::Error) -> bool {
+    if error.is_timeout() || error.is_connect() {
+        return true;
+    }
+    if error.is_decode() || error.is_builder() || error.is_redirect() {
+        return false;
+    }
+    error
+        .status()
+        .is_some_and(|status| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Adds up to 10% random jitter to `delay`, so runs retrying against the same portal spread out
+/// instead of all coming back at the moment `Retry-After` expires.
+fn with_jitter(delay: Duration) -> Duration {
+    let max_jitter_ms = delay.as_millis() as u64 / 10 + 1;
+    delay + Duration::from_millis(random_u64() % max_jitter_ms)
+}
+
+/// Returns a random delay of 0 to `max_ms` milliseconds (inclusive), so scheduled copies of the
+/// job started at the same moment don't all hit CKAN at once.
+pub fn startup_jitter(max_ms: u64) -> Duration {
+    if max_ms == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(random_u64() % (max_ms + 1))
+}
+
+/// A random number, good enough for spreading requests out (not for anything security related).
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    // `RandomState` is seeded randomly, which is plenty for spreading retries.
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// Truncates `text` to at most `max_chars` characters (not bytes, so multi-byte characters are
+/// never split), appending an ellipsis when anything was cut off.
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((byte_index, _)) => format!("{}…", &text[..byte_index]),
+        None => text.to_string(),
+    }
+}
+
+/// Converts a CKAN dataset into the flattened metadata written to the output, plus its
+/// download URLs. The `source` and `landing_page` columns are left empty for the caller to
+/// fill in. Transforms the description according to `description_mode` and joins tag names
+/// and group titles into comma-separated lists.
+pub fn build_dataset_metadata(
+    dataset: &CkanDataset,
+    format_filter: &[String],
+    include_urls: bool,
+    dedupe_formats: bool,
+    description_mode: DescriptionMode,
+) -> (crate::DatasetMetadata, ResourceUrls) {
+    let (formats, urls) =
+        extract_resource_formats_and_urls(dataset, format_filter, include_urls, dedupe_formats);
+    let tags = dataset
+        .tags
+        .iter()
+        .map(|tag| tag.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let groups = dataset
+        .groups
+        .iter()
+        .map(|group| group.title.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    (
+        crate::DatasetMetadata {
+            id: dataset.id.clone(),
+            title: dataset.title.clone(),
+            description: clean_description(&dataset.notes, description_mode),
+            license: dataset.license_title.clone(),
+            organization: dataset.organization.title.clone(),
+            created: dataset.metadata_created.clone(),
+            modified: dataset.metadata_modified.clone(),
+            format: formats,
+            tags,
+            groups,
+            author: dataset.author.clone().unwrap_or_default(),
+            author_email: dataset.author_email.clone().unwrap_or_default(),
+            maintainer: dataset.maintainer.clone().unwrap_or_default(),
+            maintainer_email: dataset.maintainer_email.clone().unwrap_or_default(),
+            source: String::new(),
+            landing_page: String::new(),
+            resource_count: dataset.resources.len(),
+            state: dataset.state.clone().unwrap_or_default(),
+            private: dataset.private.unwrap_or(false),
+            extras: DatasetExtras {
+                resources: resource_details(dataset),
+                raw_json: None,
+            },
+        },
+        urls,
+    )
+}
+
+/// Checks that CKAN is reachable before the full run by requesting a one-item package list.
+/// Fails fast with a configuration error naming the URL if the request fails or is not 2xx.
+pub async fn preflight(client: &Client, config: &Config) -> Result<(), AppError> {
+    let url = config.dataset_list_url();
+    let started = Instant::now();
+    let response = client
+        .get(&url)
+        .query(&[("limit", "1")])
+        .timeout(std::time::Duration::from_secs(config.http_timeout_secs))
+        .send()
+        .await
+        .map_err(|e| AppError::Config(format!("CKAN preflight to {url} failed: {e}")))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(AppError::Config(format!(
+            "CKAN preflight to {url} returned {status}"
+        )));
+    }
+    info!(
+        "CKAN preflight to {} succeeded in {} ms",
+        url,
+        started.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Caps the total number of CKAN request retries across a whole run, so a widespread outage
+/// doesn't turn into a retry storm. Shared between fetch tasks.
+pub struct RetryBudget {
+    /// Retries left, or None for no cap
+    remaining: Option<AtomicUsize>,
+}
+
+impl RetryBudget {
+    /// Creates a budget of `total` retries, or an unlimited one if `total` is None.
+    pub fn new(total: Option<usize>) -> Self {
+        Self {
+            remaining: total.map(AtomicUsize::new),
+        }
+    }
+
+    /// Takes one retry from the budget. Returns false once the budget is used up.
+    pub fn try_acquire(&self) -> bool {
+        match &self.remaining {
+            None => true,
+            Some(remaining) => remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok(),
+        }
+    }
+}
+
+/// Adds a response body's size to the shared download counter: the `Content-Length` header
+/// when the server sent one, otherwise the number of bytes actually read.
+fn record_bytes_downloaded(
+    bytes_downloaded: &AtomicU64,
+    content_length: Option<u64>,
+    body_len: usize,
+) {
+    bytes_downloaded.fetch_add(content_length.unwrap_or(body_len as u64), Ordering::Relaxed);
 }
 
 /// Fetches the list of dataset IDs from the CKAN API.
-/// Returns a truncated list if test_mode is enabled.
+/// Returns a truncated list if test_mode is enabled. The response size is added to
+/// `bytes_downloaded`. A 503 or 429 response is retried up to `DATASET_LIST_MAX_RETRIES`
+/// times, waiting for the `Retry-After` delay (capped, with jitter) between attempts.
+/// With `INPUT_IDS_FILE` set, the ids are read from that file instead and CKAN is not called.
 pub async fn fetch_dataset_list(
     client: &Client,
     config: &Config,
     test_mode: bool,
+    bytes_downloaded: &AtomicU64,
 ) -> Result<Vec<String>, AppError> {
-    let response = client
-        .get(config.dataset_list_url())
-        .timeout(std::time::Duration::from_secs(config.http_timeout_secs))
-        .send()
-        .await?;
-    let package_list: PackageListResponse = response.json().await?;
-    Ok(if test_mode {
-        package_list
-            .result
-            .into_iter()
-            .take(config.test_mode_dataset_limit)
-            .collect()
-    } else {
-        package_list.result
+    match fetch_dataset_list_if_changed(client, config, test_mode, bytes_downloaded, None).await? {
+        DatasetList::Changed { ids, .. } => Ok(ids),
+        // Only possible when an ETag was sent.
+        DatasetList::NotModified => Ok(Vec::new()),
+    }
+}
+
+/// Result of a conditional dataset list fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatasetList {
+    /// CKAN answered 304: the list still matches the ETag sent
+    NotModified,
+    /// The dataset ids, with the ETag CKAN returned for them (if any)
+    Changed {
+        ids: Vec<String>,
+        etag: Option<String>,
+    },
+}
+
+/// Like `fetch_dataset_list`, but sends `If-None-Match: etag` when an ETag from a previous run
+/// is given, and returns `DatasetList::NotModified` if CKAN answers 304.
+#[instrument(name = "fetch_dataset_list", skip_all, fields(phase = "list"))]
+pub async fn fetch_dataset_list_if_changed(
+    client: &Client,
+    config: &Config,
+    test_mode: bool,
+    bytes_downloaded: &AtomicU64,
+    etag: Option<&str>,
+) -> Result<DatasetList, AppError> {
+    if let Some(path) = &config.input_ids_file {
+        let text = tokio::fs::read_to_string(path).await?;
+        info!(
+            "Reading dataset ids from {} instead of the package list",
+            path
+        );
+        return Ok(DatasetList::Changed {
+            ids: parse_input_ids(&text, config, test_mode),
+            etag: None,
+        });
+    }
+    let send = || {
+        let mut request = client
+            .get(config.dataset_list_url())
+            .timeout(std::time::Duration::from_secs(config.http_timeout_secs));
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        request.send()
+    };
+    let mut retries = 0;
+    let response = loop {
+        let delay = match send().await {
+            Ok(response)
+                if matches!(
+                    response.status(),
+                    StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+                ) && retries < DATASET_LIST_MAX_RETRIES =>
+            {
+                let delay = with_jitter(retry_after_delay(
+                    response.headers(),
+                    Duration::from_secs(config.rate_limit_max_wait_secs),
+                ));
+                warn!(
+                    "CKAN returned {} for the dataset list; retrying in {} ms (retry {}/{})",
+                    response.status(),
+                    delay.as_millis(),
+                    retries + 1,
+                    DATASET_LIST_MAX_RETRIES
+                );
+                delay
+            }
+            Err(e) if is_transient(&e) && retries < DATASET_LIST_MAX_RETRIES => {
+                let delay = with_jitter(TRANSIENT_RETRY_DELAY);
+                warn!(
+                    "Dataset list request failed ({}); retrying in {} ms (retry {}/{})",
+                    e,
+                    delay.as_millis(),
+                    retries + 1,
+                    DATASET_LIST_MAX_RETRIES
+                );
+                delay
+            }
+            result => break result?,
+        };
+        retries += 1;
+        tokio::time::sleep(delay).await;
+    };
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(DatasetList::NotModified);
+    }
+    let new_etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let content_length = response.content_length();
+    let body = response.bytes().await?;
+    record_bytes_downloaded(bytes_downloaded, content_length, body.len());
+    let package_list: PackageListResponse = serde_json::from_slice(&body)?;
+    check_ckan_success(
+        "package_list",
+        package_list.success,
+        package_list.error.as_ref(),
+    )?;
+    Ok(DatasetList::Changed {
+        ids: limit_dataset_ids(package_list.result, config, test_mode),
+        etag: new_etag,
     })
 }
 
+/// Parses a newline-delimited dataset id list (`INPUT_IDS_FILE` or `INPUT_IDS_URL`), skipping
+/// blank lines, then applies the same test mode and `MAX_DATASETS` limits as the package list.
+pub fn parse_input_ids(text: &str, config: &Config, test_mode: bool) -> Vec<String> {
+    let ids = text
+        .lines()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect();
+    limit_dataset_ids(ids, config, test_mode)
+}
+
+/// Truncates a dataset id list to the test mode limit and to `MAX_DATASETS`.
+fn limit_dataset_ids(mut ids: Vec<String>, config: &Config, test_mode: bool) -> Vec<String> {
+    if test_mode {
+        ids.truncate(config.test_mode_dataset_limit);
+    }
+    if let Some(max) = config.max_datasets {
+        if ids.len() > max {
+            warn!(
+                "Package list returned {} datasets; truncating to MAX_DATASETS = {}",
+                ids.len(),
+                max
+            );
+            ids.truncate(max);
+            ids.shrink_to_fit();
+        }
+    }
+    ids
+}
+
 /// Fetches detailed metadata for a single dataset from the CKAN API.
-/// Cleans up HTML in the description and returns the metadata and download URLs.
+/// Returns the flattened metadata and download URLs (see `build_dataset_metadata`).
+/// The size of a successful response is added to `bytes_downloaded`. A rate-limited request,
+/// or one that failed with a transient error (`is_transient`), is retried once if
+/// `retry_budget` still has retries left. A 404 returns `Ok(None)`; any other failure status
+/// is returned as `AppError::Http`.
+/// Errors are wrapped in `AppError::Dataset` so they name the dataset that failed.
 pub async fn fetch_dataset_metadata(
     client: Arc<Client>,
     config: &Config,
     dataset_id: String,
-) -> Result<Option<(crate::DatasetMetadata, Vec<String>)>, AppError> {
-    let url = format!("{}{}", config.dataset_metadata_url(), dataset_id);
-    let response = client
-        .get(&url)
-        .timeout(std::time::Duration::from_secs(config.http_timeout_secs))
-        .send()
-        .await?;
+    bytes_downloaded: &AtomicU64,
+    retry_budget: &RetryBudget,
+) -> Result<Option<(crate::DatasetMetadata, ResourceUrls)>, AppError> {
+    fetch_metadata_for(client, config, &dataset_id, bytes_downloaded, retry_budget)
+        .await
+        .map_err(|e| e.for_dataset(&dataset_id))
+}
+
+/// Does the work of `fetch_dataset_metadata`, returning errors without the dataset context.
+async fn fetch_metadata_for(
+    client: Arc<Client>,
+    config: &Config,
+    dataset_id: &str,
+    bytes_downloaded: &AtomicU64,
+    retry_budget: &RetryBudget,
+) -> Result<Option<(crate::DatasetMetadata, ResourceUrls)>, AppError> {
+    let url = config.dataset_metadata_url_for(dataset_id);
+    let started = Instant::now();
+    let (client, url) = (&client, &url);
+    // A 5xx status is turned into an error here so it is classified by `is_transient` like a
+    // timeout; 404 and 429 come back as responses and are handled below.
+    let send = move || async move {
+        let response = client
+            .get(url)
+            .timeout(std::time::Duration::from_secs(config.http_timeout_secs))
+            .send()
+            .await?;
+        if response.status().is_server_error() {
+            response.error_for_status()
+        } else {
+            Ok(response)
+        }
+    };
+    // A timeout, connection failure or 5xx is retried once; anything else fails straight away.
+    let mut response = match send().await {
+        Err(e) if is_transient(&e) && retry_budget.try_acquire() => {
+            let delay = with_jitter(TRANSIENT_RETRY_DELAY);
+            warn!(
+                "Request for dataset {} failed ({}); retrying once after {} ms",
+                dataset_id,
+                e,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            send().await?
+        }
+        result => result?,
+    };
+    // On a rate limit, honour Retry-After (capped) and retry the request once.
+    if response.status() == StatusCode::TOO_MANY_REQUESTS && !retry_budget.try_acquire() {
+        warn!(
+            "CKAN rate limited dataset {}; retry budget exhausted, not retrying",
+            dataset_id
+        );
+    } else if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let delay = retry_after_delay(
+            response.headers(),
+            Duration::from_secs(config.rate_limit_max_wait_secs),
+        );
+        warn!(
+            "CKAN rate limited dataset {}; retrying once after {} ms",
+            dataset_id,
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        response = send().await?;
+    }
     if response.status().is_success() {
-        let metadata: PackageShowResponse = response.json().await?;
+        // Read the body first so a schema change surfaces as a parse error rather than an HTTP one.
+        let content_length = response.content_length();
+        let body = response.text().await?;
+        record_bytes_downloaded(bytes_downloaded, content_length, body.len());
+        warn_if_slow(config, dataset_id, started.elapsed());
+        let metadata: PackageShowResponse = serde_json::from_str(&body)?;
+        check_ckan_success("package_show", metadata.success, metadata.error.as_ref())?;
         let dataset = match &metadata.result {
             Some(val) => val,
             None => {
                 return Ok(None);
             }
         };
-        let (formats, urls_vec) = extract_resource_formats_and_urls(dataset);
-        // Use the pre-compiled regex for better performance
-        let clean_description = HTML_TAG_REGEX.replace_all(&dataset.notes, "").to_string();
-        return Ok(Some((
-            crate::DatasetMetadata {
-                id: dataset.id.clone(),
-                title: dataset.title.clone(),
-                description: clean_description,
-                license: dataset.license_title.clone(),
-                organization: dataset.organization.title.clone(),
-                created: dataset.metadata_created.clone(),
-                modified: dataset.metadata_modified.clone(),
-                format: formats,
-            },
-            urls_vec,
-        )));
+        check_resource_count(dataset);
+        let (mut meta, urls) = build_dataset_metadata(
+            dataset,
+            &config.url_format_filter,
+            config.include_urls,
+            config.dedupe_formats,
+            config.description_mode.parse()?,
+        );
+        meta.source = config.ckan_source_name();
+        let slug = if dataset.name.is_empty() {
+            &dataset.id
+        } else {
+            &dataset.name
+        };
+        meta.landing_page = config.landing_page_url(slug);
+        // Descriptions are left to DESCRIPTION_MODE (`plain` collapses their whitespace too).
+        if config.normalize_whitespace {
+            for field in [&mut meta.title, &mut meta.organization, &mut meta.license] {
+                *field = normalize_whitespace(field);
+            }
+        }
+        if config.dump_raw_json {
+            // Parsed a second time, untyped, so fields this crate ignores are kept too.
+            let mut raw: serde_json::Value = serde_json::from_str(&body)?;
+            meta.extras.raw_json = raw.get_mut("result").map(serde_json::Value::take);
+        }
+        if let Some(max) = config.max_description_chars {
+            meta.description = truncate_chars(&meta.description, max);
+        }
+        return Ok(Some((meta, urls)));
+    }
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    // Any other failure status (e.g. 403, or a 429 left after the retry) is an HTTP error, so it
+    // is tallied with the HTTP failures instead of being reported as a missing dataset.
+    response.error_for_status_ref()?;
+    Err(AppError::Other(format!(
+        "Unexpected HTTP status {}",
+        response.status()
+    )))
+}
+
+/// Logs a warning with the dataset id and elapsed time when a metadata request took longer
+/// than `slow_request_threshold_ms`. Returns true if the request was slow.
+pub fn warn_if_slow(config: &Config, dataset_id: &str, elapsed: Duration) -> bool {
+    let elapsed_ms = elapsed.as_millis() as u64;
+    if elapsed_ms <= config.slow_request_threshold_ms {
+        return false;
+    }
+    warn!(dataset_id, elapsed_ms, "Slow metadata request");
+    true
+}
+
+/// Compares CKAN's reported `num_resources` with the number of resources actually parsed,
+/// logging a warning on a mismatch so partial or truncated records don't go unnoticed.
+/// Returns false if the counts disagree; a missing `num_resources` is treated as a match.
+pub fn check_resource_count(dataset: &CkanDataset) -> bool {
+    match dataset.num_resources {
+        Some(expected) if expected as usize != dataset.resources.len() => {
+            warn!(
+                "Dataset {} reports {} resources but {} were parsed",
+                dataset.id,
+                expected,
+                dataset.resources.len()
+            );
+            false
+        }
+        _ => true,
     }
-    Ok(None)
 }
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// Limits concurrent requests with a semaphore that starts with a single permit and doubles
+/// every `interval` until it reaches the configured limit, so a cold start doesn't trip a
+/// portal's burst protection. Shared between fetch tasks.
+pub struct ConcurrencyRamp {
+    semaphore: Arc<Semaphore>,
+    ramp_task: JoinHandle<()>,
+}
+
+impl ConcurrencyRamp {
+    /// Starts ramping from one permit up to `limit`, doubling every `interval`.
+    pub fn start(limit: usize, interval: Duration) -> Self {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let ramp_semaphore = Arc::clone(&semaphore);
+        let ramp_task = tokio::spawn(async move {
+            let mut current = 1;
+            while current < limit {
+                tokio::time::sleep(interval).await;
+                let next = (current * 2).min(limit);
+                ramp_semaphore.add_permits(next - current);
+                info!("Concurrency ramp: up to {} concurrent requests", next);
+                current = next;
+            }
+        });
+        Self {
+            semaphore,
+            ramp_task,
+        }
+    }
+
+    /// Waits for a permit; the request may proceed while the permit is held.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("ramp semaphore is never closed")
+    }
+}
+
+impl Drop for ConcurrencyRamp {
+    fn drop(&mut self) {
+        self.ramp_task.abort();
+    }
+}
@@ -1,54 +1,932 @@
 // Centralised configuration struct for all application settings.
 // This makes the code more maintainable and easier to test.
 
+use aws_sdk_s3::types::StorageClass;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// File name of the normalised `(dataset_id, tag)` output written when `NORMALIZE_TAGS` is set.
+pub const TAGS_FILE_NAME: &str = "dataset_tags.csv";
+
+/// File name of the resource format counts file, written next to the output file.
+pub const FORMATS_SUMMARY_FILE_NAME: &str = "formats_summary.csv";
+
+/// File name of the one-row-per-resource file written when `WRITE_RESOURCES` is set.
+pub const RESOURCES_FILE_NAME: &str = "resources.csv";
+
+/// File name of the NDJSON dump of raw CKAN results written when `DUMP_RAW_JSON` is set.
+pub const RAW_JSON_FILE_NAME: &str = "raw_datasets.ndjson";
+
+/// File name of the new-or-changed datasets CSV written in `INCREMENTAL` mode.
+pub const DELTA_FILE_NAME: &str = "changed_datasets.csv";
+
+/// The config file read when `CONFIG_FILE` is not set, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// User-Agent sent on CKAN requests unless `USER_AGENT` is set.
+pub const DEFAULT_USER_AGENT: &str = concat!("rust-gov-data/", env!("CARGO_PKG_VERSION"));
+
+/// Port for the `/metrics` endpoint when `--serve-metrics` is given without `METRICS_PORT`.
+pub const DEFAULT_METRICS_PORT: u16 = 9090;
+
+/// Where configuration values are read from: environment variables first, then the optional
+/// TOML config file (keys are the lowercase environment variable names), then the defaults.
+#[derive(Debug, Default)]
+pub struct ConfigSource {
+    /// Values from the config file, keyed by lowercase name
+    file_values: HashMap<String, String>,
+    /// Error from reading or parsing the config file
+    error: Option<String>,
+    /// Variables to read instead of the process environment; an empty map means only the
+    /// file values and defaults apply
+    env_values: Option<HashMap<String, String>>,
+}
+
+impl ConfigSource {
+    /// Reads and parses a TOML config file, recording any error for `Config::validate`.
+    pub fn from_file(path: &str) -> Self {
+        let values = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file '{path}': {e}"))
+            .and_then(|contents| {
+                contents
+                    .parse::<toml::Table>()
+                    .map_err(|e| format!("Could not parse config file '{path}': {e}"))
+            });
+        match values {
+            Ok(table) => Self {
+                file_values: table
+                    .into_iter()
+                    .map(|(key, value)| (key.to_lowercase(), toml_value_to_string(value)))
+                    .collect(),
+                ..Self::default()
+            },
+            Err(error) => Self {
+                error: Some(error),
+                ..Self::default()
+            },
+        }
+    }
+
+    /// Reads variables from `env` instead of the process environment.
+    pub fn with_env(self, env: HashMap<String, String>) -> Self {
+        Self {
+            env_values: Some(env),
+            ..self
+        }
+    }
+
+    /// Looks up a value by environment variable name, falling back to the config file.
+    fn get(&self, var: &str) -> Option<String> {
+        match &self.env_values {
+            Some(env) => env.get(var).cloned(),
+            None => std::env::var(var).ok(),
+        }
+        .or_else(|| self.file_values.get(&var.to_lowercase()).cloned())
+    }
+
+    /// Helper to get a value or use a default value if not set.
+    fn get_or_default(&self, var: &str, default: &str) -> String {
+        self.get(var).unwrap_or_else(|| default.to_string())
+    }
+
+    /// Helper to get an optional value, treating an empty value as unset.
+    fn get_optional(&self, var: &str) -> Option<String> {
+        self.get(var).filter(|v| !v.trim().is_empty())
+    }
+
+    /// Helper to read a comma-separated list, dropping empty entries.
+    fn get_list(&self, var: &str) -> Vec<String> {
+        self.get(var)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Helper to read a boolean flag ("1" or "true", case-insensitive).
+    fn get_flag(&self, var: &str, default: bool) -> bool {
+        self.get(var)
+            .map(|v| v == "1" || v.to_lowercase() == "true")
+            .unwrap_or(default)
+    }
+}
+
+/// Converts a TOML value to the string form used for environment variables
+/// (arrays become comma-separated lists).
+fn toml_value_to_string(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s,
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(toml_value_to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        other => other.to_string(),
+    }
+}
+
+/// Output file format, selected with the `OUTPUT_FORMAT` environment variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Comma-separated values (default)
+    #[default]
+    Csv,
+    /// Apache Parquet
+    Parquet,
+    /// SQLite database with normalised `datasets` and `resources` tables
+    Sqlite,
+    /// Excel workbook with a styled, frozen header row and hyperlinked download URLs
+    Xlsx,
+    /// Items written straight to the `DYNAMODB_TABLE` DynamoDB table (no local file)
+    Dynamodb,
+}
+
+impl OutputFormat {
+    /// File extension (without the dot) for this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Sqlite => "db",
+            OutputFormat::Xlsx => "xlsx",
+            // Unused: DynamoDB output writes no local file.
+            OutputFormat::Dynamodb => "json",
+        }
+    }
+
+    /// Whether this format writes a local file that is then uploaded to S3.
+    pub fn writes_file(&self) -> bool {
+        *self != OutputFormat::Dynamodb
+    }
+
+    /// MIME type sent as the S3 object's Content-Type for this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Parquet => "application/vnd.apache.parquet",
+            OutputFormat::Sqlite => "application/vnd.sqlite3",
+            OutputFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            OutputFormat::Dynamodb => "application/json",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            "xlsx" => Ok(OutputFormat::Xlsx),
+            "dynamodb" => Ok(OutputFormat::Dynamodb),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown output format '{other}' (expected csv, parquet, sqlite, xlsx or dynamodb)"
+            ))),
+        }
+    }
+}
+
+/// Where the output file goes, selected with the `OUTPUT_DEST` environment variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputDest {
+    /// Upload to S3 (default)
+    #[default]
+    S3,
+    /// Only write the local file; nothing is sent to S3
+    Local,
+    /// Upload to S3 and keep the local file as an output
+    Both,
+}
+
+impl OutputDest {
+    /// Whether the output (and its companion files) are uploaded to S3.
+    pub fn uploads(self) -> bool {
+        self != OutputDest::Local
+    }
+}
+
+impl FromStr for OutputDest {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "s3" => Ok(OutputDest::S3),
+            "local" => Ok(OutputDest::Local),
+            "both" => Ok(OutputDest::Both),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown output destination '{other}' (expected s3, local or both)"
+            ))),
+        }
+    }
+}
+
+/// Row sort order for the output, selected with the `SORT_BY` environment variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    /// Ascending by dataset id (default)
+    #[default]
+    Id,
+    /// Ascending by title
+    Title,
+    /// Descending by modified timestamp, so the freshest datasets come first
+    Modified,
+    /// Leave rows in the order they were fetched
+    None,
+}
+
+impl FromStr for SortBy {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "id" => Ok(SortBy::Id),
+            "title" => Ok(SortBy::Title),
+            "modified" => Ok(SortBy::Modified),
+            "none" => Ok(SortBy::None),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown sort order '{other}' (expected id, title, modified or none)"
+            ))),
+        }
+    }
+}
+
+/// How dataset descriptions (`notes`) are transformed, selected with `DESCRIPTION_MODE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DescriptionMode {
+    /// Keep the description exactly as CKAN returned it, HTML included
+    Raw,
+    /// Strip HTML tags, keeping block boundaries as newlines (default)
+    #[default]
+    Strip,
+    /// Strip tags, decode HTML entities and collapse whitespace to single spaces
+    Plain,
+}
+
+impl FromStr for DescriptionMode {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "raw" => Ok(DescriptionMode::Raw),
+            "strip" => Ok(DescriptionMode::Strip),
+            "plain" => Ok(DescriptionMode::Plain),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown description mode '{other}' (expected raw, strip or plain)"
+            ))),
+        }
+    }
+}
+
+/// Log output format, selected with the `LOG_FORMAT` environment variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines (default)
+    #[default]
+    Plain,
+    /// One JSON object per event, for querying in CloudWatch Logs Insights
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown log format '{other}' (expected plain or json)"
+            ))),
+        }
+    }
+}
+
+/// How download URLs are laid out in CSV output, selected with `URL_COLUMN_MODE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UrlColumnMode {
+    /// One `download_url_N` column per URL, as wide as the dataset with the most URLs (default)
+    #[default]
+    Wide,
+    /// A single `download_urls` column holding a JSON array, for a fixed column count
+    Json,
+}
+
+impl FromStr for UrlColumnMode {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "wide" => Ok(UrlColumnMode::Wide),
+            "json" => Ok(UrlColumnMode::Json),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown URL column mode '{other}' (expected wide or json)"
+            ))),
+        }
+    }
+}
+
+/// How fields are quoted in CSV output, selected with `CSV_QUOTE_STYLE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CsvQuoteStyle {
+    /// Quote only fields containing the delimiter, a quote or a newline (default)
+    #[default]
+    Necessary,
+    /// Quote every field
+    Always,
+    /// Quote every field that isn't a number
+    NonNumeric,
+    /// Never quote fields, even when that makes the output ambiguous
+    Never,
+}
+
+impl CsvQuoteStyle {
+    /// The matching `csv` crate quote style.
+    pub fn quote_style(self) -> csv::QuoteStyle {
+        match self {
+            CsvQuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            CsvQuoteStyle::Always => csv::QuoteStyle::Always,
+            CsvQuoteStyle::NonNumeric => csv::QuoteStyle::NonNumeric,
+            CsvQuoteStyle::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+impl FromStr for CsvQuoteStyle {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "necessary" => Ok(CsvQuoteStyle::Necessary),
+            "always" => Ok(CsvQuoteStyle::Always),
+            "non_numeric" => Ok(CsvQuoteStyle::NonNumeric),
+            "never" => Ok(CsvQuoteStyle::Never),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown CSV quote style '{other}' (expected necessary, always, non_numeric or never)"
+            ))),
+        }
+    }
+}
+
+/// Line ending written after each CSV record, selected with `CSV_TERMINATOR`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CsvTerminator {
+    /// `\n` (default)
+    #[default]
+    Lf,
+    /// `\r\n`, for consumers on Windows
+    Crlf,
+}
+
+impl CsvTerminator {
+    /// The matching `csv` crate terminator.
+    pub fn terminator(self) -> csv::Terminator {
+        match self {
+            CsvTerminator::Lf => csv::Terminator::Any(b'\n'),
+            CsvTerminator::Crlf => csv::Terminator::CRLF,
+        }
+    }
+}
+
+impl FromStr for CsvTerminator {
+    type Err = crate::error::AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "lf" => Ok(CsvTerminator::Lf),
+            "crlf" => Ok(CsvTerminator::Crlf),
+            other => Err(crate::error::AppError::Config(format!(
+                "Unknown CSV terminator '{other}' (expected lf or crlf)"
+            ))),
+        }
+    }
+}
+
 /// Configuration for the application, loaded from environment variables or defaults.
-#[derive(Debug, Clone)]
+/// As JSON (`Config::from_json`), keys are the field names and unknown keys are rejected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default = "Config::defaults", deny_unknown_fields)]
 pub struct Config {
     /// The base URL for the CKAN API.
     pub ckan_api_base_url: String,
+    /// Several CKAN API base URLs to fetch from in one run; when set, replaces `ckan_api_base_url`.
+    pub ckan_api_base_urls: Vec<String>,
+    /// The CKAN action that lists dataset ids, appended to the base URL (`package_list`).
+    pub ckan_list_action: String,
+    /// The CKAN action that returns one dataset's metadata (`package_show`).
+    pub ckan_show_action: String,
+    /// The query parameter `ckan_show_action` takes the dataset id in (`id`).
+    pub ckan_id_param: String,
+    /// Optional extra `key=value` pairs (joined with `&`, already URL-encoded) appended to every
+    /// `package_show` request, e.g. `include_tracking=true`.
+    pub extra_show_params: Option<String>,
     /// The S3 bucket name for output.
     pub bucket_name: String,
     /// The output CSV file name or path.
     pub csv_file: String,
     /// The concurrency limit for async processing.
     pub concurrency_limit: usize,
+    /// Optional JSON object mapping a CKAN portal host (as in the `source` column) to its own
+    /// concurrency limit, e.g. `{"data.gov.uk": 20}`; unlisted hosts use `concurrency_limit`.
+    pub concurrency_limit_per_source: Option<String>,
+    /// Whether to start at one concurrent request and double up to `concurrency_limit`.
+    pub concurrency_ramp: bool,
+    /// How often the concurrency ramp doubles, in milliseconds.
+    pub concurrency_ramp_interval_ms: u64,
+    /// Upper bound of a random pause before the run's first request, in milliseconds, so
+    /// scheduled copies don't all start at once. 0 disables it.
+    pub startup_jitter_max_ms: u64,
+    /// Optional number of datasets fetched per batch; each batch finishes before the next
+    /// starts. Unset (or 0) fetches everything as one continuous stream.
+    pub batch_size: Option<usize>,
+    /// Pause between batches, in milliseconds, when `batch_size` is set.
+    pub batch_delay_ms: u64,
     /// The AWS region to use for S3 and other AWS services.
     pub aws_region: String,
     /// The HTTP timeout (in seconds) for API requests.
     pub http_timeout_secs: u64,
+    /// The maximum number of idle HTTP connections kept per host.
+    pub http_pool_max_idle: usize,
+    /// How long idle HTTP connections are kept alive, in seconds.
+    pub http_pool_idle_timeout_secs: u64,
+    /// The HTTP connection timeout in seconds.
+    pub http_connect_timeout_secs: u64,
     /// The dataset limit for test mode (number of datasets to process).
     pub test_mode_dataset_limit: usize,
+    /// Optional hard cap on the number of dataset IDs taken from each package list,
+    /// applied regardless of test mode.
+    pub max_datasets: Option<usize>,
+    /// Abort the run if the package lists hold fewer dataset ids than this, so a partial CKAN
+    /// outage can't overwrite a good output with a tiny one. 0 disables the check.
+    pub min_expected_datasets: usize,
+    /// Fail the Lambda invocation when more than this fraction of attempted datasets failed,
+    /// so alerting on failed invocations fires. 1.0 never fails.
+    pub max_failure_ratio: f64,
+    /// Abort before upload if the CSV row count differs from the number of datasets left after
+    /// filtering; a mismatch is always logged as an error.
+    pub abort_on_row_mismatch: bool,
+    /// Whether to also write the datasets that are new or changed since the previous upload
+    /// to a separate delta file.
+    pub incremental: bool,
+    /// Whether to compare this run's dataset IDs with the previous upload and report the
+    /// added and removed counts in the summary and manifest.
+    pub diff_previous: bool,
+    /// Whether the `DIFF_PREVIOUS` report also lists the added and removed IDs.
+    pub diff_list_ids: bool,
+    /// Optional prefix prepended to every S3 object key.
+    pub key_prefix: String,
+    /// Whether to upload to a date-partitioned, timestamped key (plus a `latest/` copy).
+    pub partition_by_date: bool,
+    /// Files larger than this many bytes are uploaded to S3 using multipart upload.
+    pub multipart_threshold_bytes: u64,
+    /// The number of multipart upload parts sent to S3 concurrently.
+    pub s3_upload_concurrency: usize,
+    /// Whether to publish run metrics to CloudWatch.
+    pub emit_metrics: bool,
+    /// The CloudWatch namespace for published metrics.
+    pub metrics_namespace: String,
+    /// Port for the Prometheus `/metrics` endpoint in local mode; setting it starts the server.
+    pub metrics_port: Option<u16>,
+    /// The output file format; see [`OutputFormat`] for the accepted values.
+    pub output_format: String,
+    /// Where the output goes (`s3`, `local` or `both`).
+    pub output_dest: String,
+    /// Optional API token sent as the `Authorization` header on every CKAN request.
+    pub ckan_api_key: Option<String>,
+    /// Optional base URL of the CKAN portal's human-facing pages, used for `landing_page`.
+    /// Derived from the API base URL (dropping `/api/...`) when unset.
+    pub ckan_portal_base_url: Option<String>,
+    /// The User-Agent header sent on CKAN requests, so portal operators can identify us.
+    pub user_agent: String,
+    /// Optional proxy for all CKAN requests. When unset, `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY`
+    /// from the environment still apply.
+    pub ckan_proxy_url: Option<String>,
+    /// The maximum time (in seconds) to wait on a CKAN `Retry-After` before retrying a rate-limited request.
+    pub rate_limit_max_wait_secs: u64,
+    /// Optional cap on the total number of CKAN retries across the whole run.
+    pub total_retry_budget: Option<usize>,
+    /// Whether to write the output locally but skip the S3 upload.
+    pub dry_run: bool,
+    /// Optional SNS topic ARN that receives a notification when each run finishes.
+    pub sns_topic_arn: Option<String>,
+    /// Whether to stream CSV rows to disk as metadata arrives instead of collecting them first.
+    pub streaming_csv: bool,
+    /// Optional cap on the number of download URL columns in the CSV; further URLs go to a
+    /// JSON `extra_urls` column.
+    pub max_url_columns: Option<usize>,
+    /// The fixed number of download URL columns written in streaming CSV mode.
+    pub streaming_url_columns: usize,
+    /// The CSV field delimiter: a single byte such as `,` or a tab (`\t` is accepted as an escape).
+    pub csv_delimiter: String,
+    /// How CSV fields are quoted (`necessary`, `always`, `non_numeric` or `never`).
+    pub csv_quote_style: String,
+    /// Line ending after each CSV record (`lf` or `crlf`).
+    pub csv_terminator: String,
+    /// The maximum number of retries for a failed S3 write.
+    pub s3_max_retries: u32,
+    /// The base delay (in milliseconds) for exponential backoff between S3 retries.
+    pub s3_retry_base_delay_ms: u64,
+    /// The maximum number of retries for S3 `SlowDown` responses, counted separately from
+    /// `s3_max_retries`.
+    pub s3_slowdown_max_retries: u32,
+    /// The base delay (in milliseconds) for backing off after an S3 `SlowDown`.
+    pub s3_slowdown_base_delay_ms: u64,
+    /// The cap (in milliseconds) on the backoff after an S3 `SlowDown`.
+    pub s3_slowdown_max_delay_ms: u64,
+    /// Whether to start the CSV output with a UTF-8 byte order mark, for Excel on Windows.
+    pub add_bom: bool,
+    /// Metadata columns to write to CSV output, in order; empty writes every column.
+    pub columns: Vec<String>,
+    /// The output row order (`id`, `title`, `modified` or `none`).
+    pub sort_by: String,
+    /// How dataset descriptions are transformed (`raw`, `strip` or `plain`).
+    pub description_mode: String,
+    /// Whether to trim and collapse whitespace in titles, organisation names and licences.
+    pub normalize_whitespace: bool,
+    /// Optional maximum description length in characters; longer descriptions are truncated
+    /// with an ellipsis.
+    pub max_description_chars: Option<usize>,
+    /// Optional Cache-Control header value set on uploaded objects.
+    pub cache_control: Option<String>,
+    /// Optional server-side encryption for uploaded objects: `aes256` or `aws:kms`.
+    pub s3_sse: Option<String>,
+    /// KMS key id used when `s3_sse` is `aws:kms`.
+    pub s3_kms_key_id: Option<String>,
+    /// S3 storage class for uploaded output files, e.g. `STANDARD` or `STANDARD_IA`.
+    pub s3_storage_class: String,
+    /// Whether to also write a normalised `dataset_tags.csv` with one `(dataset_id, tag)` row per tag.
+    pub normalize_tags: bool,
+    /// Whether to write one CSV per organisation (uploaded under `org={slug}/`) instead of a
+    /// single output file.
+    pub split_by_org: bool,
+    /// Resource formats (e.g. `CSV`, `JSON`) whose URLs are kept in the download URL columns.
+    /// Empty keeps every URL.
+    pub url_format_filter: Vec<String>,
+    /// Whether to collect resource URLs at all; when false the output has no download URL columns.
+    pub include_urls: bool,
+    /// Whether to collapse the `format` column to unique, uppercased, sorted formats.
+    pub dedupe_formats: bool,
+    /// How download URLs are written to CSV (`wide` per-URL columns or a single `json` column).
+    pub url_column_mode: String,
+    /// Organisation titles or slugs to keep; datasets from other organisations are dropped.
+    /// Empty keeps every dataset.
+    pub org_filter: Vec<String>,
+    /// Whether to drop datasets that are private or whose CKAN state is not `active`.
+    pub skip_inactive: bool,
+    /// Optional RFC 3339 timestamp; datasets whose `modified` is earlier are dropped after
+    /// fetching, for building delta feeds.
+    pub modified_since: Option<String>,
+    /// Whether to skip datasets completed by an interrupted run, as recorded in the checkpoint.
+    pub resume: bool,
+    /// Path of the checkpoint file listing completed dataset ids; their rows go alongside it.
+    pub checkpoint_file: String,
+    /// Save the checkpoint after this many newly completed datasets (0 disables checkpointing).
+    pub checkpoint_interval: usize,
+    /// DynamoDB table written to when the output format is `dynamodb`.
+    pub dynamodb_table: Option<String>,
+    /// Metadata requests slower than this many milliseconds are logged as a warning.
+    pub slow_request_threshold_ms: u64,
+    /// Number of slowest datasets recorded in the run summary.
+    pub slowest_datasets_count: usize,
+    /// Optional overall deadline in seconds; fetching stops when it is reached and the
+    /// datasets collected so far are written and uploaded.
+    pub run_deadline_secs: Option<u64>,
+    /// The log output format: `plain` or `json`.
+    pub log_format: String,
+    /// Whether to check every download URL with a HEAD request and record its status.
+    pub validate_urls: bool,
+    /// The maximum number of concurrent URL checks when validating URLs.
+    pub url_check_concurrency: usize,
+    /// Whether to upload a JSON manifest describing the run alongside the output file.
+    pub write_manifest: bool,
+    /// Whether to also write and upload `formats_summary.csv` with resource counts per format.
+    pub write_formats_summary: bool,
+    /// Whether to also write and upload `resources.csv` with one row per resource.
+    pub write_resources: bool,
+    /// Whether to keep each dataset's raw CKAN JSON and upload it as NDJSON for debugging.
+    /// Holds every response in memory, so it is off by default.
+    pub dump_raw_json: bool,
+    /// Whether to send the previous run's package list ETag as `If-None-Match` and skip the
+    /// whole run when CKAN answers 304 Not Modified.
+    pub use_conditional_get: bool,
+    /// S3 key (under the key prefix) the package list ETag is stored under.
+    pub list_etag_key: String,
+    /// S3 key (under the key prefix) of the dead-letter file listing failed dataset ids.
+    pub failed_ids_key: String,
+    /// Optional S3 key of a previous run's dead-letter file; when set, only those ids are fetched.
+    pub retry_failed_from: Option<String>,
+    /// Optional local file of newline-delimited dataset ids to fetch instead of the package list.
+    pub input_ids_file: Option<String>,
+    /// Optional `s3://bucket/key` object of newline-delimited dataset ids to fetch instead of
+    /// the package list.
+    pub input_ids_url: Option<String>,
+    /// Whether to skip the output upload when the existing object's `sha256` metadata matches.
+    pub skip_unchanged_upload: bool,
+    /// Whether to tag uploaded S3 objects with the run id and the CKAN source URL.
+    pub tag_objects: bool,
+    /// Identifier of this run, used for S3 object tags: `RUN_ID` if set, otherwise a generated
+    /// UUID. The Lambda handler replaces it with the invocation's request id.
+    pub run_id: String,
+    /// Whether to check each CKAN portal is reachable before fetching the dataset list.
+    pub preflight: bool,
+    /// Error from reading or parsing the config file, reported by `validate`.
+    #[serde(skip)]
+    config_file_error: Option<String>,
 }
 
 impl Config {
-    /// Create a new Config by reading environment variables or using defaults.
+    /// Create a new Config by reading environment variables, then the optional TOML config file
+    /// (`CONFIG_FILE`, or `config.toml` if present), then falling back to defaults.
     pub fn new() -> Self {
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) if path.ends_with(".json") => Self::from_json_file(&path),
+            Ok(path) => Self::from_toml_file(&path),
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_toml_file(DEFAULT_CONFIG_FILE)
+            }
+            Err(_) => Self::from_source(&ConfigSource::default()),
+        }
+    }
+
+    /// Create a Config from the given TOML file. Environment variables still override file values.
+    pub fn from_toml_file(path: &str) -> Self {
+        Self::from_source(&ConfigSource::from_file(path))
+    }
+
+    /// Parse a Config from JSON, keyed by field name (the lowercase environment variable
+    /// names) with typed values, e.g. `{"concurrency_limit": 20, "columns": ["id", "title"]}`.
+    /// Missing keys take their defaults; environment variables are not read, and an unknown
+    /// key is an error naming the key and its position.
+    pub fn from_json(json: &str) -> Result<Self, crate::error::AppError> {
+        serde_json::from_str(json).map_err(|e| {
+            let message = e.to_string();
+            // Drop serde's list of every accepted key, which would bury the typo.
+            let message = match message.split_once(", expected one of") {
+                Some((unknown, _)) => {
+                    format!("{unknown} at line {} column {}", e.line(), e.column())
+                }
+                None => message,
+            };
+            crate::error::AppError::Config(format!("Invalid JSON config: {message}"))
+        })
+    }
+
+    /// Create a Config from the given JSON file, recording any error for `validate`.
+    pub fn from_json_file(path: &str) -> Self {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file '{path}': {e}"))
+            .and_then(|json| {
+                Self::from_json(&json).map_err(|e| format!("Config file '{path}': {e}"))
+            })
+            .unwrap_or_else(|error| Self {
+                config_file_error: Some(error),
+                ..Self::defaults()
+            })
+    }
+
+    /// The built-in defaults, ignoring environment variables; fills keys missing from JSON.
+    fn defaults() -> Self {
+        Self::from_source(&ConfigSource::default().with_env(HashMap::new()))
+    }
+
+    /// Build the Config, looking up each value in the given source.
+    pub fn from_source(source: &ConfigSource) -> Self {
         Self {
-            ckan_api_base_url: Self::get_env_or_default(
+            ckan_api_base_url: source.get_or_default(
                 "CKAN_API_BASE_URL",
                 "https://ckan.publishing.service.gov.uk/api/action",
             ),
-            bucket_name: Self::get_env_or_default("BUCKET_NAME", "gov-data-lucky4some.com"),
-            csv_file: Self::get_csv_file(),
-            concurrency_limit: Self::get_env_or_default("CONCURRENCY_LIMIT", "10")
+            ckan_api_base_urls: source.get_list("CKAN_API_BASE_URLS"),
+            ckan_list_action: source.get_or_default("CKAN_LIST_ACTION", "package_list"),
+            ckan_show_action: source.get_or_default("CKAN_SHOW_ACTION", "package_show"),
+            ckan_id_param: source.get_or_default("CKAN_ID_PARAM", "id"),
+            extra_show_params: source
+                .get_optional("EXTRA_SHOW_PARAMS")
+                .map(|params| params.trim().trim_start_matches(['?', '&']).to_string())
+                .filter(|params| !params.is_empty()),
+            bucket_name: source.get_or_default("BUCKET_NAME", "gov-data-lucky4some.com"),
+            csv_file: Self::get_csv_file(source),
+            concurrency_limit: source
+                .get_or_default("CONCURRENCY_LIMIT", "10")
                 .parse()
                 .unwrap_or(10),
-            aws_region: Self::get_env_or_default("AWS_REGION", "eu-west-2"),
-            http_timeout_secs: Self::get_env_or_default("HTTP_TIMEOUT_SECS", "15")
+            concurrency_limit_per_source: source.get_optional("CONCURRENCY_LIMIT_PER_SOURCE"),
+            concurrency_ramp: source.get_flag("CONCURRENCY_RAMP", false),
+            concurrency_ramp_interval_ms: source
+                .get_or_default("CONCURRENCY_RAMP_INTERVAL_MS", "2000")
+                .parse()
+                .unwrap_or(2000),
+            startup_jitter_max_ms: source
+                .get_or_default("STARTUP_JITTER_MAX_MS", "0")
+                .parse()
+                .unwrap_or(0),
+            batch_size: source
+                .get_optional("BATCH_SIZE")
+                .and_then(|v| v.parse().ok())
+                .filter(|&size| size > 0),
+            batch_delay_ms: source
+                .get_or_default("BATCH_DELAY_MS", "0")
+                .parse()
+                .unwrap_or(0),
+            aws_region: source.get_or_default("AWS_REGION", "eu-west-2"),
+            http_timeout_secs: source
+                .get_or_default("HTTP_TIMEOUT_SECS", "15")
                 .parse()
                 .unwrap_or(15),
-            test_mode_dataset_limit: Self::get_env_or_default("TEST_MODE_DATASET_LIMIT", "20")
+            http_pool_max_idle: source
+                .get_or_default("HTTP_POOL_MAX_IDLE", "10")
+                .parse()
+                .unwrap_or(10),
+            http_pool_idle_timeout_secs: source
+                .get_or_default("HTTP_POOL_IDLE_TIMEOUT_SECS", "90")
+                .parse()
+                .unwrap_or(90),
+            http_connect_timeout_secs: source
+                .get_or_default("HTTP_CONNECT_TIMEOUT_SECS", "10")
+                .parse()
+                .unwrap_or(10),
+            test_mode_dataset_limit: source
+                .get_or_default("TEST_MODE_DATASET_LIMIT", "20")
+                .parse()
+                .unwrap_or(20),
+            max_datasets: source
+                .get_optional("MAX_DATASETS")
+                .and_then(|v| v.parse().ok()),
+            min_expected_datasets: source
+                .get_or_default("MIN_EXPECTED_DATASETS", "0")
+                .parse()
+                .unwrap_or(0),
+            max_failure_ratio: source
+                .get_or_default("MAX_FAILURE_RATIO", "1.0")
+                .parse()
+                .unwrap_or(1.0),
+            abort_on_row_mismatch: source.get_flag("ABORT_ON_ROW_MISMATCH", false),
+            incremental: source.get_flag("INCREMENTAL", false),
+            diff_previous: source.get_flag("DIFF_PREVIOUS", false),
+            diff_list_ids: source.get_flag("DIFF_LIST_IDS", false),
+            key_prefix: source.get_or_default("KEY_PREFIX", ""),
+            partition_by_date: source.get_flag("PARTITION_BY_DATE", false),
+            multipart_threshold_bytes: source
+                .get_or_default("MULTIPART_THRESHOLD_BYTES", "104857600")
+                .parse()
+                .unwrap_or(100 * 1024 * 1024),
+            s3_upload_concurrency: source
+                .get_or_default("S3_UPLOAD_CONCURRENCY", "4")
+                .parse()
+                .unwrap_or(4),
+            emit_metrics: source.get_flag("EMIT_METRICS", false),
+            metrics_namespace: source.get_or_default("METRICS_NAMESPACE", "GovData"),
+            metrics_port: source
+                .get_optional("METRICS_PORT")
+                .and_then(|v| v.parse().ok()),
+            output_format: source.get_or_default("OUTPUT_FORMAT", "csv"),
+            output_dest: source.get_or_default("OUTPUT_DEST", "s3"),
+            ckan_api_key: source.get_optional("CKAN_API_KEY"),
+            ckan_portal_base_url: source.get_optional("CKAN_PORTAL_BASE_URL"),
+            user_agent: source.get_or_default("USER_AGENT", DEFAULT_USER_AGENT),
+            ckan_proxy_url: source.get_optional("CKAN_PROXY_URL"),
+            rate_limit_max_wait_secs: source
+                .get_or_default("RATE_LIMIT_MAX_WAIT_SECS", "30")
+                .parse()
+                .unwrap_or(30),
+            total_retry_budget: source
+                .get_optional("TOTAL_RETRY_BUDGET")
+                .and_then(|v| v.parse().ok()),
+            dry_run: source.get_flag("DRY_RUN", false),
+            sns_topic_arn: source.get_optional("SNS_TOPIC_ARN"),
+            streaming_csv: source.get_flag("STREAMING_CSV", false),
+            max_url_columns: source
+                .get_optional("MAX_URL_COLUMNS")
+                .and_then(|v| v.parse().ok()),
+            streaming_url_columns: source
+                .get_or_default("STREAMING_URL_COLUMNS", "10")
+                .parse()
+                .unwrap_or(10),
+            csv_delimiter: source.get_or_default("CSV_DELIMITER", ","),
+            csv_quote_style: source.get_or_default("CSV_QUOTE_STYLE", "necessary"),
+            csv_terminator: source.get_or_default("CSV_TERMINATOR", "lf"),
+            s3_max_retries: source
+                .get_or_default("S3_MAX_RETRIES", "3")
+                .parse()
+                .unwrap_or(3),
+            s3_retry_base_delay_ms: source
+                .get_or_default("S3_RETRY_BASE_DELAY_MS", "200")
+                .parse()
+                .unwrap_or(200),
+            s3_slowdown_max_retries: source
+                .get_or_default("S3_SLOWDOWN_MAX_RETRIES", "5")
+                .parse()
+                .unwrap_or(5),
+            s3_slowdown_base_delay_ms: source
+                .get_or_default("S3_SLOWDOWN_BASE_DELAY_MS", "1000")
+                .parse()
+                .unwrap_or(1000),
+            s3_slowdown_max_delay_ms: source
+                .get_or_default("S3_SLOWDOWN_MAX_DELAY_MS", "20000")
+                .parse()
+                .unwrap_or(20000),
+            add_bom: source.get_flag("ADD_BOM", false),
+            columns: source.get_list("COLUMNS"),
+            sort_by: source.get_or_default("SORT_BY", "id"),
+            description_mode: source.get_or_default("DESCRIPTION_MODE", "strip"),
+            normalize_whitespace: source.get_flag("NORMALIZE_WHITESPACE", true),
+            max_description_chars: source
+                .get_optional("MAX_DESCRIPTION_CHARS")
+                .and_then(|v| v.parse().ok()),
+            log_format: source.get_or_default("LOG_FORMAT", "plain"),
+            cache_control: source.get_optional("CACHE_CONTROL"),
+            s3_sse: source.get_optional("S3_SSE"),
+            s3_kms_key_id: source.get_optional("S3_KMS_KEY_ID"),
+            s3_storage_class: source.get_or_default("S3_STORAGE_CLASS", "STANDARD"),
+            normalize_tags: source.get_flag("NORMALIZE_TAGS", false),
+            split_by_org: source.get_flag("SPLIT_BY_ORG", false),
+            url_format_filter: source.get_list("URL_FORMAT_FILTER"),
+            include_urls: source.get_flag("INCLUDE_URLS", true),
+            dedupe_formats: source.get_flag("DEDUPE_FORMATS", true),
+            url_column_mode: source.get_or_default("URL_COLUMN_MODE", "wide"),
+            org_filter: source.get_list("ORG_FILTER"),
+            skip_inactive: source.get_flag("SKIP_INACTIVE", true),
+            modified_since: source.get_optional("MODIFIED_SINCE"),
+            resume: source.get_flag("RESUME", false),
+            checkpoint_file: source.get_or_default("CHECKPOINT_FILE", "/tmp/checkpoint.json"),
+            checkpoint_interval: source
+                .get_or_default("CHECKPOINT_INTERVAL", "0")
+                .parse()
+                .unwrap_or(0),
+            dynamodb_table: source.get_optional("DYNAMODB_TABLE"),
+            slow_request_threshold_ms: source
+                .get_or_default("SLOW_REQUEST_THRESHOLD_MS", "5000")
+                .parse()
+                .unwrap_or(5000),
+            slowest_datasets_count: source
+                .get_or_default("SLOWEST_DATASETS_COUNT", "5")
+                .parse()
+                .unwrap_or(5),
+            run_deadline_secs: source
+                .get_optional("RUN_DEADLINE_SECS")
+                .and_then(|v| v.parse().ok()),
+            write_manifest: source.get_flag("WRITE_MANIFEST", false),
+            skip_unchanged_upload: source.get_flag("SKIP_UNCHANGED_UPLOAD", false),
+            tag_objects: source.get_flag("TAG_OBJECTS", false),
+            run_id: source
+                .get_optional("RUN_ID")
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            use_conditional_get: source.get_flag("USE_CONDITIONAL_GET", false),
+            list_etag_key: source.get_or_default("LIST_ETAG_KEY", "package_list.etag"),
+            failed_ids_key: source.get_or_default("FAILED_IDS_KEY", "failed_ids.json"),
+            retry_failed_from: source.get_optional("RETRY_FAILED_FROM"),
+            input_ids_file: source.get_optional("INPUT_IDS_FILE"),
+            input_ids_url: source.get_optional("INPUT_IDS_URL"),
+            write_formats_summary: source.get_flag("WRITE_FORMATS_SUMMARY", false),
+            write_resources: source.get_flag("WRITE_RESOURCES", false),
+            dump_raw_json: source.get_flag("DUMP_RAW_JSON", false),
+            preflight: source.get_flag("PREFLIGHT", true),
+            validate_urls: source.get_flag("VALIDATE_URLS", false),
+            url_check_concurrency: source
+                .get_or_default("URL_CHECK_CONCURRENCY", "20")
                 .parse()
                 .unwrap_or(20),
+            config_file_error: source.error.clone(),
         }
     }
 
     /// Validate the configuration, returning an error if any required value is missing or invalid.
     pub fn validate(&self) -> Result<(), crate::error::AppError> {
-        if self.ckan_api_base_url.trim().is_empty() {
-            return Err(crate::error::AppError::Config(
-                "CKAN API base URL must not be empty".to_string(),
-            ));
+        if let Some(error) = &self.config_file_error {
+            return Err(crate::error::AppError::Config(error.clone()));
+        }
+        for base_url in self.ckan_base_urls() {
+            Self::validate_base_url(base_url)?;
+        }
+        for (name, value) in [
+            ("CKAN_LIST_ACTION", &self.ckan_list_action),
+            ("CKAN_SHOW_ACTION", &self.ckan_show_action),
+            ("CKAN_ID_PARAM", &self.ckan_id_param),
+        ] {
+            if value.trim().trim_matches('/').is_empty() {
+                return Err(crate::error::AppError::Config(format!(
+                    "{name} must not be empty"
+                )));
+            }
+        }
+        if let Some(params) = &self.extra_show_params {
+            Self::validate_extra_show_params(params)?;
+        }
+        if let Some(proxy_url) = &self.ckan_proxy_url {
+            // The URL may carry credentials, so it is left out of the message.
+            reqwest::Proxy::all(proxy_url.as_str()).map_err(|e| {
+                crate::error::AppError::Config(format!(
+                    "CKAN_PROXY_URL is not a valid proxy URL: {e}"
+                ))
+            })?;
         }
         if self.bucket_name.trim().is_empty() {
             return Err(crate::error::AppError::Config(
@@ -60,37 +938,528 @@ impl Config {
                 "CSV file name must not be empty".to_string(),
             ));
         }
+        if self.resume && self.checkpoint_interval == 0 {
+            // With no interval nothing is ever saved, so there would be nothing to resume from.
+            return Err(crate::error::AppError::Config(
+                "RESUME requires CHECKPOINT_INTERVAL to be greater than zero".to_string(),
+            ));
+        }
+        if let Some(since) = &self.modified_since {
+            DateTime::parse_from_rfc3339(since.trim()).map_err(|e| {
+                crate::error::AppError::Config(format!(
+                    "MODIFIED_SINCE must be an RFC 3339 timestamp, got '{since}': {e}"
+                ))
+            })?;
+        }
+        if self.batch_size == Some(0) {
+            // Only reachable from JSON; BATCH_SIZE=0 in the environment turns batching off.
+            return Err(crate::error::AppError::Config(
+                "Batch size must be greater than zero".to_string(),
+            ));
+        }
         if self.concurrency_limit == 0 {
             return Err(crate::error::AppError::Config(
                 "Concurrency limit must be greater than zero".to_string(),
             ));
         }
+        if let Some(limits) = &self.concurrency_limit_per_source {
+            let limits: HashMap<String, usize> = serde_json::from_str(limits).map_err(|e| {
+                crate::error::AppError::Config(format!(
+                    "CONCURRENCY_LIMIT_PER_SOURCE must be a JSON object of host to limit: {e}"
+                ))
+            })?;
+            if let Some((host, _)) = limits.iter().find(|(_, limit)| **limit == 0) {
+                return Err(crate::error::AppError::Config(format!(
+                    "Concurrency limit for '{host}' in CONCURRENCY_LIMIT_PER_SOURCE must be greater than zero"
+                )));
+            }
+        }
+        let mut requested = std::collections::HashSet::new();
+        for column in &self.columns {
+            if !crate::DatasetMetadata::COLUMNS.contains(&column.as_str()) {
+                return Err(crate::error::AppError::Config(format!(
+                    "Unknown column '{column}' in COLUMNS; expected some of: {}",
+                    crate::DatasetMetadata::COLUMNS.join(", ")
+                )));
+            }
+            if !requested.insert(column) {
+                return Err(crate::error::AppError::Config(format!(
+                    "Column '{column}' is listed more than once in COLUMNS"
+                )));
+            }
+        }
+        if self.s3_upload_concurrency == 0 {
+            return Err(crate::error::AppError::Config(
+                "S3 upload concurrency must be greater than zero".to_string(),
+            ));
+        }
+        if self.http_connect_timeout_secs == 0 {
+            return Err(crate::error::AppError::Config(
+                "HTTP connect timeout must be greater than zero".to_string(),
+            ));
+        }
+        if self.input_ids_file.is_some() || self.input_ids_url.is_some() {
+            self.validate_input_ids()?;
+        }
+        if self.use_conditional_get && self.ckan_base_urls().len() > 1 {
+            return Err(crate::error::AppError::Config(
+                "USE_CONDITIONAL_GET needs a single CKAN API base URL".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.max_failure_ratio) {
+            return Err(crate::error::AppError::Config(
+                "MAX_FAILURE_RATIO must be between 0 and 1".to_string(),
+            ));
+        }
+        if self
+            .max_datasets
+            .is_some_and(|max| max < self.min_expected_datasets)
+        {
+            return Err(crate::error::AppError::Config(
+                "MAX_DATASETS must not be below MIN_EXPECTED_DATASETS".to_string(),
+            ));
+        }
+        if self.validate_urls && self.url_check_concurrency == 0 {
+            return Err(crate::error::AppError::Config(
+                "URL check concurrency must be greater than zero".to_string(),
+            ));
+        }
+        if self.output_format.parse::<OutputFormat>()? == OutputFormat::Dynamodb
+            && self.dynamodb_table.is_none()
+        {
+            return Err(crate::error::AppError::Config(
+                "DYNAMODB_TABLE must be set when OUTPUT_FORMAT is dynamodb".to_string(),
+            ));
+        }
+        if self.split_by_org && self.output_format.parse::<OutputFormat>()? != OutputFormat::Csv {
+            return Err(crate::error::AppError::Config(
+                "SPLIT_BY_ORG requires OUTPUT_FORMAT to be csv".to_string(),
+            ));
+        }
+        if self.streaming_csv && self.output_format.parse::<OutputFormat>()? == OutputFormat::Csv {
+            // The streaming writer only produces the main CSV with fixed URL columns.
+            let json_urls = self.url_column_mode.parse::<UrlColumnMode>()? == UrlColumnMode::Json;
+            for (enabled, setting) in [
+                (self.validate_urls, "VALIDATE_URLS"),
+                (self.normalize_tags, "NORMALIZE_TAGS"),
+                (self.write_resources, "WRITE_RESOURCES"),
+                (self.split_by_org, "SPLIT_BY_ORG"),
+                (self.incremental, "INCREMENTAL"),
+                (self.dump_raw_json, "DUMP_RAW_JSON"),
+                (json_urls, "URL_COLUMN_MODE=json"),
+                (self.max_url_columns.is_some(), "MAX_URL_COLUMNS"),
+            ] {
+                if enabled {
+                    return Err(crate::error::AppError::Config(format!(
+                        "{setting} is not supported with STREAMING_CSV"
+                    )));
+                }
+            }
+        }
+        if self.incremental {
+            self.validate_previous_baseline("INCREMENTAL")?;
+        }
+        if self.diff_previous {
+            self.validate_previous_baseline("DIFF_PREVIOUS")?;
+        }
+        self.output_dest.parse::<OutputDest>()?;
+        self.sort_by.parse::<SortBy>()?;
+        self.description_mode.parse::<DescriptionMode>()?;
+        self.log_format.parse::<LogFormat>()?;
+        self.csv_quote_style.parse::<CsvQuoteStyle>()?;
+        self.csv_terminator.parse::<CsvTerminator>()?;
+        self.url_column_mode.parse::<UrlColumnMode>()?;
+        if !StorageClass::values().contains(&self.s3_storage_class.as_str()) {
+            return Err(crate::error::AppError::Config(format!(
+                "Unknown S3 storage class '{}' (expected one of {})",
+                self.s3_storage_class,
+                StorageClass::values().join(", ")
+            )));
+        }
+        match self.s3_sse.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("aes256") => {}
+            Some("aws:kms") if self.s3_kms_key_id.is_none() => {
+                return Err(crate::error::AppError::Config(
+                    "S3_KMS_KEY_ID must be set when S3_SSE is aws:kms".to_string(),
+                ));
+            }
+            Some("aws:kms") => {}
+            Some(other) => {
+                return Err(crate::error::AppError::Config(format!(
+                    "Unknown S3 server-side encryption '{other}' (expected aes256 or aws:kms)"
+                )));
+            }
+        }
+        if self.delimiter_bytes().len() != 1 {
+            return Err(crate::error::AppError::Config(format!(
+                "CSV delimiter must be exactly one byte, got '{}'",
+                self.csv_delimiter
+            )));
+        }
         Ok(())
     }
 
-    /// Helper to get an environment variable or use a default value if not set.
-    fn get_env_or_default(var: &str, default: &str) -> String {
-        std::env::var(var).unwrap_or_else(|_| default.to_string())
+    /// Returns the CSV file path. In AWS Lambda, always use /tmp/ (the only writable directory).
+    fn get_csv_file(source: &ConfigSource) -> String {
+        let filename = source.get_or_default("CSV_FILE", "DataGovUK_Datasets.csv");
+        Self::resolve_csv_file(
+            &filename,
+            Utc::now(),
+            std::env::var("LAMBDA_TASK_ROOT").is_ok(),
+        )
     }
 
-    /// Returns the CSV file path. In AWS Lambda, always use /tmp/ (the only writable directory).
-    fn get_csv_file() -> String {
-        let filename = Self::get_env_or_default("CSV_FILE", "DataGovUK_Datasets.csv");
+    /// Expands `{date}` (`YYYYMMDD`) and `{datetime}` (`YYYYMMDDTHHMMSSZ`) in a CSV file name
+    /// template using the given UTC time, and prefixes `/tmp/` when running in Lambda.
+    /// Names without placeholders are used as-is.
+    pub fn resolve_csv_file(template: &str, now: DateTime<Utc>, in_lambda: bool) -> String {
+        let filename = template
+            .replace("{datetime}", &now.format("%Y%m%dT%H%M%SZ").to_string())
+            .replace("{date}", &now.format("%Y%m%d").to_string());
         // If running in Lambda, always use /tmp/
-        if std::env::var("LAMBDA_TASK_ROOT").is_ok() {
+        if in_lambda {
             format!("/tmp/{filename}")
         } else {
             filename
         }
     }
 
+    /// Get the local output file path: `csv_file` with its extension replaced to match the
+    /// output format (e.g. `DataGovUK_Datasets.parquet`). CSV output uses `csv_file` unchanged.
+    pub fn output_file(&self) -> String {
+        let format: OutputFormat = self.output_format.parse().unwrap_or_default();
+        if format == OutputFormat::Csv {
+            return self.csv_file.clone();
+        }
+        let file_name_start = self.csv_file.rfind('/').map_or(0, |i| i + 1);
+        let stem = match self.csv_file[file_name_start..].rfind('.') {
+            Some(dot) => &self.csv_file[..file_name_start + dot],
+            None => &self.csv_file,
+        };
+        format!("{stem}.{}", format.extension())
+    }
+
+    /// Get the local path of the normalised tags file, alongside the output file.
+    pub fn tags_file(&self) -> String {
+        self.sibling_file(TAGS_FILE_NAME)
+    }
+
+    /// Get the local path of the resource format counts file, alongside the output file.
+    pub fn formats_summary_file(&self) -> String {
+        self.sibling_file(FORMATS_SUMMARY_FILE_NAME)
+    }
+
+    /// Get the local path of the resources file, alongside the output file.
+    pub fn resources_file(&self) -> String {
+        self.sibling_file(RESOURCES_FILE_NAME)
+    }
+
+    /// Get the local path of the incremental delta CSV, alongside the output file.
+    pub fn delta_file(&self) -> String {
+        self.sibling_file(DELTA_FILE_NAME)
+    }
+
+    /// Get the local path of the raw JSON dump, alongside the output file.
+    pub fn raw_json_file(&self) -> String {
+        self.sibling_file(RAW_JSON_FILE_NAME)
+    }
+
+    /// Get the local path of the CSV for one organisation in `SPLIT_BY_ORG` mode,
+    /// e.g. `department-for-transport_DataGovUK_Datasets.csv` alongside the output file.
+    pub fn org_file(&self, slug: &str) -> String {
+        self.sibling_file(&format!("{slug}_{}", self.output_file_name()))
+    }
+
+    /// Get the S3 file name (before the key prefix) for one organisation's CSV,
+    /// e.g. `org=department-for-transport/DataGovUK_Datasets.csv`.
+    pub fn org_file_key(&self, slug: &str) -> String {
+        format!("org={slug}/{}", self.output_file_name())
+    }
+
+    /// Returns the path of `file_name` in the same directory as the output file.
+    fn sibling_file(&self, file_name: &str) -> String {
+        match self.csv_file.rfind('/') {
+            Some(i) => format!("{}{file_name}", &self.csv_file[..=i]),
+            None => file_name.to_string(),
+        }
+    }
+
+    /// Get the Content-Type for the uploaded output file. Tab-delimited CSV is sent as
+    /// `text/tab-separated-values`.
+    pub fn output_content_type(&self) -> &'static str {
+        let format: OutputFormat = self.output_format.parse().unwrap_or_default();
+        if format == OutputFormat::Csv && self.csv_delimiter_byte() == b'\t' {
+            return "text/tab-separated-values";
+        }
+        format.content_type()
+    }
+
+    /// Get the output file name without any directory.
+    fn output_file_name(&self) -> String {
+        let output_file = self.output_file();
+        output_file
+            .split('/')
+            .next_back()
+            .unwrap_or(&output_file)
+            .to_string()
+    }
+
+    /// Joins the configured key prefix (if any) onto the given S3 key.
+    pub fn prefixed_key(&self, key: &str) -> String {
+        let prefix = self.key_prefix.trim_matches('/');
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{prefix}/{key}")
+        }
+    }
+
+    /// Get the stable S3 object key for the output file.
+    /// When partitioning by date this is the `latest/` copy that downstream jobs read.
+    pub fn s3_key(&self) -> String {
+        if self.partition_by_date {
+            self.prefixed_key(&format!("latest/{}", self.output_file_name()))
+        } else {
+            self.prefixed_key(&self.output_file_name())
+        }
+    }
+
+    /// Get the date-partitioned, timestamped S3 key for a run at the given time, e.g.
+    /// `{prefix}/year=2024/month=01/day=31/DataGovUK_Datasets_20240131T120000Z.csv`.
+    pub fn partitioned_s3_key(&self, now: DateTime<Utc>) -> String {
+        let file_name = self.output_file_name();
+        let (stem, extension) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) => (stem, format!(".{ext}")),
+            None => (file_name.as_str(), String::new()),
+        };
+        self.prefixed_key(&format!(
+            "{}/{stem}_{}{extension}",
+            now.format("year=%Y/month=%m/day=%d"),
+            now.format("%Y%m%dT%H%M%SZ")
+        ))
+    }
+
+    /// Get the configured delimiter bytes, with `\t` unescaped to a tab.
+    fn delimiter_bytes(&self) -> &[u8] {
+        match self.csv_delimiter.as_str() {
+            "\\t" => b"\t",
+            other => other.as_bytes(),
+        }
+    }
+
+    /// Get the CSV delimiter byte (defaults to a comma if the configured value is invalid).
+    pub fn csv_delimiter_byte(&self) -> u8 {
+        match self.delimiter_bytes() {
+            [byte] => *byte,
+            _ => b',',
+        }
+    }
+
+    /// Checks that the previous upload can be read back as a baseline, which `setting` needs:
+    /// a single CSV with `id` and `modified` columns.
+    fn validate_previous_baseline(&self, setting: &str) -> Result<(), crate::error::AppError> {
+        if self.output_format.parse::<OutputFormat>()? != OutputFormat::Csv || self.split_by_org {
+            return Err(crate::error::AppError::Config(format!(
+                "{setting} requires OUTPUT_FORMAT to be csv without SPLIT_BY_ORG"
+            )));
+        }
+        let has_column =
+            |name: &str| self.columns.is_empty() || self.columns.iter().any(|c| c == name);
+        if !has_column("id") || !has_column("modified") {
+            return Err(crate::error::AppError::Config(format!(
+                "{setting} requires COLUMNS to include id and modified"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `EXTRA_SHOW_PARAMS` is a list of `key=value` pairs joined with `&`, using only
+    /// characters that are safe in a query string unencoded (anything else must be
+    /// percent-encoded).
+    fn validate_extra_show_params(params: &str) -> Result<(), crate::error::AppError> {
+        let is_encoded = |text: &str| {
+            let bytes = text.as_bytes();
+            let mut i = 0;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'%' if bytes
+                        .get(i + 1..i + 3)
+                        .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) =>
+                    {
+                        i += 3;
+                        continue;
+                    }
+                    b if b.is_ascii_alphanumeric() || b"-._~+,:".contains(&b) => {}
+                    _ => return false,
+                }
+                i += 1;
+            }
+            true
+        };
+        for pair in params.split('&') {
+            let valid = match pair.split_once('=') {
+                Some((key, value)) => !key.is_empty() && is_encoded(key) && is_encoded(value),
+                None => false,
+            };
+            if !valid {
+                return Err(crate::error::AppError::Config(format!(
+                    "EXTRA_SHOW_PARAMS must be URL-encoded key=value pairs joined with '&'; got '{pair}'"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that at most one id list source is set, that `INPUT_IDS_URL` is an S3 URL, and
+    /// that the ids can only belong to one CKAN portal.
+    fn validate_input_ids(&self) -> Result<(), crate::error::AppError> {
+        let error = |message: &str| Err(crate::error::AppError::Config(message.to_string()));
+        if self.input_ids_file.is_some() && self.input_ids_url.is_some() {
+            return error("Set only one of INPUT_IDS_FILE and INPUT_IDS_URL");
+        }
+        if self.retry_failed_from.is_some() {
+            return error("INPUT_IDS_FILE/INPUT_IDS_URL cannot be combined with RETRY_FAILED_FROM");
+        }
+        if self.ckan_base_urls().len() > 1 {
+            return error("INPUT_IDS_FILE/INPUT_IDS_URL need a single CKAN API base URL");
+        }
+        if self.input_ids_url.is_some() && self.input_ids_s3_location().is_none() {
+            return error("INPUT_IDS_URL must be an s3://bucket/key URL");
+        }
+        Ok(())
+    }
+
+    /// The `MODIFIED_SINCE` threshold in UTC, if it is set and valid.
+    pub fn modified_since_threshold(&self) -> Option<DateTime<Utc>> {
+        let since = DateTime::parse_from_rfc3339(self.modified_since.as_deref()?.trim()).ok()?;
+        Some(since.with_timezone(&Utc))
+    }
+
+    /// The bucket and key of `INPUT_IDS_URL`, if it is set and of the form `s3://bucket/key`.
+    pub fn input_ids_s3_location(&self) -> Option<(&str, &str)> {
+        let (bucket, key) = self
+            .input_ids_url
+            .as_deref()?
+            .strip_prefix("s3://")?
+            .split_once('/')?;
+        (!bucket.is_empty() && !key.is_empty()).then_some((bucket, key))
+    }
+
+    /// Checks that a CKAN API base URL is a non-empty http(s) URL with a host.
+    fn validate_base_url(base_url: &str) -> Result<(), crate::error::AppError> {
+        if base_url.trim().is_empty() {
+            return Err(crate::error::AppError::Config(
+                "CKAN API base URL must not be empty".to_string(),
+            ));
+        }
+        let url = url::Url::parse(base_url).map_err(|e| {
+            crate::error::AppError::Config(format!(
+                "CKAN API base URL '{base_url}' is not a valid URL: {e}"
+            ))
+        })?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(crate::error::AppError::Config(format!(
+                "CKAN API base URL must use http or https, got '{}'",
+                url.scheme()
+            )));
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(crate::error::AppError::Config(
+                "CKAN API base URL must include a host".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The CKAN API base URLs to fetch from: `CKAN_API_BASE_URLS` if set, otherwise the single
+    /// `CKAN_API_BASE_URL`.
+    pub fn ckan_base_urls(&self) -> Vec<&str> {
+        if self.ckan_api_base_urls.is_empty() {
+            vec![self.ckan_api_base_url.as_str()]
+        } else {
+            self.ckan_api_base_urls.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Returns a copy of this config that fetches from the given CKAN API base URL.
+    pub fn for_ckan_source(&self, base_url: &str) -> Config {
+        Config {
+            ckan_api_base_url: base_url.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Name of the portal this config fetches from, written to the `source` column:
+    /// the host (and explicit port, if any) of the CKAN API base URL.
+    pub fn ckan_source_name(&self) -> String {
+        let Ok(url) = url::Url::parse(&self.ckan_api_base_url) else {
+            return self.ckan_api_base_url.clone();
+        };
+        match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => self.ckan_api_base_url.clone(),
+        }
+    }
+
+    /// The concurrency limit for this config's CKAN portal: its entry in
+    /// `CONCURRENCY_LIMIT_PER_SOURCE` if there is one, otherwise `concurrency_limit`.
+    pub fn source_concurrency_limit(&self) -> usize {
+        self.concurrency_limit_per_source
+            .as_deref()
+            .and_then(|limits| serde_json::from_str::<HashMap<String, usize>>(limits).ok())
+            .and_then(|limits| limits.get(&self.ckan_source_name()).copied())
+            .unwrap_or(self.concurrency_limit)
+    }
+
+    /// Get the base URL of the portal's human-facing pages: `CKAN_PORTAL_BASE_URL` if set,
+    /// otherwise the API base URL with its `/api/...` suffix removed.
+    pub fn portal_base_url(&self) -> &str {
+        match &self.ckan_portal_base_url {
+            Some(url) => url.trim_end_matches('/'),
+            None => {
+                let api = self.api_base_url();
+                api.find("/api/")
+                    .or_else(|| api.strip_suffix("/api").map(str::len))
+                    .map_or(api, |i| &api[..i])
+            }
+        }
+    }
+
+    /// Get the landing page URL of a dataset, from its name (slug) or id.
+    pub fn landing_page_url(&self, name: &str) -> String {
+        format!("{}/dataset/{name}", self.portal_base_url())
+    }
+
+    /// Get the CKAN API base URL without any trailing slash.
+    fn api_base_url(&self) -> &str {
+        self.ckan_api_base_url.trim_end_matches('/')
+    }
+
     /// Get the CKAN dataset list URL.
     pub fn dataset_list_url(&self) -> String {
-        format!("{}/package_list", self.ckan_api_base_url)
+        format!(
+            "{}/{}",
+            self.api_base_url(),
+            self.ckan_list_action.trim_matches('/')
+        )
+    }
+
+    /// Get the full CKAN dataset metadata URL for one dataset, including `EXTRA_SHOW_PARAMS`.
+    pub fn dataset_metadata_url_for(&self, dataset_id: &str) -> String {
+        match &self.extra_show_params {
+            Some(params) => format!("{}{dataset_id}&{params}", self.dataset_metadata_url()),
+            None => format!("{}{dataset_id}", self.dataset_metadata_url()),
+        }
     }
 
-    /// Get the CKAN dataset metadata URL prefix.
+    /// Get the CKAN dataset metadata URL prefix; the dataset id is appended to it.
     pub fn dataset_metadata_url(&self) -> String {
-        format!("{}/package_show?id=", self.ckan_api_base_url)
+        format!(
+            "{}/{}?{}=",
+            self.api_base_url(),
+            self.ckan_show_action.trim_matches('/'),
+            self.ckan_id_param
+        )
     }
 }
@@ -1,59 +1,432 @@
-use crate::config::Config;
+use crate::ckan::ResourceUrls;
+use crate::config::{Config, CsvQuoteStyle, CsvTerminator, UrlColumnMode};
 use crate::error::AppError;
-use crate::DatasetMetadata;
+use crate::{organization_slug, DatasetMetadata};
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::io::Write;
+use tokio::sync::mpsc::Receiver;
+use tracing::{instrument, warn};
+
+/// The UTF-8 byte order mark written at the start of the CSV when `ADD_BOM` is set.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
 
 /// Writes the dataset metadata to a CSV file with one column per download URL.
 /// The number of download_url columns is determined by the dataset with the most URLs.
+/// If any resource has a size or last-modified value, each URL column is followed by
+/// url_size_N and url_modified_N columns; otherwise only the download_url_N columns are written.
+/// When the URLs have been validated, each URL column is also followed by a url_status_N column.
+/// With `max_url_columns` set, at most that many URL columns are written and any further URLs
+/// are collapsed into a final `extra_urls` column holding a JSON array.
+/// With `URL_COLUMN_MODE=json`, all of this is replaced by a single `download_urls` column.
+/// With `COLUMNS` set, only those metadata columns are written, in that order, before the URLs.
 /// This function ensures the CSV is easy to use in Excel or other tools.
+/// Returns the number of data rows written, which should always equal `dataset_metadata.len()`.
+#[instrument(skip_all, fields(phase = "write"))]
 pub fn write_csv(
     config: &Config,
-    dataset_metadata: &[(DatasetMetadata, Vec<String>)],
-) -> Result<(), AppError> {
-    // Find the maximum number of download URLs in any dataset for column generation.
-    let max_urls = dataset_metadata
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    if config.include_urls
+        && config
+            .url_column_mode
+            .parse::<UrlColumnMode>()
+            .unwrap_or_default()
+            == UrlColumnMode::Json
+    {
+        return write_csv_json_urls(config, dataset_metadata);
+    }
+    // Find the maximum number of download URLs in any dataset for column generation
+    // (none at all when URLs are excluded).
+    let widest = dataset_metadata
         .iter()
-        .map(|(_, urls)| urls.len())
+        .map(|(_, urls)| urls.urls.len())
         .max()
         .unwrap_or(0);
-    let file = File::create(&config.csv_file)?;
-    let mut wtr = csv::Writer::from_writer(file);
-    // Write the CSV header, including download_url_1, download_url_2, ...
-    let mut header = vec![
-        "id".to_string(),
-        "title".to_string(),
-        "description".to_string(),
-        "license".to_string(),
-        "organization".to_string(),
-        "created".to_string(),
-        "modified".to_string(),
-        "format".to_string(),
-    ];
-    for i in 1..=max_urls {
-        header.push(format!("download_url_{i}"));
+    let widest = if config.include_urls { widest } else { 0 };
+    let max_urls = config.max_url_columns.map_or(widest, |cap| widest.min(cap));
+    let include_extra = widest > max_urls;
+    // Only emit the size/modified columns when there is something to put in them.
+    let include_details = dataset_metadata.iter().any(|(_, urls)| urls.has_details());
+    let include_status = dataset_metadata.iter().any(|(_, urls)| urls.has_statuses());
+    let columns = metadata_columns(config);
+    let mut wtr = create_csv_writer(config, &config.csv_file)?;
+    let mut header = csv_header(&columns, max_urls, include_details, include_status);
+    if include_extra {
+        header.push("extra_urls".to_string());
     }
-    wtr.write_record(&header)?;
-    // Write each row, padding with empty strings if there are fewer URLs than max_urls.
+    wtr.write_record(header)?;
+    let mut collapsed_rows = 0;
+    let mut rows = 0;
     for (meta, urls) in dataset_metadata {
-        let mut row = vec![
-            meta.id.clone(),
-            meta.title.clone(),
-            meta.description.clone(),
-            meta.license.clone(),
-            meta.organization.clone(),
-            meta.created.clone(),
-            meta.modified.clone(),
-            meta.format.clone(),
-        ];
-        for i in 0..max_urls {
-            if i < urls.len() {
-                row.push(urls[i].clone());
-            } else {
+        let mut row = csv_row(
+            meta,
+            &columns,
+            urls,
+            max_urls,
+            include_details,
+            include_status,
+        );
+        if include_extra {
+            let extra = urls.urls.get(max_urls..).unwrap_or_default();
+            if extra.is_empty() {
                 row.push(String::new());
+            } else {
+                collapsed_rows += 1;
+                row.push(serde_json::to_string(extra)?);
             }
         }
-        wtr.write_record(&row)?;
+        wtr.write_record(row)?;
+        rows += 1;
+    }
+    wtr.flush()?;
+    if collapsed_rows > 0 {
+        warn!(
+            "{} datasets had more than {} download URLs; the rest were written to extra_urls",
+            collapsed_rows, max_urls
+        );
+    }
+    Ok(rows)
+}
+
+/// Writes the given rows (the new or changed datasets in `INCREMENTAL` mode) to the delta CSV,
+/// with the same columns and formatting as the CSV output. Returns the number of rows written.
+pub fn write_delta_csv(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    let mut delta_config = config.clone();
+    delta_config.csv_file = config.delta_file();
+    delta_config.output_format = "csv".to_string();
+    write_csv(&delta_config, dataset_metadata)
+}
+
+/// Writes the CSV with the metadata columns followed by a single `download_urls` column holding
+/// each dataset's URLs as a JSON array (`[]` when it has none), so the column count is fixed.
+fn write_csv_json_urls(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    let columns = metadata_columns(config);
+    let mut wtr = create_csv_writer(config, &config.csv_file)?;
+    let mut header = csv_header(&columns, 0, false, false);
+    header.push("download_urls".to_string());
+    wtr.write_record(header)?;
+    let mut rows = 0;
+    for (meta, urls) in dataset_metadata {
+        let mut row = csv_row(meta, &columns, urls, 0, false, false);
+        row.push(serde_json::to_string(&urls.urls)?);
+        wtr.write_record(row)?;
+        rows += 1;
+    }
+    wtr.flush()?;
+    Ok(rows)
+}
+
+/// Re-reads a written CSV and checks it before upload: the header must start with the
+/// configured metadata columns and every row must have an `id` (if the `id` column is written).
+/// Catches schema drift before a broken file reaches downstream consumers. Returns the number
+/// of rows checked.
+pub fn validate_csv(config: &Config, path: &str) -> Result<usize, AppError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(config.csv_delimiter_byte())
+        .from_path(path)?;
+    let header = rdr.headers()?.clone();
+    let fixed: Vec<&str> = metadata_columns(config)
+        .into_iter()
+        .map(|i| DatasetMetadata::COLUMNS[i])
+        .collect();
+    let id_column = fixed.iter().position(|column| *column == "id");
+    if header.len() < fixed.len() || header.iter().zip(&fixed).any(|(got, want)| got != *want) {
+        return Err(AppError::Other(format!(
+            "CSV validation failed for {path}: header does not start with the expected columns ({})",
+            fixed.join(", ")
+        )));
+    }
+    let mut rows = 0;
+    for record in rdr.records() {
+        let record = record?;
+        rows += 1;
+        if id_column.is_some_and(|i| record.get(i).is_none_or(|id| id.trim().is_empty())) {
+            return Err(AppError::Other(format!(
+                "CSV validation failed for {path}: row {rows} has an empty id"
+            )));
+        }
+    }
+    Ok(rows)
+}
+
+/// One per-organisation CSV written in `SPLIT_BY_ORG` mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrgFile {
+    /// Filename-safe organisation slug, e.g. `department-for-transport`
+    pub slug: String,
+    /// Local path of the CSV
+    pub path: String,
+    /// Number of datasets written to it
+    pub rows: usize,
+}
+
+/// Writes one CSV per organisation (`config.org_file(slug)`), each laid out as `write_csv` would
+/// lay it out for just that organisation's datasets. Datasets without an organisation go to
+/// the `unknown` file. Files are returned in slug order.
+pub fn write_org_csvs(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<Vec<OrgFile>, AppError> {
+    let mut by_org: BTreeMap<String, Vec<(DatasetMetadata, ResourceUrls)>> = BTreeMap::new();
+    for row in dataset_metadata {
+        let slug = match organization_slug(&row.0.organization) {
+            slug if slug.is_empty() => "unknown".to_string(),
+            slug => slug,
+        };
+        by_org.entry(slug).or_default().push(row.clone());
+    }
+    let mut files = Vec::with_capacity(by_org.len());
+    for (slug, rows) in by_org {
+        let mut org_config = config.clone();
+        org_config.csv_file = config.org_file(&slug);
+        let written = write_csv(&org_config, &rows)?;
+        files.push(OrgFile {
+            slug,
+            path: org_config.csv_file,
+            rows: written,
+        });
+    }
+    Ok(files)
+}
+
+/// Writes the normalised tags file (`config.tags_file()`) with a `(dataset_id, tag)` row for
+/// every tag, for joining against the main output. Returns the number of tag rows written.
+/// Tags are split back out of the comma-separated `tags` column; CKAN tag names cannot
+/// contain commas, so this is lossless.
+pub fn write_tags_csv(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    let mut wtr = create_csv_writer(config, &config.tags_file())?;
+    wtr.write_record(["dataset_id", "tag"])?;
+    let mut rows = 0;
+    for (meta, _) in dataset_metadata {
+        for tag in meta.tags.split(", ").filter(|tag| !tag.is_empty()) {
+            wtr.write_record([meta.id.as_str(), tag])?;
+            rows += 1;
+        }
+    }
+    wtr.flush()?;
+    Ok(rows)
+}
+
+/// Writes the resources file (`config.resources_file()`) with a `(dataset_id, resource_url,
+/// format, size, last_modified, mimetype)` row for every resource that has a URL.
+/// Returns the number of resource rows written.
+pub fn write_resources_csv(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    let mut wtr = create_csv_writer(config, &config.resources_file())?;
+    wtr.write_record([
+        "dataset_id",
+        "resource_url",
+        "format",
+        "size",
+        "last_modified",
+        "mimetype",
+    ])?;
+    let mut rows = 0;
+    for (meta, _) in dataset_metadata {
+        for res in &meta.extras.resources {
+            wtr.write_record([
+                meta.id.as_str(),
+                &res.url,
+                &res.format,
+                &res.size,
+                &res.last_modified,
+                &res.mimetype,
+            ])?;
+            rows += 1;
+        }
+    }
+    wtr.flush()?;
+    Ok(rows)
+}
+
+/// Writes the resource format counts file (`config.formats_summary_file()`) with a
+/// `(format, count)` row per format, most common first.
+pub fn write_formats_csv(
+    config: &Config,
+    resource_formats: &BTreeMap<String, usize>,
+) -> Result<(), AppError> {
+    let mut counts: Vec<(&String, &usize)> = resource_formats.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1));
+    let mut wtr = create_csv_writer(config, &config.formats_summary_file())?;
+    wtr.write_record(["format", "count"])?;
+    for (format, count) in counts {
+        wtr.write_record([format.as_str(), count.to_string().as_str()])?;
     }
     wtr.flush()?;
     Ok(())
 }
+
+/// Writes CSV rows one at a time as metadata arrives, so the whole catalogue never has to be
+/// held in memory. Uses a fixed number of URL columns (`streaming_url_columns`) instead of
+/// pre-scanning for the widest dataset, and always writes the url_size_N/url_modified_N columns.
+pub struct StreamingCsvWriter {
+    wtr: csv::Writer<File>,
+    columns: Vec<usize>,
+    max_urls: usize,
+    rows_written: usize,
+    truncated_rows: usize,
+}
+
+impl StreamingCsvWriter {
+    /// Creates the CSV file at `config.csv_file` and writes the header.
+    pub fn create(config: &Config) -> Result<Self, AppError> {
+        let max_urls = if config.include_urls {
+            config.streaming_url_columns
+        } else {
+            0
+        };
+        let columns = metadata_columns(config);
+        let mut wtr = create_csv_writer(config, &config.csv_file)?;
+        wtr.write_record(csv_header(&columns, max_urls, true, false))?;
+        Ok(Self {
+            wtr,
+            columns,
+            max_urls,
+            rows_written: 0,
+            truncated_rows: 0,
+        })
+    }
+
+    /// Writes a single row. URLs beyond the fixed column count are dropped.
+    pub fn write_row(
+        &mut self,
+        meta: &DatasetMetadata,
+        urls: &ResourceUrls,
+    ) -> Result<(), AppError> {
+        if urls.urls.len() > self.max_urls {
+            self.truncated_rows += 1;
+        }
+        self.wtr.write_record(csv_row(
+            meta,
+            &self.columns,
+            urls,
+            self.max_urls,
+            true,
+            false,
+        ))?;
+        self.rows_written += 1;
+        Ok(())
+    }
+
+    /// Writes every row received on the channel until all senders are dropped, then finishes
+    /// the file. Blocks on the channel, so run it with `spawn_blocking`.
+    pub fn write_from_channel(
+        mut self,
+        mut rx: Receiver<(DatasetMetadata, ResourceUrls)>,
+    ) -> Result<usize, AppError> {
+        while let Some((meta, urls)) = rx.blocking_recv() {
+            self.write_row(&meta, &urls)?;
+        }
+        self.finish()
+    }
+
+    /// Flushes the file and returns the number of rows written.
+    pub fn finish(mut self) -> Result<usize, AppError> {
+        self.wtr.flush()?;
+        if self.truncated_rows > 0 {
+            warn!(
+                "{} datasets had more than {} download URLs; extra URLs were dropped",
+                self.truncated_rows, self.max_urls
+            );
+        }
+        Ok(self.rows_written)
+    }
+}
+
+/// Creates a CSV file at `path` with the configured format, starting with a UTF-8 byte order
+/// mark when `ADD_BOM` is set so Excel on Windows reads the file as UTF-8.
+fn create_csv_writer(config: &Config, path: &str) -> Result<csv::Writer<File>, AppError> {
+    let mut file = File::create(path)?;
+    if config.add_bom {
+        file.write_all(UTF8_BOM)?;
+    }
+    Ok(csv_writer_builder(config).from_writer(file))
+}
+
+/// Returns a CSV writer builder with the configured delimiter, quote style and line ending.
+fn csv_writer_builder(config: &Config) -> csv::WriterBuilder {
+    let quote_style: CsvQuoteStyle = config.csv_quote_style.parse().unwrap_or_default();
+    let terminator: CsvTerminator = config.csv_terminator.parse().unwrap_or_default();
+    let mut builder = csv::WriterBuilder::new();
+    builder
+        .delimiter(config.csv_delimiter_byte())
+        .quote_style(quote_style.quote_style())
+        .terminator(terminator.terminator());
+    builder
+}
+
+/// Indices into `DatasetMetadata::COLUMNS` of the metadata columns to write, in order: the
+/// `COLUMNS` setting if given (validated to be known, distinct names), otherwise all of them.
+fn metadata_columns(config: &Config) -> Vec<usize> {
+    if config.columns.is_empty() {
+        return (0..DatasetMetadata::COLUMNS.len()).collect();
+    }
+    config
+        .columns
+        .iter()
+        .filter_map(|name| DatasetMetadata::COLUMNS.iter().position(|c| c == name))
+        .collect()
+}
+
+/// Builds the CSV header, including download_url_1, download_url_2, ...
+fn csv_header(
+    columns: &[usize],
+    max_urls: usize,
+    include_details: bool,
+    include_status: bool,
+) -> Vec<String> {
+    let mut header: Vec<String> = columns
+        .iter()
+        .map(|&i| DatasetMetadata::COLUMNS[i].to_string())
+        .collect();
+    for i in 1..=max_urls {
+        header.push(format!("download_url_{i}"));
+        if include_details {
+            header.push(format!("url_size_{i}"));
+            header.push(format!("url_modified_{i}"));
+        }
+        if include_status {
+            header.push(format!("url_status_{i}"));
+        }
+    }
+    header
+}
+
+/// Builds a CSV row, padding with empty strings if there are fewer URLs than max_urls.
+fn csv_row(
+    meta: &DatasetMetadata,
+    columns: &[usize],
+    urls: &ResourceUrls,
+    max_urls: usize,
+    include_details: bool,
+    include_status: bool,
+) -> Vec<String> {
+    let mut values = meta.values();
+    let mut row: Vec<String> = columns
+        .iter()
+        .map(|&i| std::mem::take(&mut values[i]))
+        .collect();
+    for i in 0..max_urls {
+        row.push(urls.urls.get(i).cloned().unwrap_or_default());
+        if include_details {
+            row.push(urls.sizes.get(i).cloned().unwrap_or_default());
+            row.push(urls.last_modified.get(i).cloned().unwrap_or_default());
+        }
+        if include_status {
+            row.push(urls.statuses.get(i).cloned().unwrap_or_default());
+        }
+    }
+    row
+}
@@ -0,0 +1,125 @@
+use crate::aws::load_aws_config;
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use aws_sdk_dynamodb::types::{AttributeValue, PutRequest, WriteRequest};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Maximum number of items DynamoDB accepts in a single `BatchWriteItem` call.
+const BATCH_SIZE: usize = 25;
+
+/// Number of times unprocessed items are resent before the write fails.
+const MAX_UNPROCESSED_RETRIES: u32 = 5;
+
+/// Base delay before resending unprocessed items, doubled on each retry.
+const UNPROCESSED_RETRY_BASE_DELAY_MS: u64 = 50;
+
+/// Creates a DynamoDB client using the shared AWS configuration.
+pub async fn create_dynamodb_client(config: &Config) -> DynamoDbClient {
+    DynamoDbClient::new(&load_aws_config(config).await)
+}
+
+/// Writes each dataset as an item keyed by `id` to the configured DynamoDB table, using
+/// `BatchWriteItem` in chunks of 25. Metadata fields are string attributes and the download
+/// URLs are a de-duplicated `download_urls` string set (omitted when a dataset has none, since
+/// DynamoDB sets cannot be empty). Items DynamoDB reports as unprocessed are resent with backoff.
+pub async fn write_dynamodb(
+    client: &DynamoDbClient,
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<(), AppError> {
+    let table = config
+        .dynamodb_table
+        .as_deref()
+        .ok_or_else(|| AppError::Config("DYNAMODB_TABLE is not set".to_string()))?;
+    for chunk in dataset_metadata.chunks(BATCH_SIZE) {
+        let requests = chunk
+            .iter()
+            .map(|(meta, urls)| dynamodb_write_request(meta, urls))
+            .collect::<Result<Vec<_>, _>>()?;
+        batch_write_with_retries(client, table, requests).await?;
+    }
+    info!(
+        "Wrote {} items to DynamoDB table {}",
+        dataset_metadata.len(),
+        table
+    );
+    Ok(())
+}
+
+/// Builds the put request for a single dataset.
+fn dynamodb_write_request(
+    meta: &DatasetMetadata,
+    urls: &ResourceUrls,
+) -> Result<WriteRequest, AppError> {
+    let mut item: HashMap<String, AttributeValue> = DatasetMetadata::COLUMNS
+        .iter()
+        .zip(meta.values())
+        .map(|(column, value)| (column.to_string(), AttributeValue::S(value)))
+        .collect();
+    // DynamoDB rejects a string set with duplicate or empty members, and CKAN can list the same
+    // URL on more than one resource.
+    let download_urls: BTreeSet<&String> = urls.urls.iter().filter(|url| !url.is_empty()).collect();
+    if !download_urls.is_empty() {
+        item.insert(
+            "download_urls".to_string(),
+            AttributeValue::Ss(download_urls.into_iter().cloned().collect()),
+        );
+    }
+    let put = PutRequest::builder()
+        .set_item(Some(item))
+        .build()
+        .map_err(|e| AppError::Other(format!("DynamoDB put request invalid: {e}")))?;
+    Ok(WriteRequest::builder().put_request(put).build())
+}
+
+/// Sends one batch, resending any unprocessed items with exponential backoff.
+async fn batch_write_with_retries(
+    client: &DynamoDbClient,
+    table: &str,
+    mut requests: Vec<WriteRequest>,
+) -> Result<(), AppError> {
+    let mut retries = 0;
+    loop {
+        let output = client
+            .batch_write_item()
+            .request_items(table, requests)
+            .send()
+            .await
+            .map_err(|e| {
+                AppError::Other(format!(
+                    "DynamoDB batch write failed: {}",
+                    DisplayErrorContext(&e)
+                ))
+            })?;
+        requests = output
+            .unprocessed_items
+            .and_then(|mut unprocessed| unprocessed.remove(table))
+            .unwrap_or_default();
+        if requests.is_empty() {
+            return Ok(());
+        }
+        if retries == MAX_UNPROCESSED_RETRIES {
+            return Err(AppError::Other(format!(
+                "DynamoDB left {} items unprocessed after {} retries",
+                requests.len(),
+                MAX_UNPROCESSED_RETRIES
+            )));
+        }
+        retries += 1;
+        let delay = Duration::from_millis(UNPROCESSED_RETRY_BASE_DELAY_MS << (retries - 1));
+        warn!(
+            "DynamoDB left {} items unprocessed, retrying in {} ms (retry {}/{})",
+            requests.len(),
+            delay.as_millis(),
+            retries,
+            MAX_UNPROCESSED_RETRIES
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -16,10 +16,54 @@ pub enum AppError {
     /// Serde JSON error (parsing CKAN responses)
     #[error("Serde JSON error: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    /// CKAN answered but reported `success: false` (the message includes CKAN's error object)
+    #[error("CKAN API error: {0}")]
+    Ckan(String),
     /// Configuration validation error (invalid or missing config values)
     #[error("Configuration error: {0}")]
     Config(String),
     /// Any other error (string message)
     #[error("Other error: {0}")]
     Other(String),
+    /// An error while handling a single dataset, tagged with the dataset id
+    #[error("Dataset {id}: {source}")]
+    Dataset {
+        /// The CKAN id of the dataset being handled
+        id: String,
+        /// The underlying error
+        source: Box<AppError>,
+    },
+}
+
+impl AppError {
+    /// Short category name for logs and run summaries, e.g. to tell a CKAN outage ("http")
+    /// apart from a CKAN schema change ("parse").
+    pub fn category(&self) -> &'static str {
+        match self {
+            AppError::Http(_) => "http",
+            AppError::Csv(_) => "csv",
+            AppError::Io(_) => "io",
+            AppError::SerdeJson(_) => "parse",
+            AppError::Ckan(_) => "ckan",
+            AppError::Config(_) => "config",
+            AppError::Other(_) => "other",
+            AppError::Dataset { source, .. } => source.category(),
+        }
+    }
+
+    /// Tags the error with the id of the dataset that caused it.
+    pub fn for_dataset(self, id: &str) -> Self {
+        AppError::Dataset {
+            id: id.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The underlying error, with any dataset context removed.
+    pub fn root(&self) -> &AppError {
+        match self {
+            AppError::Dataset { source, .. } => source.root(),
+            other => other,
+        }
+    }
 }
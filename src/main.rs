@@ -3,24 +3,70 @@
 use futures::stream::StreamExt; // For concurrent async processing
 use lambda_runtime::{run, service_fn, Error, LambdaEvent}; // Lambda runtime and event types
 use serde::{Deserialize, Serialize}; // For (de)serialising JSON and CSV
+use std::collections::{HashMap, HashSet}; // For de-duplicating dataset ids across portals
+use std::sync::atomic::{AtomicU64, Ordering}; // For counting downloaded bytes across tasks
 use std::sync::Arc; // For sharing HTTP client across tasks
-use tracing::{error, info}; // For structured logging
+use std::time::Instant; // For timing workflow phases
+use tracing::{error, info, info_span, warn, Instrument}; // For structured logging
+use tracing_subscriber::fmt::format::FmtSpan; // For logging phase spans as they close
 
+mod aws;
+mod checkpoint;
 mod ckan;
+mod concurrency_ramp;
 mod config;
 mod csv_writer;
+mod dynamodb_writer;
 mod error;
+mod metrics;
+mod metrics_server;
+mod notify;
+mod parquet_writer;
+mod raw_json_writer;
 mod s3_upload;
+mod shutdown;
+mod sqlite_writer;
+mod summary;
+mod url_check;
+mod xlsx_writer;
 
-use ckan::{create_http_client, fetch_dataset_list, fetch_dataset_metadata};
-use config::Config;
-use csv_writer::write_csv;
+use aws_sdk_s3::Client as S3Client;
+use checkpoint::{load_checkpoint, Checkpoint, SavedCheckpoint};
+use ckan::{
+    create_http_client, fetch_dataset_list, fetch_dataset_list_if_changed, fetch_dataset_metadata,
+    parse_input_ids, preflight, startup_jitter, DatasetExtras, DatasetList, ResourceUrls,
+    RetryBudget,
+};
+use concurrency_ramp::ConcurrencyRamp;
+use config::{
+    Config, CsvQuoteStyle, LogFormat, OutputDest, OutputFormat, SortBy, DEFAULT_METRICS_PORT,
+};
+use csv_writer::{
+    validate_csv, write_csv, write_delta_csv, write_formats_csv, write_org_csvs,
+    write_resources_csv, write_tags_csv, StreamingCsvWriter,
+};
+use dynamodb_writer::{create_dynamodb_client, write_dynamodb};
 use error::AppError;
-use s3_upload::upload_to_s3;
+use metrics::emit_metrics;
+use metrics_server::{start_metrics_server, PrometheusMetrics};
+use notify::{notify_run_result, SnsNotifier};
+use parquet_writer::write_parquet;
+use raw_json_writer::write_raw_json;
+use s3_upload::{
+    create_s3_client, fetch_failed_ids, fetch_input_ids_text, fetch_last_run_timestamps,
+    fetch_list_etag, upload_delta_file, upload_failed_ids, upload_formats_summary,
+    upload_list_etag, upload_manifest, upload_org_files, upload_raw_json, upload_resources_file,
+    upload_tags_file, upload_to_s3,
+};
+use shutdown::{shutdown_on_signal, Shutdown};
+use sqlite_writer::write_sqlite;
+use summary::{DatasetDiff, FailedDataset, RunManifest, RunSummary};
+use url_check::{check_download_urls, create_url_check_client};
+use xlsx_writer::write_xlsx;
 
 /// Struct for storing dataset metadata in CSV and S3.
 /// This is the main data structure written to the output CSV file.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatasetMetadata {
     /// Dataset ID
     pub id: String,
@@ -38,45 +84,941 @@ pub struct DatasetMetadata {
     pub modified: String,
     /// Resource formats (comma-separated)
     pub format: String,
+    /// Tag names (comma-separated)
+    pub tags: String,
+    /// Group (theme) titles (comma-separated)
+    pub groups: String,
+    /// Author name
+    pub author: String,
+    /// Author contact email
+    pub author_email: String,
+    /// Maintainer name
+    pub maintainer: String,
+    /// Maintainer contact email
+    pub maintainer_email: String,
+    /// CKAN portal the dataset was fetched from (host of the API base URL)
+    pub source: String,
+    /// Human-facing page of the dataset on the CKAN portal
+    pub landing_page: String,
+    /// Number of resources attached to the dataset (including any without a URL)
+    pub resource_count: usize,
+    /// CKAN lifecycle state (`active`, `draft` or `deleted`; empty if not reported)
+    pub state: String,
+    /// Whether the dataset is private to its organisation
+    pub private: bool,
+    /// Resource records and raw JSON for the companion outputs (not an output column)
+    #[serde(default)]
+    pub extras: DatasetExtras,
 }
 
-/// Main processing function: fetches dataset IDs, fetches metadata concurrently, writes CSV, uploads to S3, and handles test mode.
+impl DatasetMetadata {
+    /// Output column names for the metadata fields, in the order they are written.
+    pub const COLUMNS: [&'static str; 19] = [
+        "id",
+        "title",
+        "description",
+        "license",
+        "organization",
+        "created",
+        "modified",
+        "format",
+        "tags",
+        "groups",
+        "author",
+        "author_email",
+        "maintainer",
+        "maintainer_email",
+        "source",
+        "landing_page",
+        "resource_count",
+        "state",
+        "private",
+    ];
+
+    /// Returns the field values in the same order as `COLUMNS`.
+    pub fn values(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.title.clone(),
+            self.description.clone(),
+            self.license.clone(),
+            self.organization.clone(),
+            self.created.clone(),
+            self.modified.clone(),
+            self.format.clone(),
+            self.tags.clone(),
+            self.groups.clone(),
+            self.author.clone(),
+            self.author_email.clone(),
+            self.maintainer.clone(),
+            self.maintainer_email.clone(),
+            self.source.clone(),
+            self.landing_page.clone(),
+            self.resource_count.to_string(),
+            self.state.clone(),
+            self.private.to_string(),
+        ]
+    }
+}
+
+/// Adds a dataset to the dead-letter list if its fetch failed or returned no metadata.
+fn record_failure<T>(
+    failed: &mut Vec<FailedDataset>,
+    source: &Config,
+    id: &str,
+    result: &Result<Option<T>, AppError>,
+) {
+    let category = match result {
+        Ok(Some(_)) => return,
+        Ok(None) => "not_found",
+        Err(e) => e.category(),
+    };
+    failed.push(FailedDataset {
+        id: id.to_string(),
+        category: category.to_string(),
+        ckan_api_base_url: source.ckan_api_base_url.clone(),
+    });
+}
+
+/// Sorts the fetched datasets so output order is deterministic and runs can be diffed.
+/// Modified timestamps sort descending so the freshest datasets come first.
+pub fn sort_dataset_metadata(
+    dataset_metadata: &mut [(DatasetMetadata, ResourceUrls)],
+    sort_by: SortBy,
+) {
+    match sort_by {
+        SortBy::Id => dataset_metadata.sort_by(|(a, _), (b, _)| a.id.cmp(&b.id)),
+        SortBy::Title => dataset_metadata.sort_by(|(a, _), (b, _)| a.title.cmp(&b.title)),
+        SortBy::Modified => dataset_metadata.sort_by(|(a, _), (b, _)| b.modified.cmp(&a.modified)),
+        SortBy::None => {}
+    }
+}
+
+/// Returns true if the dataset's organisation is in the filter list (or the list is empty).
+/// Matching is case-insensitive against either the organisation title or its slug form,
+/// e.g. `Department for Transport` also matches `department-for-transport`.
+pub fn matches_organization(meta: &DatasetMetadata, organizations: &[String]) -> bool {
+    if organizations.is_empty() {
+        return true;
+    }
+    let title = meta.organization.trim().to_lowercase();
+    let slug = organization_slug(&meta.organization);
+    organizations.iter().any(|org| {
+        let org = org.trim().to_lowercase();
+        org == title || org == slug
+    })
+}
+
+/// Returns true if the dataset is public and active. Portals that don't report a state are
+/// treated as active, so their datasets are not all dropped.
+pub fn is_active(meta: &DatasetMetadata) -> bool {
+    !meta.private && (meta.state.is_empty() || meta.state.eq_ignore_ascii_case("active"))
+}
+
+/// Returns true if the dataset was modified at or after the threshold (or there is none).
+/// CKAN's `metadata_modified` has no offset and is read as UTC; a `modified` value that can't
+/// be parsed keeps the dataset, with a warning, rather than silently dropping it.
+pub fn is_modified_since(
+    meta: &DatasetMetadata,
+    threshold: Option<chrono::DateTime<chrono::Utc>>,
+) -> bool {
+    let Some(threshold) = threshold else {
+        return true;
+    };
+    let modified = meta.modified.trim();
+    let parsed = chrono::DateTime::parse_from_rfc3339(modified)
+        .map(|date| date.with_timezone(&chrono::Utc))
+        .or_else(|_| {
+            chrono::NaiveDateTime::parse_from_str(modified, "%Y-%m-%dT%H:%M:%S%.f")
+                .map(|date| date.and_utc())
+        })
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(modified, "%Y-%m-%d")
+                .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        });
+    match parsed {
+        Ok(modified) => modified >= threshold,
+        Err(_) => {
+            warn!(
+                "Keeping dataset {} despite MODIFIED_SINCE: cannot parse modified '{}'",
+                meta.id, meta.modified
+            );
+            true
+        }
+    }
+}
+
+/// Checks the invariant that every fetched or resumed dataset left after filtering
+/// (`ORG_FILTER`, `SKIP_INACTIVE` and `MODIFIED_SINCE`) becomes exactly one output row.
+/// `expected` is tallied while fetching and `written` is the writer's own count. A mismatch
+/// means rows were lost or duplicated while writing; it is logged as an error and fails the
+/// run with `ABORT_ON_ROW_MISMATCH`.
+pub fn check_rows_written(
+    config: &Config,
+    expected: usize,
+    written: usize,
+) -> Result<(), AppError> {
+    if written == expected {
+        return Ok(());
+    }
+    error!(
+        "Wrote {} rows but {} datasets were fetched and kept after filtering",
+        written, expected
+    );
+    if config.abort_on_row_mismatch {
+        return Err(AppError::Other(format!(
+            "Row count mismatch: wrote {written} rows for {expected} datasets; aborting without uploading"
+        )));
+    }
+    Ok(())
+}
+
+/// Fails when the run's failure ratio exceeds `MAX_FAILURE_RATIO`. Used by the Lambda handler,
+/// after the output has been uploaded, so the invocation is marked failed and alerting fires.
+pub fn check_failure_ratio(config: &Config, summary: &RunSummary) -> Result<(), AppError> {
+    let ratio = summary.failure_ratio();
+    if ratio > config.max_failure_ratio {
+        return Err(AppError::Other(format!(
+            "{} of {} datasets failed ({:.1}%), above MAX_FAILURE_RATIO = {}",
+            summary.datasets_failed,
+            summary.datasets_fetched + summary.datasets_failed,
+            ratio * 100.0,
+            config.max_failure_ratio
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the lowercase, hyphen-separated slug of an organisation title, e.g.
+/// `Department for Transport` becomes `department-for-transport`. Safe to use in file names.
+pub fn organization_slug(organization: &str) -> String {
+    organization
+        .trim()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Main processing function: runs the workflow, records the total duration, and sends a
+/// completion notification if an SNS topic is configured. Returns a summary of the run.
+async fn process_datasets(
+    config: &Config,
+    test_mode: bool,
+    s3_client: &S3Client,
+) -> Result<RunSummary, AppError> {
+    process_datasets_until(config, test_mode, s3_client, Shutdown::never()).await
+}
+
+/// Like `process_datasets`, but stops fetching when `shutdown` fires and finishes the run with
+/// the datasets collected so far, exactly as when the run deadline is reached.
+async fn process_datasets_until(
+    config: &Config,
+    test_mode: bool,
+    s3_client: &S3Client,
+    shutdown: Shutdown,
+) -> Result<RunSummary, AppError> {
+    let start = Instant::now();
+    let result = run_workflow(config, test_mode, s3_client, shutdown)
+        .await
+        .map(|mut summary| {
+            summary.total_duration_ms = start.elapsed().as_millis() as u64;
+            summary
+        });
+    if let Some(topic_arn) = &config.sns_topic_arn {
+        let notifier = SnsNotifier::new(config, topic_arn).await;
+        notify_run_result(&notifier, &result).await;
+    }
+    result
+}
+
+/// Fetches dataset IDs, fetches metadata concurrently, writes the output, uploads to S3, and handles test mode.
 /// This is the main workflow for the Lambda function.
-async fn process_datasets(config: &Config, test_mode: bool) -> Result<(), AppError> {
+async fn run_workflow(
+    config: &Config,
+    test_mode: bool,
+    s3_client: &S3Client,
+    shutdown: Shutdown,
+) -> Result<RunSummary, AppError> {
     info!("Starting process_datasets: test_mode = {}", test_mode);
+    let run_started = chrono::Utc::now();
+    let deadline = config
+        .run_deadline_secs
+        .map(|secs| tokio::time::Instant::now() + std::time::Duration::from_secs(secs));
+    let output_format: OutputFormat = config.output_format.parse()?;
+    let output_dest: OutputDest = config.output_dest.parse()?;
+    let sort_by: SortBy = config.sort_by.parse()?;
+    let mut summary = RunSummary::default();
+    let jitter = startup_jitter(config.startup_jitter_max_ms);
+    if !jitter.is_zero() {
+        info!("Waiting {} ms of startup jitter", jitter.as_millis());
+        tokio::time::sleep(jitter).await;
+    }
+    // In incremental mode, load the previous run first so new and changed datasets can be
+    // picked out into the delta file.
+    // DIFF_PREVIOUS reads the same upload to report which dataset IDs came and went.
+    let previous_run = if (config.incremental || config.diff_previous) && !output_dest.uploads() {
+        warn!(
+            "INCREMENTAL and DIFF_PREVIOUS need the previous S3 upload; ignored with OUTPUT_DEST=local"
+        );
+        None
+    } else if config.incremental || config.diff_previous {
+        match fetch_last_run_timestamps(s3_client, config).await {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                warn!(
+                    "Could not read previous run, treating every dataset as new: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let previous = previous_run.as_ref().filter(|_| config.incremental);
+    // Datasets that are new or whose modified timestamp changed since the last upload.
+    let is_changed = |meta: &DatasetMetadata| {
+        previous.is_none_or(|previous| previous.get(&meta.id) != Some(&meta.modified))
+    };
+    let modified_since = config.modified_since_threshold();
+    // Datasets that belong in the catalogue at all: ORG_FILTER, and SKIP_INACTIVE dropping
+    // deleted, draft and private datasets.
+    let is_selected = |meta: &DatasetMetadata| {
+        matches_organization(meta, &config.org_filter) && (!config.skip_inactive || is_active(meta))
+    };
+    let fetch_start = Instant::now();
     // Use the optimised HTTP client with better connection pooling
     let client = Arc::new(create_http_client(config)?);
-    let dataset_ids = fetch_dataset_list(&client, config, test_mode).await?;
-    info!("Fetched {} dataset ids", dataset_ids.len());
-    let concurrency_limit = config.concurrency_limit;
+    let bytes_downloaded = Arc::new(AtomicU64::new(0));
+    let retry_budget = Arc::new(RetryBudget::new(config.total_retry_budget));
+    // Gather ids from every configured portal, a previous run's dead-letter file, or an input
+    // id list (INPUT_IDS_FILE is read by fetch_dataset_list itself).
+    let mut listed_ids = Vec::new();
+    // A test-mode or local run must not store the ETag, or the next full run would be skipped.
+    let conditional_get = config.use_conditional_get && !test_mode && output_dest.uploads();
+    let previous_etag = if conditional_get {
+        fetch_list_etag(s3_client, config)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Could not read the stored package list ETag: {}", e);
+                None
+            })
+    } else {
+        None
+    };
+    let mut list_etag = None;
+    if let Some(key) = &config.retry_failed_from {
+        let failed = fetch_failed_ids(s3_client, config, key).await?;
+        info!(
+            "Retrying {} failed datasets listed in {}",
+            failed.len(),
+            key
+        );
+        let mut sources: HashMap<String, Arc<Config>> = HashMap::new();
+        for entry in failed {
+            let source_config = sources
+                .entry(entry.ckan_api_base_url)
+                .or_insert_with_key(|base_url| Arc::new(config.for_ckan_source(base_url)));
+            listed_ids.push((Arc::clone(source_config), entry.id));
+        }
+    } else if let Some((bucket, key)) = config.input_ids_s3_location() {
+        let text = fetch_input_ids_text(s3_client, config, bucket, key).await?;
+        let ids = parse_input_ids(&text, config, test_mode);
+        info!(
+            "Read {} dataset ids from s3://{}/{}",
+            ids.len(),
+            bucket,
+            key
+        );
+        let source_config = Arc::new(config.for_ckan_source(config.ckan_base_urls()[0]));
+        listed_ids.extend(ids.into_iter().map(|id| (Arc::clone(&source_config), id)));
+    } else {
+        for base_url in config.ckan_base_urls() {
+            let source_config = Arc::new(config.for_ckan_source(base_url));
+            if config.preflight {
+                preflight(&client, &source_config).await?;
+            }
+            let ids = if conditional_get {
+                match fetch_dataset_list_if_changed(
+                    &client,
+                    &source_config,
+                    test_mode,
+                    &bytes_downloaded,
+                    previous_etag.as_deref(),
+                )
+                .await?
+                {
+                    DatasetList::NotModified => {
+                        info!(
+                            "Package list unchanged since the last run (ETag match); nothing to do"
+                        );
+                        summary.catalogue_unchanged = true;
+                        return Ok(summary);
+                    }
+                    DatasetList::Changed { ids, etag } => {
+                        list_etag = etag;
+                        ids
+                    }
+                }
+            } else {
+                fetch_dataset_list(&client, &source_config, test_mode, &bytes_downloaded).await?
+            };
+            info!("Fetched {} dataset ids from {}", ids.len(), base_url);
+            listed_ids.extend(ids.into_iter().map(|id| (Arc::clone(&source_config), id)));
+        }
+    }
+    // Keep the first source for ids seen twice.
+    let mut seen_ids = HashSet::new();
+    let mut dataset_ids = Vec::new();
+    let mut duplicates = 0;
+    for (source_config, id) in listed_ids {
+        if seen_ids.insert(id.clone()) {
+            dataset_ids.push((source_config, id));
+        } else {
+            duplicates += 1;
+        }
+    }
+    if duplicates > 0 {
+        info!(
+            "Removed {} duplicate dataset ids from the package list",
+            duplicates
+        );
+    }
+    // A suspiciously short list usually means a partial CKAN outage; stop before the previous
+    // good output is overwritten. Test mode, retry and input id list runs are short by design.
+    if !test_mode
+        && config.retry_failed_from.is_none()
+        && config.input_ids_file.is_none()
+        && config.input_ids_url.is_none()
+        && dataset_ids.len() < config.min_expected_datasets
+    {
+        return Err(AppError::Other(format!(
+            "Package list returned {} datasets, below MIN_EXPECTED_DATASETS = {}; aborting without uploading",
+            dataset_ids.len(),
+            config.min_expected_datasets
+        )));
+    }
+    // When resuming, skip datasets an interrupted run already completed.
+    let saved = if config.resume {
+        load_checkpoint(&config.checkpoint_file)?
+    } else {
+        SavedCheckpoint::default()
+    };
+    let checkpoint = Arc::new(Checkpoint::new(
+        &config.checkpoint_file,
+        config.checkpoint_interval,
+        saved.completed,
+    ));
+    if !config.resume && config.checkpoint_interval > 0 {
+        // A fresh run starts a fresh checkpoint rather than appending to an old one.
+        checkpoint.clear();
+    }
+    // Rows the interrupted run fetched go back into this run's output.
+    let mut resumed_rows = saved.rows;
+    if !resumed_rows.is_empty() {
+        let listed_ids: HashSet<&str> = dataset_ids.iter().map(|(_, id)| id.as_str()).collect();
+        resumed_rows.retain(|(meta, _)| listed_ids.contains(meta.id.as_str()));
+    }
+    let resumed_count = resumed_rows.len();
+    let listed = dataset_ids.len();
+    dataset_ids.retain(|(_, id)| !checkpoint.is_completed(id));
+    summary.datasets_resumed = listed - dataset_ids.len();
+    if summary.datasets_resumed > 0 {
+        info!(
+            "Resuming from checkpoint: skipping {} completed datasets",
+            summary.datasets_resumed
+        );
+    }
+    summary.datasets_listed = dataset_ids.len();
+    let mut concurrency_limit = config.concurrency_limit;
+    // With CONCURRENCY_LIMIT_PER_SOURCE each portal is capped by its own semaphore, and the
+    // stream is widened so every portal can reach its limit at the same time.
+    let mut source_limits: HashMap<String, Arc<tokio::sync::Semaphore>> = HashMap::new();
+    if config.concurrency_limit_per_source.is_some() {
+        for (source_config, _) in &dataset_ids {
+            source_limits
+                .entry(source_config.ckan_api_base_url.clone())
+                .or_insert_with(|| {
+                    let limit = source_config.source_concurrency_limit();
+                    info!(
+                        "Concurrency limit for {}: {}",
+                        source_config.ckan_source_name(),
+                        limit
+                    );
+                    Arc::new(tokio::sync::Semaphore::new(limit))
+                });
+        }
+        concurrency_limit = source_limits
+            .values()
+            .map(|limit| limit.available_permits())
+            .sum::<usize>()
+            .max(1);
+    }
+    let source_limits = Arc::new(source_limits);
+    let ramp = config.concurrency_ramp.then(|| {
+        Arc::new(ConcurrencyRamp::start(
+            concurrency_limit,
+            std::time::Duration::from_millis(config.concurrency_ramp_interval_ms),
+        ))
+    });
     info!("Starting concurrent metadata fetch for all datasets...");
-    let metadata_results = futures::stream::iter(dataset_ids)
-        .map(|id| {
+    let fetch_one = {
+        let client = Arc::clone(&client);
+        let bytes_downloaded = Arc::clone(&bytes_downloaded);
+        let checkpoint = Arc::clone(&checkpoint);
+        let retry_budget = Arc::clone(&retry_budget);
+        move |(config, id): (Arc<Config>, String)| {
             let client = Arc::clone(&client);
-            let config = config.clone();
+            let checkpoint = Arc::clone(&checkpoint);
+            let ramp = ramp.clone();
+            let bytes_downloaded = Arc::clone(&bytes_downloaded);
+            let retry_budget = Arc::clone(&retry_budget);
+            let source_limit = source_limits.get(&config.ckan_api_base_url).cloned();
             async move {
+                let _source_permit = match source_limit {
+                    Some(limit) => limit.acquire_owned().await.ok(),
+                    None => None,
+                };
+                let _permit = match &ramp {
+                    Some(ramp) => Some(ramp.acquire().await),
+                    None => None,
+                };
                 info!("Fetching metadata for dataset: {}", id);
-                let result = fetch_dataset_metadata(client, &config, id.clone()).await;
+                let started = Instant::now();
+                let result = fetch_dataset_metadata(
+                    client,
+                    &config,
+                    id.clone(),
+                    &bytes_downloaded,
+                    &retry_budget,
+                )
+                .await;
+                let duration_ms = started.elapsed().as_millis() as u64;
+                // Structured fields (rather than interpolated text) so JSON logs can be queried.
                 match &result {
-                    Ok(Some(_)) => info!("Finished fetching metadata for dataset: {}", id),
-                    Ok(None) => error!("No metadata found for dataset: {}", id),
-                    Err(e) => error!("Error fetching metadata for dataset {}: {}", id, e),
+                    Ok(Some(row)) => {
+                        info!(
+                            dataset_id = %id,
+                            status = "fetched",
+                            duration_ms,
+                            resource_count = row.1.urls.len(),
+                            "Finished fetching metadata for dataset"
+                        );
+                        checkpoint.record(row);
+                    }
+                    Ok(None) => error!(
+                        dataset_id = %id,
+                        status = "not_found",
+                        duration_ms,
+                        "No metadata found for dataset"
+                    ),
+                    Err(e) => error!(
+                        dataset_id = %id,
+                        status = "failed",
+                        duration_ms,
+                        category = e.category(),
+                        error = %e,
+                        "Error fetching metadata for dataset"
+                    ),
                 }
-                result
+                (config, id, duration_ms, result)
             }
+        }
+    };
+    // Without BATCH_SIZE every id is one batch, so this is a single continuous buffered stream.
+    let batch_size = config.batch_size.unwrap_or(dataset_ids.len()).max(1);
+    let batch_delay = std::time::Duration::from_millis(config.batch_delay_ms);
+    if config.batch_size.is_some() {
+        info!(
+            "Fetching in batches of {} with a {} ms pause between batches",
+            batch_size, config.batch_delay_ms
+        );
+    }
+    let metadata_stream = futures::stream::iter(dataset_ids)
+        .chunks(batch_size)
+        .enumerate()
+        .flat_map(move |(batch, ids)| {
+            let pause = if batch == 0 {
+                std::time::Duration::ZERO
+            } else {
+                batch_delay
+            };
+            let fetch_one = fetch_one.clone();
+            // The next batch starts only once this one has finished and the pause has passed.
+            futures::stream::once(tokio::time::sleep(pause)).flat_map(move |_| {
+                futures::stream::iter(ids.clone())
+                    .map(fetch_one.clone())
+                    .buffered(concurrency_limit)
+            })
         })
-        .buffered(concurrency_limit)
-        .collect::<Vec<_>>()
+        // Stop fetching at the deadline or on shutdown (dropping in-flight requests) so there
+        // is still time to write and upload what was collected.
+        .take_until(async move {
+            let deadline_reached = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = deadline_reached => info!("Run deadline reached: stopping fetch"),
+                _ = shutdown.wait() => info!("Shutdown requested: stopping fetch"),
+            }
+        });
+    // The fetch phase gets its own span so its duration is logged when the span closes.
+    let fetch_span = info_span!("fetch_metadata", phase = "fetch");
+    let mut datasets_attempted = 0;
+    let mut failed_datasets = Vec::new();
+    let output_file = config.output_file();
+    let mut tags_file = None;
+    let mut resources_file = None;
+    let mut raw_json_file = None;
+    let mut delta_file = None;
+    let mut org_files = None;
+    // Ids of the datasets in the output, for the DIFF_PREVIOUS report.
+    let mut current_ids = Vec::new();
+    if config.streaming_csv && output_format == OutputFormat::Csv {
+        // Write each row as it arrives so memory use stays flat.
+        info!("Streaming datasets to CSV as metadata arrives...");
+        let writer = StreamingCsvWriter::create(config)?;
+        // Rows go through a bounded channel to a single blocking writer task, so a slow disk
+        // holds back fetching instead of letting rows pile up in memory.
+        let (tx, rx) = tokio::sync::mpsc::channel(concurrency_limit);
+        let writer_task = tokio::task::spawn_blocking(move || writer.write_from_channel(rx));
+        let mut metadata_stream = std::pin::pin!(metadata_stream);
+        // Rows handed to the writer, checked against what it reports writing.
+        let mut expected_rows = 0;
+        async {
+            for (meta, urls) in resumed_rows {
+                summary.record_formats(&urls.formats);
+                if previous_run.is_some() && is_selected(&meta) {
+                    current_ids.push(meta.id.clone());
+                }
+                if is_selected(&meta) && is_modified_since(&meta, modified_since) {
+                    expected_rows += 1;
+                    if tx.send((meta, urls)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            while let Some((source, id, duration_ms, result)) = metadata_stream.next().await {
+                datasets_attempted += 1;
+                record_failure(&mut failed_datasets, &source, &id, &result);
+                match result {
+                    Ok(Some((meta, urls))) => {
+                        summary.datasets_fetched += 1;
+                        summary.record_timing(&id, duration_ms, config.slowest_datasets_count);
+                        summary.record_formats(&urls.formats);
+                        if previous_run.is_some() && is_selected(&meta) {
+                            current_ids.push(meta.id.clone());
+                        }
+                        if is_selected(&meta) && is_modified_since(&meta, modified_since) {
+                            expected_rows += 1;
+                            if tx.send((meta, urls)).await.is_err() {
+                                // The writer stopped on an error, which is returned below.
+                                break;
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => summary.record_fetch_error(&e),
+                }
+            }
+        }
+        .instrument(fetch_span)
         .await;
-    info!("Finished concurrent metadata fetch for all datasets.");
-    let dataset_metadata: Vec<(DatasetMetadata, Vec<String>)> =
-        metadata_results.into_iter().flatten().flatten().collect();
-    info!("Writing {} datasets to CSV...", dataset_metadata.len());
-    write_csv(config, &dataset_metadata)?;
-    info!("CSV file written: {}", config.csv_file);
-    upload_to_s3(config, &config.csv_file).await?;
-    info!("CSV file uploaded to S3 successfully.");
-    Ok(())
+        // Save the final checkpoint in case a deadline or shutdown cut the fetch short.
+        checkpoint.save().await;
+        drop(tx);
+        summary.rows_written = writer_task
+            .await
+            .map_err(|e| AppError::Other(format!("CSV writer task failed: {e}")))??;
+        check_rows_written(config, expected_rows, summary.rows_written)?;
+        info!("Finished concurrent metadata fetch for all datasets.");
+        summary.fetch_duration_ms = fetch_start.elapsed().as_millis() as u64;
+    } else {
+        let metadata_results = metadata_stream
+            .collect::<Vec<_>>()
+            .instrument(fetch_span)
+            .await;
+        datasets_attempted = metadata_results.len();
+        // Save the final checkpoint in case a deadline or shutdown cut the fetch short.
+        checkpoint.save().await;
+        info!("Finished concurrent metadata fetch for all datasets.");
+        summary.fetch_duration_ms = fetch_start.elapsed().as_millis() as u64;
+        let mut dataset_metadata: Vec<(DatasetMetadata, ResourceUrls)> = Vec::new();
+        for (source, id, duration_ms, result) in metadata_results {
+            record_failure(&mut failed_datasets, &source, &id, &result);
+            match result {
+                Ok(Some(row)) => {
+                    summary.record_timing(&id, duration_ms, config.slowest_datasets_count);
+                    summary.record_formats(&row.1.formats);
+                    dataset_metadata.push(row);
+                }
+                Ok(None) => {}
+                Err(e) => summary.record_fetch_error(&e),
+            }
+        }
+        summary.datasets_fetched = dataset_metadata.len();
+        for row in resumed_rows {
+            summary.record_formats(&row.1.formats);
+            dataset_metadata.push(row);
+        }
+        if previous_run.is_some() {
+            current_ids = dataset_metadata
+                .iter()
+                .filter(|(meta, _)| is_selected(meta))
+                .map(|(meta, _)| meta.id.clone())
+                .collect();
+        }
+        let before_filtering = dataset_metadata.len();
+        dataset_metadata
+            .retain(|(meta, _)| is_selected(meta) && is_modified_since(meta, modified_since));
+        // Tallied from the fetch rather than the slice the writers iterate, so lost rows show.
+        let expected_rows =
+            summary.datasets_fetched + resumed_count - (before_filtering - dataset_metadata.len());
+        if config.validate_urls {
+            let url_check_client = create_url_check_client(config)?;
+            summary.broken_urls =
+                check_download_urls(&url_check_client, config, &mut dataset_metadata).await;
+        }
+        sort_dataset_metadata(&mut dataset_metadata, sort_by);
+        info!(
+            "Writing {} datasets as {:?}...",
+            dataset_metadata.len(),
+            output_format
+        );
+        match output_format {
+            OutputFormat::Csv if config.split_by_org => {
+                let files = write_org_csvs(config, &dataset_metadata)?;
+                info!("Wrote {} per-organisation CSV files", files.len());
+                let written = files.iter().map(|file| file.rows).sum();
+                check_rows_written(config, expected_rows, written)?;
+                org_files = Some(files);
+            }
+            OutputFormat::Csv => {
+                let written = write_csv(config, &dataset_metadata)?;
+                check_rows_written(config, expected_rows, written)?;
+            }
+            OutputFormat::Parquet => write_parquet(config, &dataset_metadata)?,
+            OutputFormat::Sqlite => write_sqlite(config, &dataset_metadata)?,
+            OutputFormat::Xlsx => write_xlsx(config, &dataset_metadata)?,
+            OutputFormat::Dynamodb if config.dry_run => {
+                info!("DRY RUN: skipping DynamoDB write")
+            }
+            OutputFormat::Dynamodb if !output_dest.uploads() => {
+                info!("OUTPUT_DEST=local: skipping DynamoDB write")
+            }
+            OutputFormat::Dynamodb => {
+                let dynamodb_client = create_dynamodb_client(config).await;
+                write_dynamodb(&dynamodb_client, config, &dataset_metadata).await?
+            }
+        }
+        if config.normalize_tags {
+            let tag_rows = write_tags_csv(config, &dataset_metadata)?;
+            info!(
+                "Wrote {} dataset tag rows to {}",
+                tag_rows,
+                config.tags_file()
+            );
+            tags_file = Some(config.tags_file());
+        }
+        if config.write_resources {
+            let resource_rows = write_resources_csv(config, &dataset_metadata)?;
+            info!(
+                "Wrote {} resource rows to {}",
+                resource_rows,
+                config.resources_file()
+            );
+            resources_file = Some(config.resources_file());
+        }
+        if previous.is_some() {
+            // The full output stays the baseline for the next run; the delta goes alongside.
+            let changed: Vec<_> = dataset_metadata
+                .iter()
+                .filter(|(meta, _)| is_changed(meta))
+                .cloned()
+                .collect();
+            let delta_rows = write_delta_csv(config, &changed)?;
+            info!(
+                "Incremental mode: wrote {} new or changed datasets to {} ({} unchanged)",
+                delta_rows,
+                config.delta_file(),
+                dataset_metadata.len() - delta_rows
+            );
+            summary.delta_rows = Some(delta_rows);
+            delta_file = Some(config.delta_file());
+        }
+        if config.dump_raw_json {
+            let raw_lines = write_raw_json(config, &dataset_metadata)?;
+            info!(
+                "Wrote {} raw CKAN datasets to {}",
+                raw_lines,
+                config.raw_json_file()
+            );
+            raw_json_file = Some(config.raw_json_file());
+        }
+        summary.rows_written = dataset_metadata.len();
+    }
+    let mut formats_file = None;
+    if config.write_formats_summary {
+        write_formats_csv(config, &summary.resource_formats)?;
+        info!(
+            "Wrote {} resource format counts to {}",
+            summary.resource_formats.len(),
+            config.formats_summary_file()
+        );
+        formats_file = Some(config.formats_summary_file());
+    }
+    summary.bytes_downloaded = bytes_downloaded.load(Ordering::Relaxed);
+    summary.datasets_not_fetched = summary.datasets_listed - datasets_attempted;
+    if summary.datasets_not_fetched > 0 {
+        summary.deadline_reached = true;
+        warn!(
+            "Run stopped early: writing partial output, {} datasets not fetched",
+            summary.datasets_not_fetched
+        );
+    }
+    summary.datasets_failed = datasets_attempted - summary.datasets_fetched;
+    if summary.datasets_failed > 0 {
+        warn!(
+            "{} datasets failed: {} http, {} parse, {} missing or other",
+            summary.datasets_failed,
+            summary.http_failures,
+            summary.parse_failures,
+            summary.datasets_failed - summary.http_failures - summary.parse_failures
+        );
+    }
+    if let Some(previous_run) = previous_run.as_ref().filter(|_| config.diff_previous) {
+        if summary.deadline_reached {
+            // Datasets that were never fetched would all show up as removed.
+            warn!("DIFF_PREVIOUS skipped: the run stopped early so the output is partial");
+        } else {
+            let diff = DatasetDiff::between(previous_run.keys(), &current_ids);
+            info!(
+                "Compared with the previous run: {} dataset IDs added, {} removed",
+                diff.added.len(),
+                diff.removed.len()
+            );
+            summary.record_diff(diff, config.diff_list_ids);
+        }
+    }
+    if output_format == OutputFormat::Csv
+        && config.csv_quote_style.parse::<CsvQuoteStyle>()? == CsvQuoteStyle::Never
+    {
+        // Unquoted output can't be read back reliably once a field holds the delimiter or a
+        // newline, which is what `never` allows by design.
+        info!("CSV_QUOTE_STYLE=never: skipping CSV validation");
+    } else if output_format == OutputFormat::Csv {
+        // Check what was written before anything is uploaded.
+        match &org_files {
+            Some(files) => {
+                for file in files {
+                    validate_csv(config, &file.path)?;
+                }
+            }
+            None => {
+                validate_csv(config, &output_file)?;
+            }
+        }
+    }
+    let written_output = match &org_files {
+        Some(files) => format!("{} per-organisation files", files.len()),
+        None => output_file.clone(),
+    };
+    if !output_format.writes_file() {
+        info!("DynamoDB output has no file to upload to S3");
+    } else if config.dry_run {
+        info!("Output written: {}", written_output);
+        info!("DRY RUN: skipping S3 upload");
+    } else if !output_dest.uploads() {
+        info!(
+            "Output written locally (OUTPUT_DEST=local): {}",
+            written_output
+        );
+    } else {
+        info!("Output written: {}", written_output);
+        let upload_start = Instant::now();
+        if let Some(org_files) = &org_files {
+            summary.org_output_keys = upload_org_files(s3_client, config, org_files).await?;
+        } else {
+            let uploaded = upload_to_s3(s3_client, config, &output_file).await?;
+            summary.output_key = uploaded.key;
+            summary.output_sha256 = uploaded.sha256;
+            summary.upload_skipped = uploaded.skipped;
+        }
+        if let Some(tags_file) = &tags_file {
+            summary.tags_output_key = Some(upload_tags_file(s3_client, config, tags_file).await?);
+        }
+        if let Some(resources_file) = &resources_file {
+            summary.resources_output_key =
+                Some(upload_resources_file(s3_client, config, resources_file).await?);
+        }
+        if let Some(delta_file) = &delta_file {
+            summary.delta_output_key =
+                Some(upload_delta_file(s3_client, config, delta_file).await?);
+        }
+        if let Some(raw_json_file) = &raw_json_file {
+            summary.raw_json_output_key =
+                Some(upload_raw_json(s3_client, config, raw_json_file).await?);
+        }
+        if let Some(formats_file) = &formats_file {
+            summary.formats_output_key =
+                Some(upload_formats_summary(s3_client, config, formats_file).await?);
+        }
+        summary.upload_duration_ms = upload_start.elapsed().as_millis() as u64;
+        if org_files.is_some() {
+            info!(
+                "Uploaded {} per-organisation files to S3",
+                summary.org_output_keys.len()
+            );
+        } else {
+            info!(
+                "Output file uploaded to S3 successfully: key={}",
+                summary.output_key
+            );
+        }
+        if output_dest == OutputDest::Both {
+            info!("Output also kept locally: {}", written_output);
+        }
+        if config.write_manifest && org_files.is_some() {
+            warn!("WRITE_MANIFEST is not supported with SPLIT_BY_ORG; skipping the manifest");
+        } else if config.write_manifest {
+            let byte_size = std::fs::metadata(&output_file)?.len();
+            let manifest = RunManifest::new(
+                &summary,
+                byte_size,
+                run_started,
+                &config.ckan_base_urls().join(","),
+            );
+            upload_manifest(s3_client, config, &manifest).await?;
+        }
+        // Only a complete run's ETag is stored, so a partial run is redone next time.
+        if let Some(etag) = list_etag.as_deref().filter(|_| !summary.deadline_reached) {
+            upload_list_etag(s3_client, config, etag).await?;
+        }
+    }
+    if !failed_datasets.is_empty() {
+        if config.dry_run || !output_dest.uploads() {
+            info!(
+                "Skipping upload of {} failed dataset ids (dry run or local output)",
+                failed_datasets.len()
+            );
+        } else {
+            summary.failed_ids_key =
+                Some(upload_failed_ids(s3_client, config, &failed_datasets).await?);
+        }
+    }
+    // A complete run no longer needs its checkpoint; a partial one keeps it for the next run.
+    if (config.resume || config.checkpoint_interval > 0) && !summary.deadline_reached {
+        checkpoint.clear();
+    }
+    if config.emit_metrics {
+        emit_metrics(config, &summary).await;
+    }
+    Ok(summary)
 }
 
 /// Lambda handler function. This is the entry point for AWS Lambda.
@@ -89,35 +1031,139 @@ async fn function_handler(
         .payload
         .get("test_mode")
         .and_then(|v| v.as_bool())
-        .unwrap_or_else(|| {
-            std::env::var("TEST_MODE")
-                .map(|v| v == "1" || v.to_lowercase() == "true")
-                .unwrap_or(false)
-        });
+        .unwrap_or_else(|| env_flag("TEST_MODE"));
     info!("Lambda handler invoked. test_mode = {}", test_mode);
-    let config = Config::new();
-    process_datasets(&config, test_mode)
+    let mut config = Config::new();
+    // Tag this run's S3 objects with the invocation's request id, so they can be traced back.
+    config.run_id = event.context.request_id.clone();
+    let s3_client = create_s3_client(&config).await;
+    let summary = process_datasets(&config, test_mode, &s3_client)
         .await
         .map_err(|e| Error::from(e.to_string()))?;
-    Ok(serde_json::json!({ "status": "success" }))
+    check_failure_ratio(&config, &summary).map_err(|e| Error::from(e.to_string()))?;
+    Ok(serde_json::json!({
+        "status": if summary.catalogue_unchanged {
+            "unchanged"
+        } else if summary.deadline_reached {
+            "partial"
+        } else {
+            "success"
+        },
+        "dry_run": config.dry_run,
+        "summary": summary
+    }))
+}
+
+/// Returns true if the environment variable is set to "1" or "true" (case-insensitive).
+fn env_flag(var: &str) -> bool {
+    std::env::var(var)
+        .map(|v| v == "1" || v.to_lowercase() == "true")
+        .unwrap_or(false)
+}
+
+/// Options parsed from the command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct CliOptions {
+    /// Run the workflow directly instead of starting the Lambda runtime (`--local`)
+    pub local: bool,
+    /// Process only a small number of datasets (`--test-mode`)
+    pub test_mode: bool,
+    /// Serve Prometheus metrics on `/metrics` in local mode (`--serve-metrics`)
+    pub serve_metrics: bool,
+}
+
+/// Parses command-line arguments (excluding the program name). Unknown arguments are ignored.
+pub fn parse_cli_options<I: IntoIterator<Item = String>>(args: I) -> CliOptions {
+    let mut options = CliOptions::default();
+    for arg in args {
+        match arg.as_str() {
+            "--local" => options.local = true,
+            "--test-mode" => options.test_mode = true,
+            "--serve-metrics" => options.serve_metrics = true,
+            _ => {}
+        }
+    }
+    options
 }
 
-/// Main function for the binary. Sets up logging, validates configuration, and runs the Lambda runtime.
+/// Runs the workflow once outside the Lambda runtime and prints the run summary.
+/// Exits with a non-zero status if the run fails. With `--serve-metrics` or `METRICS_PORT`,
+/// the run is recorded on a Prometheus `/metrics` endpoint that keeps serving after the run
+/// (including a failed one) until SIGTERM or Ctrl-C.
+/// A SIGTERM or Ctrl-C during the fetch stops it early and writes partial output.
+async fn run_local(config: &Config, test_mode: bool, serve_metrics: bool) {
+    info!("Local run mode. test_mode = {}", test_mode);
+    let metrics = Arc::new(PrometheusMetrics::default());
+    let metrics_port = config
+        .metrics_port
+        .or(serve_metrics.then_some(DEFAULT_METRICS_PORT));
+    if let Some(port) = metrics_port {
+        let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+        if let Err(e) = start_metrics_server(addr, Arc::clone(&metrics)).await {
+            error!("Failed to start metrics server on port {}: {}", port, e);
+            std::process::exit(1);
+        }
+    }
+    // SIGTERM from a container scheduler ends fetching early but still writes and uploads.
+    let shutdown = shutdown_on_signal();
+    let s3_client = create_s3_client(config).await;
+    metrics.record_run_started();
+    match process_datasets_until(config, test_mode, &s3_client, shutdown.clone()).await {
+        Ok(summary) => {
+            metrics.record_run(&summary);
+            match serde_json::to_string_pretty(&summary) {
+                Ok(json) => println!("{json}"),
+                Err(e) => error!("Failed to serialise run summary: {}", e),
+            }
+        }
+        Err(e) => {
+            error!("Local run failed: {}", e);
+            metrics.record_failed_run();
+            if metrics_port.is_none() {
+                std::process::exit(1);
+            }
+        }
+    }
+    if metrics_port.is_some() {
+        info!("Run finished; serving metrics until the process is stopped");
+        shutdown.wait().await;
+    }
+}
+
+/// Main function for the binary. Sets up logging, validates configuration, and runs the Lambda runtime
+/// (or the workflow directly when `--local` or `LOCAL_RUN` is set).
 #[tokio::main]
 async fn main() {
+    let config = Config::new();
     // Initialise tracing subscriber for logging. This works for both local and Lambda environments.
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .with_target(false)
         .without_time()
         .with_ansi(false) // Disable colour codes for cleaner logs in CloudWatch
-        .init();
+        // Log each phase span as it closes, with its busy/idle time.
+        .with_span_events(FmtSpan::CLOSE);
+    match config.log_format.parse().unwrap_or_default() {
+        // Flatten event fields to the top level so Logs Insights can query them directly.
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+        LogFormat::Plain => subscriber.init(),
+    }
     // Validate configuration before starting
-    let config = Config::new();
     if let Err(e) = config.validate() {
         error!("Configuration validation failed: {}", e);
         std::process::exit(1);
     }
+    // Local mode bypasses the Lambda runtime entirely.
+    let options = parse_cli_options(std::env::args().skip(1));
+    if options.local || env_flag("LOCAL_RUN") {
+        run_local(
+            &config,
+            options.test_mode || env_flag("TEST_MODE"),
+            options.serve_metrics,
+        )
+        .await;
+        return;
+    }
     // Run the Lambda runtime with our handler.
     if let Err(e) = run(service_fn(function_handler)).await {
         error!("Lambda runtime error: {}", e);
@@ -0,0 +1,59 @@
+use crate::aws::load_aws_config;
+use crate::config::Config;
+use crate::summary::RunSummary;
+use aws_sdk_cloudwatch::types::{MetricDatum, StandardUnit};
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+use tracing::{error, info};
+
+/// Builds the CloudWatch metric data points for a completed run.
+pub fn build_metric_data(summary: &RunSummary) -> Vec<MetricDatum> {
+    let datum = |name: &str, value: f64, unit: StandardUnit| {
+        MetricDatum::builder()
+            .metric_name(name)
+            .value(value)
+            .unit(unit)
+            .build()
+    };
+    vec![
+        datum(
+            "DatasetsFetched",
+            summary.datasets_fetched as f64,
+            StandardUnit::Count,
+        ),
+        datum(
+            "DatasetsFailed",
+            summary.datasets_failed as f64,
+            StandardUnit::Count,
+        ),
+        datum(
+            "FetchDurationMs",
+            summary.fetch_duration_ms as f64,
+            StandardUnit::Milliseconds,
+        ),
+        datum(
+            "UploadDurationMs",
+            summary.upload_duration_ms as f64,
+            StandardUnit::Milliseconds,
+        ),
+    ]
+}
+
+/// Publishes run metrics to CloudWatch under the configured namespace.
+/// Failures are logged and never propagated, so metrics can't fail the main workflow.
+pub async fn emit_metrics(config: &Config, summary: &RunSummary) {
+    let aws_config = load_aws_config(config).await;
+    let client = CloudWatchClient::new(&aws_config);
+    match client
+        .put_metric_data()
+        .namespace(&config.metrics_namespace)
+        .set_metric_data(Some(build_metric_data(summary)))
+        .send()
+        .await
+    {
+        Ok(_) => info!(
+            "Published run metrics to CloudWatch namespace {}",
+            config.metrics_namespace
+        ),
+        Err(e) => error!("Failed to publish CloudWatch metrics: {}", e),
+    }
+}
@@ -0,0 +1,156 @@
+use crate::error::AppError;
+use crate::summary::RunSummary;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Run counters exposed on the `/metrics` endpoint when running as a long-lived container.
+/// Only used in local mode; Lambda runs publish to CloudWatch instead (see `metrics.rs`).
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    runs: AtomicU64,
+    runs_failed: AtomicU64,
+    datasets_fetched: AtomicU64,
+    datasets_failed: AtomicU64,
+    last_run_duration_ms: AtomicU64,
+}
+
+impl PrometheusMetrics {
+    /// Counts a run as it starts, so runs that crash or never finish are still counted.
+    pub fn record_run_started(&self) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds a completed run's dataset counts and records its duration.
+    pub fn record_run(&self, summary: &RunSummary) {
+        self.datasets_fetched
+            .fetch_add(summary.datasets_fetched as u64, Ordering::Relaxed);
+        self.datasets_failed
+            .fetch_add(summary.datasets_failed as u64, Ordering::Relaxed);
+        self.last_run_duration_ms
+            .store(summary.total_duration_ms, Ordering::Relaxed);
+    }
+
+    /// Counts a run that failed before producing a summary.
+    pub fn record_failed_run(&self) {
+        self.runs_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "gov_data_runs_total",
+            "counter",
+            "Workflow runs started, including ones that failed or never finished.",
+            load(&self.runs).to_string(),
+        );
+        metric(
+            "gov_data_runs_failed_total",
+            "counter",
+            "Workflow runs that failed.",
+            load(&self.runs_failed).to_string(),
+        );
+        metric(
+            "gov_data_datasets_fetched_total",
+            "counter",
+            "Datasets whose metadata was fetched successfully.",
+            load(&self.datasets_fetched).to_string(),
+        );
+        metric(
+            "gov_data_datasets_failed_total",
+            "counter",
+            "Datasets that failed or returned no metadata.",
+            load(&self.datasets_failed).to_string(),
+        );
+        metric(
+            "gov_data_last_run_duration_seconds",
+            "gauge",
+            "Duration of the last successful run.",
+            format!("{:.3}", load(&self.last_run_duration_ms) as f64 / 1000.0),
+        );
+        out
+    }
+}
+
+/// Binds `addr` and serves `metrics` on `GET /metrics` in a background task.
+/// Returns the bound address, which differs from `addr` when binding port 0.
+pub async fn start_metrics_server(
+    addr: SocketAddr,
+    metrics: Arc<PrometheusMetrics>,
+) -> Result<SocketAddr, AppError> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!(
+        "Serving Prometheus metrics on http://{}/metrics",
+        local_addr
+    );
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, Arc::clone(&metrics)));
+                }
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Largest request head read before giving up; scrapers send a few hundred bytes.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Answers a single HTTP request: the metrics for `GET /metrics`, 404 for any other method or
+/// path, and 400 for a request line that can't be parsed.
+async fn handle_connection(mut stream: TcpStream, metrics: Arc<PrometheusMetrics>) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    // Read until the request line is complete; the headers and any body are ignored.
+    while !head.windows(2).any(|w| w == b"\r\n") && head.len() < MAX_REQUEST_HEAD {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => head.extend_from_slice(&buf[..read]),
+        }
+    }
+    let request = String::from_utf8_lossy(&head);
+    let (status, content_type, body) = match parse_request_line(&request) {
+        Some(("GET", "/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        ),
+        Some(_) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        None => ("400 Bad Request", "text/plain", "Bad Request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!("Failed to write metrics response: {}", e);
+    }
+}
+
+/// Splits an HTTP/1.x request line (`GET /metrics?x=1 HTTP/1.1`) into its method and path,
+/// dropping any query string. Returns None if the line is incomplete or malformed.
+pub fn parse_request_line(request: &str) -> Option<(&str, &str)> {
+    let (line, _) = request.split_once("\r\n")?;
+    let mut parts = line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+        return None;
+    }
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    Some((method, path))
+}
@@ -0,0 +1,59 @@
+use crate::aws::load_aws_config;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::summary::RunSummary;
+use aws_sdk_sns::Client as SnsClient;
+use tracing::{error, info};
+
+/// Destination for run completion notifications.
+pub trait Notifier {
+    /// Publishes a notification with the given subject and message body.
+    async fn publish(&self, subject: &str, message: &str) -> Result<(), AppError>;
+}
+
+/// Publishes notifications to an SNS topic.
+pub struct SnsNotifier {
+    client: SnsClient,
+    topic_arn: String,
+}
+
+impl SnsNotifier {
+    /// Creates a notifier for the given topic using the shared AWS configuration.
+    pub async fn new(config: &Config, topic_arn: &str) -> Self {
+        Self {
+            client: SnsClient::new(&load_aws_config(config).await),
+            topic_arn: topic_arn.to_string(),
+        }
+    }
+}
+
+impl Notifier for SnsNotifier {
+    async fn publish(&self, subject: &str, message: &str) -> Result<(), AppError> {
+        self.client
+            .publish()
+            .topic_arn(&self.topic_arn)
+            .subject(subject)
+            .message(message)
+            .send()
+            .await
+            .map_err(|e| AppError::Other(format!("SNS publish failed: {e}")))?;
+        Ok(())
+    }
+}
+
+/// Sends a success notification containing the run summary, or a failure notification with the error.
+/// Notification failures are logged and never propagated, so they can't mask the run result.
+pub async fn notify_run_result<N: Notifier>(notifier: &N, result: &Result<RunSummary, AppError>) {
+    let (subject, message) = match result {
+        Ok(summary) => (
+            "gov-data run succeeded",
+            serde_json::to_string_pretty(summary)
+                .unwrap_or_else(|e| format!("Run succeeded (summary unavailable: {e})")),
+        ),
+        Err(e) => ("gov-data run failed", format!("Run failed: {e}")),
+    };
+    match notifier.publish(subject, &message).await {
+        Ok(()) => info!("Published run notification: {}", subject),
+        Err(e) => error!("Failed to publish run notification: {}", e),
+    }
+}
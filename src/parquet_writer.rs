@@ -0,0 +1,68 @@
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Writes the dataset metadata to a Parquet file at `config.output_file()`.
+/// Each metadata field becomes a Utf8 column and the download URLs become a single
+/// `download_urls` List<Utf8> column, so the schema is fixed regardless of the data.
+pub fn write_parquet(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<(), AppError> {
+    let mut fields: Vec<Field> = DatasetMetadata::COLUMNS
+        .iter()
+        .map(|name| Field::new(*name, DataType::Utf8, false))
+        .collect();
+    fields.push(Field::new(
+        "download_urls",
+        DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+        false,
+    ));
+    let schema = Arc::new(Schema::new(fields));
+
+    // Build one string column per metadata field.
+    let rows: Vec<Vec<String>> = dataset_metadata
+        .iter()
+        .map(|(meta, _)| meta.values())
+        .collect();
+    let mut columns: Vec<ArrayRef> = (0..DatasetMetadata::COLUMNS.len())
+        .map(|i| {
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|row| row[i].as_str()),
+            )) as ArrayRef
+        })
+        .collect();
+    let mut urls_builder = ListBuilder::new(StringBuilder::new());
+    for (_, urls) in dataset_metadata {
+        for url in &urls.urls {
+            urls_builder.values().append_value(url);
+        }
+        urls_builder.append(true);
+    }
+    columns.push(Arc::new(urls_builder.finish()));
+
+    let batch = RecordBatch::try_new(schema.clone(), columns)
+        .map_err(|e| AppError::Other(format!("Parquet record batch failed: {e}")))?;
+    let file = File::create(config.output_file())?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(file, schema, Some(properties))
+        .map_err(|e| AppError::Other(format!("Parquet write failed: {e}")))?;
+    writer
+        .write(&batch)
+        .map_err(|e| AppError::Other(format!("Parquet write failed: {e}")))?;
+    writer
+        .close()
+        .map_err(|e| AppError::Other(format!("Parquet write failed: {e}")))?;
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Writes the raw CKAN `result` object of every dataset that has one to
+/// `config.raw_json_file()`, one JSON document per line (NDJSON).
+/// Returns the number of lines written.
+pub fn write_raw_json(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<usize, AppError> {
+    let mut writer = BufWriter::new(File::create(config.raw_json_file())?);
+    let mut lines = 0;
+    for raw in dataset_metadata
+        .iter()
+        .filter_map(|(meta, _)| meta.extras.raw_json.as_ref())
+    {
+        serde_json::to_writer(&mut writer, raw)?;
+        writer.write_all(b"\n")?;
+        lines += 1;
+    }
+    writer.flush()?;
+    Ok(lines)
+}
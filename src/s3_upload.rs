@@ -1,45 +1,494 @@
-use crate::config::Config;
+use crate::aws::load_aws_config;
+use crate::config::{
+    Config, DELTA_FILE_NAME, FORMATS_SUMMARY_FILE_NAME, RAW_JSON_FILE_NAME, RESOURCES_FILE_NAME,
+    TAGS_FILE_NAME,
+};
+use crate::csv_writer::OrgFile;
 use crate::error::AppError;
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::primitives::ByteStream;
+use crate::summary::{FailedDataset, RunManifest};
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, ServerSideEncryption, StorageClass,
+};
 use aws_sdk_s3::Client as S3Client;
-use aws_types::region::Region;
-use tracing::info;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use futures::stream::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Read;
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
 
-/// Uploads the given CSV file to the configured S3 bucket.
-/// Streams the file directly from the filesystem for memory efficiency.
-/// Logs file size and upload status.
+/// Size of each part in a multipart upload (S3 requires at least 5MB for all but the last part).
+const MULTIPART_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Number of per-organisation files uploaded to S3 concurrently in `SPLIT_BY_ORG` mode.
+const ORG_UPLOAD_CONCURRENCY: usize = 4;
+
+/// Creates an S3 client using the shared AWS configuration.
+/// The SDK's built-in retries are disabled because every S3 call, read or write, goes through
+/// `with_retries` (or `send_with_retries`), which applies our own backoff and logging; leaving
+/// both on would multiply attempts.
+pub async fn create_s3_client(config: &Config) -> S3Client {
+    let s3_config = aws_sdk_s3::config::Builder::from(&load_aws_config(config).await)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    S3Client::from_conf(s3_config)
+}
+
+/// An output file uploaded to S3.
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    /// S3 key the file was written to
+    pub key: String,
+    /// Hex-encoded SHA-256 digest of the file, also stored as `x-amz-meta-sha256`
+    pub sha256: String,
+    /// Whether the PUT was skipped because the object in S3 already had the same digest
+    pub skipped: bool,
+}
+
+/// Uploads the given output file to the configured S3 bucket and returns the key it was written to
+/// along with the file's SHA-256 digest, which is logged and attached as `sha256` user metadata.
+/// When `partition_by_date` is enabled the file goes to a date-partitioned, timestamped key
+/// and is then copied to the stable `latest/` key; otherwise it is written to the stable key.
+/// `s3_storage_class` applies to the uploaded object; the `latest/` copy stays in STANDARD
+/// so downstream readers can always fetch it.
+/// With `skip_unchanged_upload`, the stable key's stored `sha256` metadata is checked first and
+/// nothing is written if it matches the local digest; the returned key is then the stable key.
 ///
 /// # Arguments
+/// * `client` - The S3 client to upload with
 /// * `config` - The application configuration (must contain bucket name)
-/// * `csv_file` - The path to the CSV file to upload
-pub async fn upload_to_s3(config: &Config, csv_file: &str) -> Result<(), AppError> {
-    info!("Uploading {} to S3 bucket...", csv_file);
+/// * `output_file` - The path to the output file to upload
+#[instrument(skip_all, fields(phase = "upload"))]
+pub async fn upload_to_s3(
+    client: &S3Client,
+    config: &Config,
+    output_file: &str,
+) -> Result<UploadedFile, AppError> {
+    info!("Uploading {} to S3 bucket...", output_file);
 
-    // Load AWS configuration with optimised settings
-    let region_provider =
-        RegionProviderChain::default_provider().or_else(Region::new(config.aws_region.clone()));
-    let aws_config = aws_config::from_env().region(region_provider).load().await;
+    let bucket = &config.bucket_name;
+    let sha256 = file_sha256(output_file)?;
+    info!("Output file SHA-256: {}", sha256);
+
+    if config.skip_unchanged_upload {
+        let key = config.s3_key();
+        if stored_sha256(client, config, &key).await.as_deref() == Some(sha256.as_str()) {
+            info!("content unchanged, skipping upload: key={}", key);
+            return Ok(UploadedFile {
+                key,
+                sha256,
+                skipped: true,
+            });
+        }
+    }
+
+    if !config.partition_by_date {
+        let key = config.s3_key();
+        put_file(
+            client,
+            config,
+            &key,
+            output_file,
+            config.output_content_type(),
+            &sha256,
+        )
+        .await?;
+        return Ok(UploadedFile {
+            key,
+            sha256,
+            skipped: false,
+        });
+    }
+
+    let key = config.partitioned_s3_key(Utc::now());
+    put_file(
+        client,
+        config,
+        &key,
+        output_file,
+        config.output_content_type(),
+        &sha256,
+    )
+    .await?;
+    // Server-side copy to the stable path so downstream jobs don't need to know the timestamp.
+    let latest_key = config.s3_key();
+    with_retries(config, "S3 copy", || {
+        client
+            .copy_object()
+            .bucket(bucket)
+            .key(&latest_key)
+            .copy_source(copy_source(bucket, &key))
+            .set_server_side_encryption(server_side_encryption(config))
+            .set_ssekms_key_id(ssekms_key_id(config))
+            .send()
+    })
+    .await?;
+    info!(
+        "Copied {} to latest key: bucket={}, key={}",
+        key, bucket, latest_key
+    );
+    Ok(UploadedFile {
+        key,
+        sha256,
+        skipped: false,
+    })
+}
+
+/// Returns the `sha256` user metadata of an existing object, or None if the object is missing,
+/// has no digest, or the HEAD request fails (in which case the upload simply goes ahead).
+async fn stored_sha256(client: &S3Client, config: &Config, key: &str) -> Option<String> {
+    match send_with_retries(config, "S3 head", || {
+        client
+            .head_object()
+            .bucket(&config.bucket_name)
+            .key(key)
+            .send()
+    })
+    .await
+    {
+        Ok(output) => output.metadata()?.get("sha256").cloned(),
+        Err(SdkError::ServiceError(e)) if e.err().is_not_found() => None,
+        Err(e) => {
+            warn!(
+                "Could not read the existing object's digest; uploading anyway: {}",
+                DisplayErrorContext(&e)
+            );
+            None
+        }
+    }
+}
+
+/// Computes the hex-encoded SHA-256 digest of a file, reading it in chunks so large
+/// outputs are never held in memory.
+pub fn file_sha256(path: &str) -> Result<String, AppError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Uploads the normalised `dataset_tags.csv` file under the key prefix and returns its key.
+pub async fn upload_tags_file(
+    client: &S3Client,
+    config: &Config,
+    path: &str,
+) -> Result<String, AppError> {
+    upload_csv_file(client, config, TAGS_FILE_NAME, path).await
+}
+
+/// Uploads the `formats_summary.csv` file under the key prefix and returns its key.
+pub async fn upload_formats_summary(
+    client: &S3Client,
+    config: &Config,
+    path: &str,
+) -> Result<String, AppError> {
+    upload_csv_file(client, config, FORMATS_SUMMARY_FILE_NAME, path).await
+}
+
+/// Uploads the `resources.csv` file to `resources/resources.csv` under the key prefix and
+/// returns its key.
+pub async fn upload_resources_file(
+    client: &S3Client,
+    config: &Config,
+    path: &str,
+) -> Result<String, AppError> {
+    upload_csv_file(
+        client,
+        config,
+        &format!("resources/{RESOURCES_FILE_NAME}"),
+        path,
+    )
+    .await
+}
 
-    let client = S3Client::new(&aws_config);
+/// Uploads the incremental delta CSV to `delta/changed_datasets.csv` under the key prefix and
+/// returns its key. The full output stays at the main key as the next run's baseline.
+pub async fn upload_delta_file(
+    client: &S3Client,
+    config: &Config,
+    path: &str,
+) -> Result<String, AppError> {
+    upload_csv_file(client, config, &format!("delta/{DELTA_FILE_NAME}"), path).await
+}
+
+/// Uploads the raw JSON dump to `raw/raw_datasets.ndjson` under the key prefix and returns
+/// its key.
+pub async fn upload_raw_json(
+    client: &S3Client,
+    config: &Config,
+    path: &str,
+) -> Result<String, AppError> {
+    let key = config.prefixed_key(&format!("raw/{RAW_JSON_FILE_NAME}"));
+    let sha256 = file_sha256(path)?;
+    put_file(client, config, &key, path, "application/x-ndjson", &sha256).await?;
+    Ok(key)
+}
+
+/// Uploads the per-organisation CSVs written in `SPLIT_BY_ORG` mode, each to
+/// `org={slug}/{file name}` under the key prefix, a few at a time. Returns their keys in the
+/// order given.
+pub async fn upload_org_files(
+    client: &S3Client,
+    config: &Config,
+    files: &[OrgFile],
+) -> Result<Vec<String>, AppError> {
+    futures::stream::iter(files)
+        .map(|file| async move {
+            upload_csv_file(client, config, &config.org_file_key(&file.slug), &file.path).await
+        })
+        .buffered(ORG_UPLOAD_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// Uploads a CSV file to `file_name` under the key prefix and returns its key.
+async fn upload_csv_file(
+    client: &S3Client,
+    config: &Config,
+    file_name: &str,
+    path: &str,
+) -> Result<String, AppError> {
+    let key = config.prefixed_key(file_name);
+    let sha256 = file_sha256(path)?;
+    put_file(client, config, &key, path, "text/csv", &sha256).await?;
+    Ok(key)
+}
+
+/// Returns the manifest key for an output key: the extension is replaced with `.manifest.json`,
+/// e.g. `DataGovUK_Datasets.csv` becomes `DataGovUK_Datasets.manifest.json`.
+pub fn manifest_key(output_key: &str) -> String {
+    let file_start = output_key.rfind('/').map_or(0, |i| i + 1);
+    let stem = match output_key[file_start..].rfind('.') {
+        Some(dot) => &output_key[..file_start + dot],
+        None => output_key,
+    };
+    format!("{stem}.manifest.json")
+}
+
+/// Uploads the run manifest as JSON next to the output file and returns the key it was written to.
+pub async fn upload_manifest(
+    client: &S3Client,
+    config: &Config,
+    manifest: &RunManifest,
+) -> Result<String, AppError> {
     let bucket = &config.bucket_name;
-    let key = csv_file.split('/').next_back().unwrap_or(csv_file);
+    let key = manifest_key(&manifest.output_key);
+    let body = serde_json::to_vec_pretty(manifest)?;
+    with_retries(config, "S3 manifest upload", || {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type("application/json")
+            .set_server_side_encryption(server_side_encryption(config))
+            .set_ssekms_key_id(ssekms_key_id(config))
+            .body(ByteStream::from(body.clone()))
+            .send()
+    })
+    .await?;
+    info!("Uploaded run manifest: bucket={}, key={}", bucket, key);
+    Ok(key)
+}
+
+/// Uploads the failed dataset ids as JSON to `failed_ids_key` under the key prefix and returns
+/// the key it was written to.
+pub async fn upload_failed_ids(
+    client: &S3Client,
+    config: &Config,
+    failed: &[FailedDataset],
+) -> Result<String, AppError> {
+    let bucket = &config.bucket_name;
+    let key = config.prefixed_key(&config.failed_ids_key);
+    let body = serde_json::to_vec_pretty(failed)?;
+    with_retries(config, "S3 failed ids upload", || {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type("application/json")
+            .set_server_side_encryption(server_side_encryption(config))
+            .set_ssekms_key_id(ssekms_key_id(config))
+            .body(ByteStream::from(body.clone()))
+            .send()
+    })
+    .await?;
+    info!(
+        "Uploaded {} failed dataset ids: bucket={}, key={}",
+        failed.len(),
+        bucket,
+        key
+    );
+    Ok(key)
+}
 
-    // Use ByteStream::from_path for memory-efficient streaming upload
-    let bytestream = ByteStream::from_path(csv_file)
+/// Stores the package list ETag under `LIST_ETAG_KEY` so the next run can send it as
+/// `If-None-Match` (`USE_CONDITIONAL_GET`).
+pub async fn upload_list_etag(
+    client: &S3Client,
+    config: &Config,
+    etag: &str,
+) -> Result<(), AppError> {
+    let bucket = &config.bucket_name;
+    let key = config.prefixed_key(&config.list_etag_key);
+    with_retries(config, "S3 list ETag upload", || {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type("text/plain")
+            .set_server_side_encryption(server_side_encryption(config))
+            .set_ssekms_key_id(ssekms_key_id(config))
+            .body(ByteStream::from(etag.as_bytes().to_vec()))
+            .send()
+    })
+    .await?;
+    info!("Stored package list ETag: bucket={}, key={}", bucket, key);
+    Ok(())
+}
+
+/// Reads the package list ETag stored by `upload_list_etag`, or None if no run has stored one.
+pub async fn fetch_list_etag(
+    client: &S3Client,
+    config: &Config,
+) -> Result<Option<String>, AppError> {
+    let bucket = &config.bucket_name;
+    let key = config.prefixed_key(&config.list_etag_key);
+    let response = match send_with_retries(config, "S3 download", || {
+        client.get_object().bucket(bucket).key(&key).send()
+    })
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return match e.into_service_error() {
+                GetObjectError::NoSuchKey(_) => Ok(None),
+                e => Err(AppError::Other(format!(
+                    "S3 download of list ETag failed: {e}"
+                ))),
+            };
+        }
+    };
+    let body = response
+        .body
+        .collect()
         .await
-        .map_err(|e| AppError::Other(e.to_string()))?;
+        .map_err(|e| AppError::Other(format!("S3 download failed: {e}")))?
+        .into_bytes();
+    let etag = String::from_utf8_lossy(&body).trim().to_string();
+    Ok((!etag.is_empty()).then_some(etag))
+}
 
-    info!("Uploading file to S3: bucket={}, key={}", bucket, key);
+/// Downloads a dead-letter file written by `upload_failed_ids` from the configured bucket.
+pub async fn fetch_failed_ids(
+    client: &S3Client,
+    config: &Config,
+    key: &str,
+) -> Result<Vec<FailedDataset>, AppError> {
+    let bucket = &config.bucket_name;
+    let response = send_with_retries(config, "S3 download", || {
+        client.get_object().bucket(bucket).key(key).send()
+    })
+    .await
+    .map_err(|e| {
+        AppError::Other(format!(
+            "S3 download of failed ids from {key} failed: {}",
+            DisplayErrorContext(&e)
+        ))
+    })?;
+    let body = response
+        .body
+        .collect()
+        .await
+        .map_err(|e| AppError::Other(format!("S3 download failed: {e}")))?
+        .into_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
 
-    client
-        .put_object()
-        .bucket(bucket)
-        .key(key)
-        .body(bytestream)
-        .send()
+/// Downloads the newline-delimited dataset id list named by `INPUT_IDS_URL`.
+pub async fn fetch_input_ids_text(
+    client: &S3Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+) -> Result<String, AppError> {
+    let response = send_with_retries(config, "S3 download", || {
+        client.get_object().bucket(bucket).key(key).send()
+    })
+    .await
+    .map_err(|e| {
+        AppError::Other(format!(
+            "S3 download of input ids from s3://{bucket}/{key} failed: {}",
+            DisplayErrorContext(&e)
+        ))
+    })?;
+    let body = response
+        .body
+        .collect()
         .await
-        .map_err(|e| AppError::Other(format!("S3 upload failed: {e}")))?;
+        .map_err(|e| AppError::Other(format!("S3 download failed: {e}")))?
+        .into_bytes();
+    String::from_utf8(body.to_vec())
+        .map_err(|_| AppError::Other(format!("s3://{bucket}/{key} is not valid UTF-8")))
+}
+
+/// Uploads a single local file to the configured bucket under the given key, with the given
+/// Content-Type and the configured Cache-Control. Files above `multipart_threshold_bytes` use
+/// multipart upload; smaller files use a single PUT.
+async fn put_file(
+    client: &S3Client,
+    config: &Config,
+    key: &str,
+    path: &str,
+    content_type: &str,
+    sha256: &str,
+) -> Result<(), AppError> {
+    let bucket = &config.bucket_name;
+    let file_size = std::fs::metadata(path)?.len();
+    info!(
+        "Uploading file to S3: bucket={}, key={}, size={} bytes",
+        bucket, key, file_size
+    );
+
+    if file_size > config.multipart_threshold_bytes {
+        put_file_multipart(client, config, key, path, file_size, content_type, sha256).await?;
+    } else {
+        with_retries(config, "S3 upload", || async {
+            // Use ByteStream::from_path for memory-efficient streaming upload.
+            // The stream is rebuilt on every attempt since a body can only be sent once.
+            let bytestream = ByteStream::from_path(path)
+                .await
+                .map_err(SdkError::construction_failure)?;
+            client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .content_type(content_type)
+                .set_cache_control(config.cache_control.clone())
+                .storage_class(StorageClass::from(config.s3_storage_class.as_str()))
+                .metadata("sha256", sha256)
+                .set_server_side_encryption(server_side_encryption(config))
+                .set_ssekms_key_id(ssekms_key_id(config))
+                .set_tagging(object_tagging(config))
+                .body(bytestream)
+                .send()
+                .await
+        })
+        .await?;
+    }
 
     info!(
         "Successfully uploaded file to S3: bucket={}, key={}",
@@ -47,3 +496,364 @@ pub async fn upload_to_s3(config: &Config, csv_file: &str) -> Result<(), AppErro
     );
     Ok(())
 }
+
+/// Uploads a large file using S3 multipart upload, streaming parts concurrently from disk.
+/// The multipart upload is aborted if any part fails so no orphaned parts are left behind.
+async fn put_file_multipart(
+    client: &S3Client,
+    config: &Config,
+    key: &str,
+    path: &str,
+    file_size: u64,
+    content_type: &str,
+    sha256: &str,
+) -> Result<(), AppError> {
+    let bucket = &config.bucket_name;
+    let upload = with_retries(config, "S3 multipart upload start", || {
+        client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_cache_control(config.cache_control.clone())
+            .storage_class(StorageClass::from(config.s3_storage_class.as_str()))
+            .metadata("sha256", sha256)
+            .set_server_side_encryption(server_side_encryption(config))
+            .set_ssekms_key_id(ssekms_key_id(config))
+            .set_tagging(object_tagging(config))
+            .send()
+    })
+    .await?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| AppError::Other("S3 returned no multipart upload id".to_string()))?;
+
+    match upload_parts(client, config, key, path, upload_id, file_size).await {
+        Ok(parts) => {
+            let part_count = parts.len();
+            let completed = CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build();
+            with_retries(config, "S3 multipart upload completion", || {
+                client
+                    .complete_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(completed.clone())
+                    .send()
+            })
+            .await?;
+            info!("Multipart upload complete: {} parts uploaded", part_count);
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(abort_err) = with_retries(config, "S3 multipart upload abort", || {
+                client
+                    .abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+            })
+            .await
+            {
+                error!(
+                    "Failed to abort multipart upload {}: {}",
+                    upload_id, abort_err
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Uploads every part of the file, `s3_upload_concurrency` at a time, and returns the completed
+/// parts in order.
+async fn upload_parts(
+    client: &S3Client,
+    config: &Config,
+    key: &str,
+    path: &str,
+    upload_id: &str,
+    file_size: u64,
+) -> Result<Vec<CompletedPart>, AppError> {
+    let bucket = &config.bucket_name;
+    let mut parts = futures::stream::iter(multipart_part_ranges(file_size, MULTIPART_PART_SIZE))
+        .map(|(part_number, offset, length)| async move {
+            let operation = format!("S3 part {part_number} upload");
+            let response = with_retries(config, &operation, || async {
+                let body = ByteStream::read_from()
+                    .path(path)
+                    .offset(offset)
+                    .length(Length::Exact(length))
+                    .build()
+                    .await
+                    .map_err(SdkError::construction_failure)?;
+                client
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(body)
+                    .send()
+                    .await
+            })
+            .await?;
+            Ok::<_, AppError>(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(response.e_tag().map(String::from))
+                    .build(),
+            )
+        })
+        .buffer_unordered(config.s3_upload_concurrency)
+        .try_collect::<Vec<_>>()
+        .await?;
+    // S3 requires the parts list in ascending part-number order.
+    parts.sort_by_key(|part| part.part_number());
+    Ok(parts)
+}
+
+/// Returns the URL-encoded S3 tag set (`run_id=...&source=...`) for uploaded objects when
+/// `TAG_OBJECTS` is set, or None. The source is the CKAN API base URL (several are separated
+/// by spaces, since S3 tag values can't contain commas).
+pub fn object_tagging(config: &Config) -> Option<String> {
+    if !config.tag_objects {
+        return None;
+    }
+    Some(
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("run_id", &config.run_id)
+            .append_pair("source", &config.ckan_base_urls().join(" "))
+            .finish(),
+    )
+}
+
+/// Returns the `CopySource` value for copying `key` within `bucket`. S3 expects it
+/// URL-encoded, so every byte outside the unreserved set is percent-encoded except `/`, which
+/// separates the bucket and key segments.
+pub fn copy_source(bucket: &str, key: &str) -> String {
+    let mut source = format!("{bucket}/");
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            source.push(byte as char);
+        } else {
+            source.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    source
+}
+
+/// Maps the validated `s3_sse` setting to the SDK's server-side encryption value.
+/// Returns None when unset, so uploads fall back to the bucket's default encryption.
+fn server_side_encryption(config: &Config) -> Option<ServerSideEncryption> {
+    match config.s3_sse.as_deref()?.to_lowercase().as_str() {
+        "aws:kms" => Some(ServerSideEncryption::AwsKms),
+        _ => Some(ServerSideEncryption::Aes256),
+    }
+}
+
+/// Returns the KMS key id to send, only when KMS encryption is selected.
+fn ssekms_key_id(config: &Config) -> Option<String> {
+    match server_side_encryption(config)? {
+        ServerSideEncryption::AwsKms => config.s3_kms_key_id.clone(),
+        _ => None,
+    }
+}
+
+/// Runs an S3 operation, retrying throttling, 5xx and network errors with exponential backoff
+/// (`s3_retry_base_delay_ms`, doubling each attempt, up to `s3_max_retries` retries).
+/// `SlowDown` responses, which S3 sends when a prefix is written too fast, have their own
+/// longer, capped backoff and retry count (see `slowdown_delay`) so they don't use up the
+/// generic retries. Errors that won't succeed on retry, such as 403 or 404, are returned
+/// immediately.
+async fn with_retries<T, E, F, Fut>(
+    config: &Config,
+    operation: &str,
+    send: F,
+) -> Result<T, AppError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    send_with_retries(config, operation, send)
+        .await
+        .map_err(|e| AppError::Other(format!("{operation} failed: {}", DisplayErrorContext(&e))))
+}
+
+/// Does the retrying for `with_retries`, but hands back the SDK error itself so reads can
+/// still tell a missing object (`NoSuchKey`, 404) apart from a failure.
+async fn send_with_retries<T, E, F, Fut>(
+    config: &Config,
+    operation: &str,
+    mut send: F,
+) -> Result<T, SdkError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let mut retries = 0;
+    let mut slowdowns = 0;
+    loop {
+        match send().await {
+            Ok(output) => return Ok(output),
+            Err(e)
+                if e.code() == Some("SlowDown") && slowdowns < config.s3_slowdown_max_retries =>
+            {
+                slowdowns += 1;
+                let delay = slowdown_delay(config, slowdowns);
+                warn!(
+                    "{} slowed down by S3, backing off {} ms (slowdown retry {}/{})",
+                    operation,
+                    delay.as_millis(),
+                    slowdowns,
+                    config.s3_slowdown_max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e)
+                if retries < config.s3_max_retries
+                    && e.code() != Some("SlowDown")
+                    && is_retryable_s3_error(&e) =>
+            {
+                retries += 1;
+                let delay = Duration::from_millis(
+                    config
+                        .s3_retry_base_delay_ms
+                        .saturating_mul(1 << (retries - 1).min(16)),
+                );
+                warn!(
+                    "{} failed, retrying in {} ms (retry {}/{}): {}",
+                    operation,
+                    delay.as_millis(),
+                    retries,
+                    config.s3_max_retries,
+                    DisplayErrorContext(&e)
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The backoff before the given `SlowDown` retry (counting from 1): `s3_slowdown_base_delay_ms`
+/// doubling on each retry, capped at `s3_slowdown_max_delay_ms`.
+pub fn slowdown_delay(config: &Config, slowdown: u32) -> Duration {
+    let delay = config
+        .s3_slowdown_base_delay_ms
+        .saturating_mul(1 << slowdown.saturating_sub(1).min(16));
+    Duration::from_millis(delay.min(config.s3_slowdown_max_delay_ms))
+}
+
+/// Returns true if an S3 error is transient: throttling, a 5xx response, or a timeout or
+/// network failure. Other client errors (403, 404, ...) are not worth retrying.
+fn is_retryable_s3_error<E: ProvideErrorMetadata>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(_) => {
+            let status = err
+                .raw_response()
+                .map(|response| response.status().as_u16())
+                .unwrap_or_default();
+            status == 429
+                || status >= 500
+                || matches!(
+                    err.code(),
+                    Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestTimeout")
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Splits a file into multipart upload parts, returning `(part_number, offset, length)` for each.
+/// Part numbers start at 1 as required by S3.
+pub fn multipart_part_ranges(file_size: u64, part_size: u64) -> Vec<(i32, u64, u64)> {
+    (0..file_size.div_ceil(part_size))
+        .map(|i| {
+            let offset = i * part_size;
+            (i as i32 + 1, offset, part_size.min(file_size - offset))
+        })
+        .collect()
+}
+
+/// Downloads the previously uploaded CSV from S3 and returns a map of dataset id to
+/// `modified` timestamp, used by incremental mode to find new or changed datasets.
+/// Gzipped objects (a `.gz` key or `Content-Encoding: gzip`) are decompressed before parsing.
+/// Returns an empty map if there is no previous upload.
+pub async fn fetch_last_run_timestamps(
+    client: &S3Client,
+    config: &Config,
+) -> Result<HashMap<String, String>, AppError> {
+    let bucket = &config.bucket_name;
+    let key = config.s3_key();
+    info!(
+        "Fetching previous run from S3: bucket={}, key={}",
+        bucket, key
+    );
+    let response = match send_with_retries(config, "S3 download", || {
+        client.get_object().bucket(bucket).key(&key).send()
+    })
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return match e.into_service_error() {
+                GetObjectError::NoSuchKey(_) => {
+                    info!("No previous run found at key={}", key);
+                    Ok(HashMap::new())
+                }
+                e => Err(AppError::Other(format!("S3 download failed: {e}"))),
+            };
+        }
+    };
+    let gzipped = key.ends_with(".gz")
+        || response
+            .content_encoding()
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip"));
+    let body = response
+        .body
+        .collect()
+        .await
+        .map_err(|e| AppError::Other(format!("S3 download failed: {e}")))?
+        .into_bytes();
+    if gzipped {
+        parse_last_run_timestamps(GzDecoder::new(body.as_ref()), config.csv_delimiter_byte())
+    } else {
+        parse_last_run_timestamps(body.as_ref(), config.csv_delimiter_byte())
+    }
+}
+
+/// Parses a previously written CSV into a map of dataset id to `modified` timestamp.
+pub fn parse_last_run_timestamps<R: Read>(
+    reader: R,
+    delimiter: u8,
+) -> Result<HashMap<String, String>, AppError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| AppError::Other(format!("Previous CSV has no '{name}' column")))
+    };
+    let id_idx = column("id")?;
+    let modified_idx = column("modified")?;
+    let mut timestamps = HashMap::new();
+    for record in rdr.records() {
+        let record = record?;
+        if let (Some(id), Some(modified)) = (record.get(id_idx), record.get(modified_idx)) {
+            timestamps.insert(id.to_string(), modified.to_string());
+        }
+    }
+    Ok(timestamps)
+}
@@ -0,0 +1,87 @@
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Tells a run to stop fetching early and finish with what it has, the same way the run
+/// deadline does. Only wired to signals in local/container mode; Lambda runs never see one.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+/// The sending half of a `Shutdown`.
+#[derive(Debug)]
+pub struct ShutdownTrigger {
+    tx: watch::Sender<bool>,
+}
+
+/// Creates a connected trigger and shutdown pair.
+pub fn shutdown_channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger { tx }, Shutdown { rx })
+}
+
+impl ShutdownTrigger {
+    /// Requests shutdown. Every clone of the paired `Shutdown` sees it, even ones that start
+    /// waiting later.
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+}
+
+impl Shutdown {
+    /// A shutdown that is never requested.
+    pub fn never() -> Self {
+        shutdown_channel().1
+    }
+
+    /// Waits until shutdown is requested. Never completes if the trigger was dropped without
+    /// firing.
+    pub async fn wait(mut self) {
+        if self.rx.wait_for(|requested| *requested).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Returns a `Shutdown` that fires on SIGTERM (as sent by container schedulers) or Ctrl-C.
+pub fn shutdown_on_signal() -> Shutdown {
+    let (trigger, shutdown) = shutdown_channel();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown signal received; finishing with the datasets fetched so far");
+        trigger.trigger();
+    });
+    shutdown
+}
+
+/// Waits for SIGTERM or Ctrl-C.
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = wait_for_ctrl_c() => {}
+            }
+        }
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            wait_for_ctrl_c().await;
+        }
+    }
+}
+
+/// Waits for Ctrl-C (SIGTERM is Unix-only).
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    wait_for_ctrl_c().await;
+}
+
+/// Waits for Ctrl-C, or forever if the handler can't be installed.
+async fn wait_for_ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,78 @@
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use rusqlite::{params, params_from_iter, Connection};
+
+/// Writes the dataset metadata to a SQLite database at `config.output_file()`.
+/// Metadata goes into a `datasets` table keyed by id, and each download URL becomes a row in
+/// a normalised `resources` table with a foreign key back to its dataset, so the data can be
+/// queried relationally instead of through the wide download_url_N layout.
+pub fn write_sqlite(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<(), AppError> {
+    let sqlite_error = |e: rusqlite::Error| AppError::Other(format!("SQLite write failed: {e}"));
+    let path = config.output_file();
+    // Start from an empty database rather than appending to a previous run.
+    if std::path::Path::new(&path).exists() {
+        std::fs::remove_file(&path)?;
+    }
+    let mut conn = Connection::open(&path).map_err(sqlite_error)?;
+    let columns = DatasetMetadata::COLUMNS
+        .iter()
+        .map(|c| {
+            if *c == "id" {
+                "id TEXT PRIMARY KEY".to_string()
+            } else {
+                format!("{c} TEXT NOT NULL")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    conn.execute_batch(&format!(
+        "CREATE TABLE datasets ({columns});
+         CREATE TABLE resources (
+             dataset_id TEXT NOT NULL REFERENCES datasets(id),
+             position INTEGER NOT NULL,
+             url TEXT NOT NULL,
+             size TEXT,
+             last_modified TEXT,
+             status TEXT,
+             PRIMARY KEY (dataset_id, position)
+         );"
+    ))
+    .map_err(sqlite_error)?;
+
+    // A single transaction keeps the inserts fast and the file consistent.
+    let tx = conn.transaction().map_err(sqlite_error)?;
+    {
+        let placeholders = vec!["?"; DatasetMetadata::COLUMNS.len()].join(", ");
+        let mut insert_dataset = tx
+            .prepare(&format!("INSERT INTO datasets VALUES ({placeholders})"))
+            .map_err(sqlite_error)?;
+        let mut insert_resource = tx
+            .prepare("INSERT INTO resources VALUES (?, ?, ?, ?, ?, ?)")
+            .map_err(sqlite_error)?;
+        for (meta, urls) in dataset_metadata {
+            insert_dataset
+                .execute(params_from_iter(meta.values()))
+                .map_err(sqlite_error)?;
+            for (i, url) in urls.urls.iter().enumerate() {
+                let optional = |values: &[String]| values.get(i).filter(|v| !v.is_empty()).cloned();
+                insert_resource
+                    .execute(params![
+                        meta.id,
+                        i as i64 + 1,
+                        url,
+                        optional(&urls.sizes),
+                        optional(&urls.last_modified),
+                        optional(&urls.statuses),
+                    ])
+                    .map_err(sqlite_error)?;
+            }
+        }
+    }
+    tx.commit().map_err(sqlite_error)?;
+    Ok(())
+}
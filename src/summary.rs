@@ -0,0 +1,232 @@
+use crate::error::AppError;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Summary of a single run, returned from `process_datasets` and included in the handler response.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RunSummary {
+    /// Number of dataset IDs returned by the CKAN list endpoint (excluding any resumed from a
+    /// checkpoint)
+    pub datasets_listed: usize,
+    /// Number of datasets whose metadata was fetched successfully
+    pub datasets_fetched: usize,
+    /// Number of datasets that failed or returned no metadata
+    pub datasets_failed: usize,
+    /// Number of datasets skipped because a resumed checkpoint had already completed them
+    pub datasets_resumed: usize,
+    /// Number of listed datasets not fetched because the run deadline was reached
+    pub datasets_not_fetched: usize,
+    /// Whether the run deadline cut fetching short, making the output partial
+    pub deadline_reached: bool,
+    /// Number of dataset fetches that failed with an HTTP or network error
+    pub http_failures: usize,
+    /// Number of dataset fetches whose response could not be parsed
+    pub parse_failures: usize,
+    /// Number of rows written to the output file
+    pub rows_written: usize,
+    /// Number of download URLs that failed validation (only counted when `VALIDATE_URLS` is set)
+    pub broken_urls: usize,
+    /// Time spent fetching the dataset list and metadata, in milliseconds
+    pub fetch_duration_ms: u64,
+    /// Time spent uploading the output to S3, in milliseconds
+    pub upload_duration_ms: u64,
+    /// Total run time, in milliseconds
+    pub total_duration_ms: u64,
+    /// S3 key the output was uploaded to
+    pub output_key: String,
+    /// Hex-encoded SHA-256 digest of the uploaded output, for comparing consecutive runs
+    pub output_sha256: String,
+    /// Whether the output upload was skipped because S3 already held identical content
+    pub upload_skipped: bool,
+    /// S3 key the normalised tags file was uploaded to, if `NORMALIZE_TAGS` is set
+    pub tags_output_key: Option<String>,
+    /// Total size of CKAN response bodies downloaded (package list and metadata), in bytes
+    pub bytes_downloaded: u64,
+    /// Number of resources of each format (trimmed and uppercased) across all fetched datasets
+    pub resource_formats: BTreeMap<String, usize>,
+    /// S3 key the format counts file was uploaded to, if `WRITE_FORMATS_SUMMARY` is set
+    pub formats_output_key: Option<String>,
+    /// Number of new or changed datasets written to the delta file, if `INCREMENTAL` is set
+    pub delta_rows: Option<usize>,
+    /// S3 key the delta file was uploaded to, if `INCREMENTAL` is set
+    pub delta_output_key: Option<String>,
+    /// S3 key the resources file was uploaded to, if `WRITE_RESOURCES` is set
+    pub resources_output_key: Option<String>,
+    /// S3 key the raw JSON dump was uploaded to, if `DUMP_RAW_JSON` is set
+    pub raw_json_output_key: Option<String>,
+    /// Whether the run was skipped because CKAN reported the package list unchanged
+    /// (`USE_CONDITIONAL_GET`)
+    pub catalogue_unchanged: bool,
+    /// Number of dataset IDs not in the previous upload, if `DIFF_PREVIOUS` is set
+    pub added_ids: Option<usize>,
+    /// Number of dataset IDs in the previous upload but missing from this run, if
+    /// `DIFF_PREVIOUS` is set
+    pub removed_ids: Option<usize>,
+    /// The added dataset IDs, sorted, if `DIFF_LIST_IDS` is also set
+    pub added_id_list: Vec<String>,
+    /// The removed dataset IDs, sorted, if `DIFF_LIST_IDS` is also set
+    pub removed_id_list: Vec<String>,
+    /// S3 key the failed dataset ids were uploaded to, if any dataset failed
+    pub failed_ids_key: Option<String>,
+    /// S3 keys of the per-organisation output files, if `SPLIT_BY_ORG` is set
+    pub org_output_keys: Vec<String>,
+    /// The slowest successful metadata requests, slowest first
+    pub slowest_datasets: Vec<DatasetTiming>,
+}
+
+/// How long the metadata request for one dataset took.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DatasetTiming {
+    /// Dataset ID
+    pub dataset_id: String,
+    /// Request duration in milliseconds
+    pub duration_ms: u64,
+}
+
+impl RunSummary {
+    /// Records a successful fetch's duration, keeping only the `limit` slowest datasets.
+    pub fn record_timing(&mut self, dataset_id: &str, duration_ms: u64, limit: usize) {
+        let position = self
+            .slowest_datasets
+            .partition_point(|timing| timing.duration_ms >= duration_ms);
+        if position < limit {
+            self.slowest_datasets.insert(
+                position,
+                DatasetTiming {
+                    dataset_id: dataset_id.to_string(),
+                    duration_ms,
+                },
+            );
+            self.slowest_datasets.truncate(limit);
+        }
+    }
+
+    /// Counts each of a dataset's resource formats, normalised to trimmed uppercase so `csv`
+    /// and ` CSV` are counted together. Blank formats are skipped.
+    pub fn record_formats(&mut self, formats: &[String]) {
+        for format in formats.iter().map(|f| f.trim().to_uppercase()) {
+            if !format.is_empty() {
+                *self.resource_formats.entry(format).or_default() += 1;
+            }
+        }
+    }
+
+    /// Fraction of attempted datasets that failed, or 0 if none were attempted.
+    pub fn failure_ratio(&self) -> f64 {
+        let attempted = self.datasets_fetched + self.datasets_failed;
+        if attempted == 0 {
+            0.0
+        } else {
+            self.datasets_failed as f64 / attempted as f64
+        }
+    }
+
+    /// Records the difference between the previous upload and this run, keeping the ID lists
+    /// only when `list_ids` is set.
+    pub fn record_diff(&mut self, diff: DatasetDiff, list_ids: bool) {
+        self.added_ids = Some(diff.added.len());
+        self.removed_ids = Some(diff.removed.len());
+        if list_ids {
+            self.added_id_list = diff.added;
+            self.removed_id_list = diff.removed;
+        }
+    }
+
+    /// Tallies a failed dataset fetch under its error category.
+    pub fn record_fetch_error(&mut self, error: &AppError) {
+        match error.root() {
+            AppError::Http(_) => self.http_failures += 1,
+            AppError::SerdeJson(_) => self.parse_failures += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Dataset IDs added and removed since the previous upload, each sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DatasetDiff {
+    /// IDs in this run but not in the previous upload
+    pub added: Vec<String>,
+    /// IDs in the previous upload but not in this run
+    pub removed: Vec<String>,
+}
+
+impl DatasetDiff {
+    /// Compares the previous upload's dataset IDs with this run's.
+    pub fn between<'a>(
+        previous: impl IntoIterator<Item = &'a String>,
+        current: impl IntoIterator<Item = &'a String>,
+    ) -> Self {
+        let previous: HashSet<&String> = previous.into_iter().collect();
+        let current: HashSet<&String> = current.into_iter().collect();
+        let mut added: Vec<String> = current
+            .difference(&previous)
+            .map(|id| id.to_string())
+            .collect();
+        let mut removed: Vec<String> = previous
+            .difference(&current)
+            .map(|id| id.to_string())
+            .collect();
+        added.sort();
+        removed.sort();
+        Self { added, removed }
+    }
+}
+
+/// A dataset that failed or returned no metadata, recorded in the dead-letter file
+/// (`failed_ids.json`) so a later run can retry just these with `RETRY_FAILED_FROM`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedDataset {
+    /// Dataset ID
+    pub id: String,
+    /// Category of the last error (see `AppError::category`), or `not_found`
+    pub category: String,
+    /// CKAN API base URL the dataset was listed by
+    pub ckan_api_base_url: String,
+}
+
+/// Machine-readable description of a successful upload, written next to the output file
+/// when `WRITE_MANIFEST` is enabled so orchestration can pick up each run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunManifest {
+    /// Number of rows written to the output file
+    pub row_count: usize,
+    /// S3 key the output was uploaded to
+    pub output_key: String,
+    /// Size of the output file in bytes
+    pub byte_size: u64,
+    /// When the run started, in RFC 3339 format
+    pub run_timestamp: String,
+    /// Number of datasets that failed or returned no metadata
+    pub datasets_failed: usize,
+    /// CKAN API base URL(s) the datasets were fetched from, comma-separated
+    pub ckan_api_base_url: String,
+    /// Number of dataset IDs not in the previous upload, if `DIFF_PREVIOUS` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added_ids: Option<usize>,
+    /// Number of dataset IDs missing since the previous upload, if `DIFF_PREVIOUS` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removed_ids: Option<usize>,
+}
+
+impl RunManifest {
+    /// Builds the manifest for a run from its summary.
+    pub fn new(
+        summary: &RunSummary,
+        byte_size: u64,
+        run_timestamp: DateTime<Utc>,
+        ckan_api_base_url: &str,
+    ) -> Self {
+        Self {
+            row_count: summary.rows_written,
+            output_key: summary.output_key.clone(),
+            byte_size,
+            run_timestamp: run_timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            datasets_failed: summary.datasets_failed,
+            ckan_api_base_url: ckan_api_base_url.to_string(),
+            added_ids: summary.added_ids,
+            removed_ids: summary.removed_ids,
+        }
+    }
+}
@@ -2,11 +2,52 @@
 // These tests check the parsing of CKAN API responses into strongly-typed Rust structs.
 // They help ensure that changes to the API or our code are caught early.
 
-use crate::ckan::fetch_dataset_list;
+use crate::checkpoint::{load_checkpoint, rows_path, save_checkpoint, Checkpoint};
 use crate::ckan::PackageListResponse;
 use crate::ckan::PackageShowResponse;
-use crate::config::Config;
-use wiremock::matchers::{method, path};
+use crate::ckan::{
+    build_dataset_metadata, check_resource_count, clean_description, clean_html,
+    extract_resource_formats_and_urls, resource_details, CkanDataset, DatasetExtras, ResourceUrls,
+};
+use crate::ckan::{
+    create_http_client, fetch_dataset_list, fetch_dataset_list_if_changed, fetch_dataset_metadata,
+    is_transient, normalize_whitespace, retry_after_delay, startup_jitter, truncate_chars,
+    warn_if_slow, DatasetList, RetryBudget,
+};
+use crate::concurrency_ramp::ConcurrencyRamp;
+use crate::config::{
+    Config, ConfigSource, CsvQuoteStyle, CsvTerminator, DescriptionMode, LogFormat, SortBy,
+    DEFAULT_USER_AGENT,
+};
+use crate::csv_writer::{
+    validate_csv, write_csv, write_formats_csv, write_org_csvs, write_resources_csv,
+    write_tags_csv, StreamingCsvWriter,
+};
+use crate::dynamodb_writer::write_dynamodb;
+use crate::error::AppError;
+use crate::metrics::build_metric_data;
+use crate::metrics_server::{parse_request_line, start_metrics_server, PrometheusMetrics};
+use crate::notify::{notify_run_result, Notifier};
+use crate::parquet_writer::write_parquet;
+use crate::raw_json_writer::write_raw_json;
+use crate::s3_upload::{
+    copy_source, fetch_last_run_timestamps, file_sha256, manifest_key, multipart_part_ranges,
+    object_tagging, parse_last_run_timestamps, slowdown_delay, upload_to_s3,
+};
+use crate::shutdown::shutdown_channel;
+use crate::sqlite_writer::write_sqlite;
+use crate::summary::{DatasetDiff, FailedDataset, RunManifest, RunSummary};
+use crate::url_check::{check_download_urls, create_url_check_client};
+use crate::xlsx_writer::write_xlsx;
+use crate::{
+    check_failure_ratio, check_rows_written, is_modified_since, matches_organization,
+    parse_cli_options, process_datasets, process_datasets_until, sort_dataset_metadata, CliOptions,
+    DatasetMetadata,
+};
+use chrono::TimeZone;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wiremock::matchers::{any, header, method, path, query_param};
 use wiremock::{Mock, MockServer, ResponseTemplate};
 
 #[test]
@@ -93,7 +134,351 @@ async fn test_fetch_dataset_list_success() {
     let mut config = Config::new();
     config.ckan_api_base_url = mock_server.uri();
     let client = reqwest::Client::new();
-    let result = fetch_dataset_list(&client, &config, false).await.unwrap();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["dataset1", "dataset2"]);
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_list_max_datasets() {
+    // Test that MAX_DATASETS caps the package list with or without test mode.
+    let mock_server = MockServer::start().await;
+    let response = serde_json::json!({ "result": ["d1", "d2", "d3", "d4", "d5"] });
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(response))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    config.max_datasets = Some(3);
+    config.test_mode_dataset_limit = 4;
+    let client = reqwest::Client::new();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["d1", "d2", "d3"]);
+    // The smaller of the test mode limit and MAX_DATASETS wins.
+    let result = fetch_dataset_list(&client, &config, true, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result.len(), 3);
+    config.test_mode_dataset_limit = 2;
+    let result = fetch_dataset_list(&client, &config, true, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["d1", "d2"]);
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_list_from_input_ids_file() {
+    // Test that INPUT_IDS_FILE replaces the package list call and test mode still truncates it.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+    let ids_file = temp_csv_path("input_ids");
+    std::fs::write(&ids_file, "road-traffic\n  bus-stops  \n\nrail-stations\n").unwrap();
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    config.input_ids_file = Some(ids_file.clone());
+    assert!(config.validate().is_ok());
+    let client = reqwest::Client::new();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["road-traffic", "bus-stops", "rail-stations"]);
+    config.test_mode_dataset_limit = 2;
+    let result = fetch_dataset_list(&client, &config, true, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["road-traffic", "bus-stops"]);
+
+    config.input_ids_url = Some("s3://bucket/ids.txt".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    config.input_ids_file = None;
+    assert_eq!(config.input_ids_s3_location(), Some(("bucket", "ids.txt")));
+    config.input_ids_url = Some("https://bucket/ids.txt".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    std::fs::remove_file(&ids_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_bytes_downloaded_counts_response_bodies() {
+    // Test that the list and metadata response sizes are added to the download counter and the run summary.
+    let mock_server = MockServer::start().await;
+    let list_body = r#"{"result":["abc"]}"#;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(list_body))
+        .mount(&mock_server)
+        .await;
+    let show_body = package_show_json("abc").to_string();
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(show_body.clone()))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = Arc::new(reqwest::Client::new());
+    let counter = AtomicU64::new(0);
+    fetch_dataset_list(&client, &config, false, &counter)
+        .await
+        .unwrap();
+    assert_eq!(counter.load(Ordering::Relaxed), list_body.len() as u64);
+    fetch_dataset_metadata(
+        Arc::clone(&client),
+        &config,
+        "abc".to_string(),
+        &counter,
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap();
+    let expected = (list_body.len() + show_body.len()) as u64;
+    assert_eq!(counter.load(Ordering::Relaxed), expected);
+
+    config.csv_file = temp_csv_path("bytes_downloaded");
+    config.dry_run = true;
+    config.preflight = false;
+    let summary = process_datasets(&config, false, &mock_s3_client(&mock_server.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.bytes_downloaded, expected);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_ckan_success_false_is_an_error() {
+    // Test that success:false responses become CKAN errors rather than an empty list or a missing dataset.
+    let mock_server = MockServer::start().await;
+    let failure = serde_json::json!({
+        "success": false,
+        "error": { "__type": "Authorization Error", "message": "Access denied" }
+    });
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(failure.clone()))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(failure))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = Arc::new(reqwest::Client::new());
+    let err = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap_err();
+    assert!(matches!(&err, AppError::Ckan(msg) if msg.contains("Access denied")));
+    let err = fetch_dataset_metadata(
+        client,
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.category(), "ckan");
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_metadata_error_names_dataset() {
+    // Test that a failed metadata fetch is tagged with the dataset id but keeps its category.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let err = fetch_dataset_metadata(
+        Arc::new(reqwest::Client::new()),
+        &config,
+        "road-traffic-counts".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(&err, AppError::Dataset { id, .. } if id == "road-traffic-counts"));
+    assert!(err
+        .to_string()
+        .starts_with("Dataset road-traffic-counts: Serde JSON error"));
+    assert_eq!(err.category(), "parse");
+    assert!(matches!(err.root(), AppError::SerdeJson(_)));
+    let mut summary = RunSummary::default();
+    summary.record_fetch_error(&err);
+    assert_eq!(summary.parse_failures, 1);
+}
+
+#[tokio::test]
+async fn test_is_transient_classifies_reqwest_errors() {
+    // Test that timeouts, connect failures and 5xx/429 are retryable while decode, builder and 4xx errors are not.
+    let server = MockServer::start().await;
+    Mock::given(path("/slow"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(1)))
+        .mount(&server)
+        .await;
+    Mock::given(path("/garbage"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+        .mount(&server)
+        .await;
+    for status in [503, 429, 404] {
+        Mock::given(path(format!("/status/{status}")))
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&server)
+            .await;
+    }
+    let client = reqwest::Client::new();
+
+    let timeout = client
+        .get(format!("{}/slow", server.uri()))
+        .timeout(std::time::Duration::from_millis(50))
+        .send()
+        .await
+        .unwrap_err();
+    assert!(is_transient(&timeout));
+
+    // Nothing listens on port 1, so the connection is refused.
+    let connect = client.get("http://127.0.0.1:1/").send().await.unwrap_err();
+    assert!(is_transient(&connect));
+
+    let decode = client
+        .get(format!("{}/garbage", server.uri()))
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap_err();
+    assert!(!is_transient(&decode));
+
+    let builder = client.get("not a url").send().await.unwrap_err();
+    assert!(!is_transient(&builder));
+
+    for (status, expected) in [(503, true), (429, true), (404, false)] {
+        let error = client
+            .get(format!("{}/status/{status}", server.uri()))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        assert_eq!(is_transient(&error), expected, "status {status}");
+    }
+}
+
+#[tokio::test]
+async fn test_extra_show_params_sent_to_package_show() {
+    // Test that EXTRA_SHOW_PARAMS is appended to package_show requests and must be URL-encoded.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "abc"))
+        .and(query_param("include_tracking", "true"))
+        .and(query_param("x", "a b"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(package_show_json("abc")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    config.extra_show_params = Some("include_tracking=true&x=a%20b".to_string());
+    assert!(config.validate().is_ok());
+    let (meta, _) = fetch_dataset_metadata(
+        Arc::new(reqwest::Client::new()),
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(meta.id, "abc");
+    let requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(
+        requests[0].url.query(),
+        Some("id=abc&include_tracking=true&x=a%20b")
+    );
+
+    for invalid in ["a b=c", "key", "=value", "a=b&c=d#e", "a=%zz"] {
+        config.extra_show_params = Some(invalid.to_string());
+        assert!(
+            matches!(config.validate(), Err(AppError::Config(_))),
+            "{invalid}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_list_gzipped_response() {
+    // Test that a gzip-encoded package list is requested with Accept-Encoding and decoded transparently.
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+        .write_all(br#"{"success": true, "result": ["dataset1", "dataset2"]}"#)
+        .unwrap();
+    let gzipped = encoder.finish().unwrap();
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .and(header("Accept-Encoding", "gzip"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("Content-Encoding", "gzip")
+                .insert_header("Content-Type", "application/json")
+                .set_body_bytes(gzipped),
+        )
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = create_http_client(&config).unwrap();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["dataset1", "dataset2"]);
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_list_retries_unavailable() {
+    // Test that a 503 with Retry-After on the dataset list is retried and the list returned.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["dataset1", "dataset2"] })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = reqwest::Client::new();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
     assert_eq!(result, vec!["dataset1", "dataset2"]);
 }
 
@@ -109,6 +494,3696 @@ async fn test_fetch_dataset_list_error() {
     let mut config = Config::new();
     config.ckan_api_base_url = mock_server.uri();
     let client = reqwest::Client::new();
-    let result = fetch_dataset_list(&client, &config, false).await;
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0)).await;
     assert!(result.is_err());
 }
+
+#[test]
+fn test_extract_resource_sizes_and_modified() {
+    // Test that size (string or number) and last_modified are extracted in parallel with URLs.
+    let data = serde_json::json!({
+        "id": "abc",
+        "title": "Test",
+        "notes": "desc",
+        "license_title": "Open",
+        "organization": { "title": "Org" },
+        "metadata_created": "2020-01-01",
+        "metadata_modified": "2020-01-02",
+        "resources": [
+            { "format": "CSV", "url": "http://a", "size": 1024, "last_modified": "2021-01-01" },
+            { "format": "PDF", "url": "http://b", "size": "2048", "last_modified": null },
+            { "format": "HTML", "url": null }
+        ]
+    });
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (formats, urls) = extract_resource_formats_and_urls(&dataset, &[], true, false);
+    assert_eq!(formats, "CSV, PDF, HTML");
+    assert_eq!(urls.urls, vec!["http://a", "http://b"]);
+    assert_eq!(urls.sizes, vec!["1024", "2048"]);
+    assert_eq!(urls.last_modified, vec!["2021-01-01", ""]);
+}
+
+#[test]
+fn test_write_csv_url_columns() {
+    // Test that url_size_N/url_modified_N columns appear only when resource details are present.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("url_columns");
+    let url_only = ResourceUrls {
+        urls: vec!["http://a".to_string()],
+        sizes: vec![String::new()],
+        last_modified: vec![String::new()],
+        statuses: Vec::new(),
+        formats: Vec::new(),
+    };
+    write_csv(&config, &[(sample_metadata("abc"), url_only.clone())]).unwrap();
+    let header = read_csv_header(&config.csv_file);
+    assert_eq!(header.last().unwrap(), "download_url_1");
+    assert!(!header.iter().any(|h| h.starts_with("url_size_")));
+
+    let detailed = ResourceUrls {
+        urls: vec!["http://b".to_string()],
+        sizes: vec!["10".to_string()],
+        last_modified: vec!["2021-01-01".to_string()],
+        statuses: Vec::new(),
+        formats: Vec::new(),
+    };
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), url_only),
+            (sample_metadata("def"), detailed),
+        ],
+    )
+    .unwrap();
+    let header = read_csv_header(&config.csv_file);
+    assert!(header.ends_with(&[
+        "download_url_1".to_string(),
+        "url_size_1".to_string(),
+        "url_modified_1".to_string(),
+    ]));
+    let contents = std::fs::read_to_string(&config.csv_file).unwrap();
+    assert!(contents.contains("http://b,10,2021-01-01"));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_max_url_columns_collapses_extra_urls() {
+    // Test that MAX_URL_COLUMNS caps the download_url_N columns and the rest go into a JSON extra_urls column.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("max_url_columns");
+    config.max_url_columns = Some(2);
+    let urls = |n: usize| ResourceUrls {
+        urls: (1..=n).map(|i| format!("http://example.com/{i}")).collect(),
+        ..Default::default()
+    };
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), urls(4)),
+            (sample_metadata("def"), urls(1)),
+        ],
+    )
+    .unwrap();
+    let header = read_csv_header(&config.csv_file);
+    assert!(header.ends_with(&[
+        "download_url_1".to_string(),
+        "download_url_2".to_string(),
+        "extra_urls".to_string(),
+    ]));
+    let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&config.csv_file)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    let extra: Vec<String> = serde_json::from_str(&rows[0][rows[0].len() - 1]).unwrap();
+    assert_eq!(extra, vec!["http://example.com/3", "http://example.com/4"]);
+    assert_eq!(&rows[1][rows[1].len() - 1], "");
+
+    // Under the cap, the layout is unchanged and there is no extra_urls column.
+    write_csv(&config, &[(sample_metadata("abc"), urls(2))]).unwrap();
+    assert_eq!(
+        read_csv_header(&config.csv_file).last().unwrap(),
+        "download_url_2"
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+/// Builds a minimal DatasetMetadata for CSV writer tests.
+fn sample_metadata(id: &str) -> DatasetMetadata {
+    DatasetMetadata {
+        id: id.to_string(),
+        title: format!("Title {id}"),
+        description: "desc".to_string(),
+        license: "Open".to_string(),
+        organization: "Org".to_string(),
+        created: "2020-01-01".to_string(),
+        modified: "2020-01-02".to_string(),
+        format: "CSV".to_string(),
+        tags: String::new(),
+        groups: String::new(),
+        author: String::new(),
+        author_email: String::new(),
+        maintainer: String::new(),
+        maintainer_email: String::new(),
+        source: String::new(),
+        landing_page: String::new(),
+        resource_count: 1,
+        state: "active".to_string(),
+        private: false,
+        extras: DatasetExtras::default(),
+    }
+}
+
+/// Returns a unique temporary CSV path so tests can run in parallel.
+fn temp_csv_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("gov_data_test_{}_{name}.csv", std::process::id()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Reads the header row of a CSV file.
+fn read_csv_header(path: &str) -> Vec<String> {
+    let mut rdr = csv::Reader::from_path(path).unwrap();
+    rdr.headers().unwrap().iter().map(String::from).collect()
+}
+
+#[test]
+fn test_parse_last_run_timestamps() {
+    // Test that the previous run's CSV is parsed into an id -> modified map for incremental mode.
+    let csv = "id,title,description,license,organization,created,modified,format,download_url_1\n\
+               abc,A,d,Open,Org,2020-01-01,2020-01-02,CSV,http://a\n\
+               def,B,d,Open,Org,2020-01-01,2021-05-05,CSV,\n";
+    let timestamps = parse_last_run_timestamps(csv.as_bytes(), b',').unwrap();
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(timestamps["abc"], "2020-01-02");
+    assert_eq!(timestamps["def"], "2021-05-05");
+}
+
+#[test]
+fn test_s3_keys_with_prefix_and_partitioning() {
+    // Test the stable and date-partitioned S3 keys built from the prefix and output file name.
+    let mut config = Config::new();
+    config.csv_file = "/tmp/DataGovUK_Datasets.csv".to_string();
+    assert_eq!(config.s3_key(), "DataGovUK_Datasets.csv");
+
+    config.key_prefix = "archive/".to_string();
+    assert_eq!(config.s3_key(), "archive/DataGovUK_Datasets.csv");
+
+    config.partition_by_date = true;
+    assert_eq!(config.s3_key(), "archive/latest/DataGovUK_Datasets.csv");
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 31, 12, 5, 9).unwrap();
+    assert_eq!(
+        config.partitioned_s3_key(now),
+        "archive/year=2024/month=01/day=31/DataGovUK_Datasets_20240131T120509Z.csv"
+    );
+}
+
+#[test]
+fn test_multipart_part_ranges() {
+    // Test that a file is split into contiguous parts with a short final part.
+    let parts = multipart_part_ranges(20, 8);
+    assert_eq!(parts, vec![(1, 0, 8), (2, 8, 8), (3, 16, 4)]);
+    assert_eq!(multipart_part_ranges(16, 8).len(), 2);
+}
+
+#[test]
+fn test_build_metric_data() {
+    // Test that the run summary maps onto the expected CloudWatch metrics.
+    let summary = RunSummary {
+        datasets_fetched: 18,
+        datasets_failed: 2,
+        fetch_duration_ms: 1500,
+        upload_duration_ms: 250,
+        ..Default::default()
+    };
+    let data = build_metric_data(&summary);
+    let values: Vec<(&str, f64)> = data
+        .iter()
+        .map(|d| (d.metric_name().unwrap(), d.value().unwrap()))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("DatasetsFetched", 18.0),
+            ("DatasetsFailed", 2.0),
+            ("FetchDurationMs", 1500.0),
+            ("UploadDurationMs", 250.0),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_cli_options() {
+    // Test that --local, --test-mode and --serve-metrics are recognised and unknown arguments are ignored.
+    let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(parse_cli_options(args(&[])), CliOptions::default());
+    assert_eq!(
+        parse_cli_options(args(&["--local", "--verbose", "--test-mode"])),
+        CliOptions {
+            local: true,
+            test_mode: true,
+            serve_metrics: false
+        }
+    );
+    assert!(parse_cli_options(args(&["--local", "--serve-metrics"])).serve_metrics);
+}
+
+#[test]
+fn test_config_validation_malformed_scheme() {
+    // Test that a typo in the URL scheme is rejected with a configuration error.
+    let mut config = Config::new();
+    config.ckan_api_base_url = "htps://ckan.example.com/api/action".to_string();
+    let error_msg = config.validate().unwrap_err().to_string();
+    assert!(error_msg.contains("must use http or https"));
+
+    config.ckan_api_base_url = "not a url".to_string();
+    let error_msg = config.validate().unwrap_err().to_string();
+    assert!(error_msg.contains("is not a valid URL"));
+}
+
+#[test]
+fn test_config_trailing_slash_normalisation() {
+    // Test that a trailing slash on the base URL doesn't produce a double slash.
+    let mut config = Config::new();
+    config.ckan_api_base_url = "https://ckan.example.com/api/action/".to_string();
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        config.dataset_list_url(),
+        "https://ckan.example.com/api/action/package_list"
+    );
+    assert_eq!(
+        config.dataset_metadata_url(),
+        "https://ckan.example.com/api/action/package_show?id="
+    );
+}
+
+#[test]
+fn test_config_custom_ckan_action_paths() {
+    // Test that overridden action names and id parameter are used to build the CKAN URLs.
+    let mut config = Config::new();
+    config.ckan_api_base_url = "https://data.example.com/ckan/api/3/action/".to_string();
+    config.ckan_list_action = "/dataset_list/".to_string();
+    config.ckan_show_action = "dataset_show".to_string();
+    config.ckan_id_param = "name_or_id".to_string();
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        config.dataset_list_url(),
+        "https://data.example.com/ckan/api/3/action/dataset_list"
+    );
+    assert_eq!(
+        config.dataset_metadata_url(),
+        "https://data.example.com/ckan/api/3/action/dataset_show?name_or_id="
+    );
+
+    config.ckan_show_action = " / ".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_extract_resource_urls_deduplicated() {
+    // Test that a URL listed under several resources appears once, in first-seen order.
+    let data = serde_json::json!({
+        "id": "abc",
+        "title": "Test",
+        "notes": "desc",
+        "license_title": "Open",
+        "organization": { "title": "Org" },
+        "metadata_created": "2020-01-01",
+        "metadata_modified": "2020-01-02",
+        "resources": [
+            { "format": "CSV", "url": "http://a" },
+            { "format": "JSON", "url": "http://b" },
+            { "format": "CSV", "url": "http://a" }
+        ]
+    });
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (formats, urls) = extract_resource_formats_and_urls(&dataset, &[], true, false);
+    assert_eq!(formats, "CSV, JSON, CSV");
+    assert_eq!(urls.urls, vec!["http://a", "http://b"]);
+    assert_eq!(urls.sizes.len(), 2);
+}
+
+#[test]
+fn test_output_format_parsing_and_file_name() {
+    // Test that OUTPUT_FORMAT is validated and selects the output file extension.
+    let mut config = Config::new();
+    config.csv_file = "/tmp/DataGovUK_Datasets.csv".to_string();
+    assert_eq!(config.output_file(), "/tmp/DataGovUK_Datasets.csv");
+    config.output_format = "Parquet".to_string();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.output_file(), "/tmp/DataGovUK_Datasets.parquet");
+    assert_eq!(config.s3_key(), "DataGovUK_Datasets.parquet");
+    config.output_format = "xml".to_string();
+    let error_msg = config.validate().unwrap_err().to_string();
+    assert!(error_msg.contains("Unknown output format 'xml'"));
+}
+
+#[test]
+fn test_write_parquet_round_trip() {
+    // Test that the Parquet file has one row per dataset and a list column of URLs.
+    use arrow_array::{Array, ListArray, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("parquet");
+    config.output_format = "parquet".to_string();
+    let urls = ResourceUrls {
+        urls: vec!["http://a".to_string(), "http://b".to_string()],
+        ..Default::default()
+    };
+    write_parquet(
+        &config,
+        &[
+            (sample_metadata("abc"), urls),
+            (sample_metadata("def"), ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+
+    let file = std::fs::File::open(config.output_file()).unwrap();
+    let mut reader = ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .build()
+        .unwrap();
+    let batch = reader.next().unwrap().unwrap();
+    assert_eq!(batch.num_rows(), 2);
+    let ids = batch
+        .column_by_name("id")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(ids.value(1), "def");
+    let download_urls = batch
+        .column_by_name("download_urls")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<ListArray>()
+        .unwrap();
+    assert_eq!(download_urls.value(0).len(), 2);
+    assert_eq!(download_urls.value(1).len(), 0);
+    std::fs::remove_file(config.output_file()).unwrap();
+}
+
+#[tokio::test]
+async fn test_ckan_api_key_sent_as_authorization_header() {
+    // Test that a configured CKAN API key is sent on outgoing requests.
+    let mock_server = MockServer::start().await;
+    let response = serde_json::json!({ "result": ["dataset1"] });
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .and(header("Authorization", "secret-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(response))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    config.ckan_api_key = Some("secret-token".to_string());
+    let client = create_http_client(&config).unwrap();
+    let result = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(result, vec!["dataset1"]);
+}
+
+#[tokio::test]
+async fn test_url_check_does_not_send_ckan_api_key() {
+    // Test that URL checks against a third-party host carry the User-Agent but not the CKAN
+    // API key.
+    let host = MockServer::start().await;
+    Mock::given(method("HEAD"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&host)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_key = Some("secret-token".to_string());
+    let urls = ResourceUrls {
+        urls: vec![format!("{}/data.csv", host.uri())],
+        ..Default::default()
+    };
+    let mut rows = vec![(sample_metadata("abc"), urls)];
+    let client = create_url_check_client(&config).unwrap();
+    assert_eq!(check_download_urls(&client, &config, &mut rows).await, 0);
+    let requests = host.received_requests().await.unwrap();
+    assert!(requests[0].headers.get("authorization").is_none());
+    assert_eq!(
+        requests[0].headers.get("user-agent").unwrap(),
+        config.user_agent.as_str()
+    );
+}
+
+#[tokio::test]
+async fn test_user_agent_sent_on_ckan_requests() {
+    // Test that the default and a configured User-Agent are sent on outgoing CKAN requests.
+    let mock_server = MockServer::start().await;
+    let response = serde_json::json!({ "result": ["dataset1"] });
+    for user_agent in [
+        DEFAULT_USER_AGENT,
+        "gov-data-tests/2.0 (ops@example.gov.uk)",
+    ] {
+        Mock::given(method("GET"))
+            .and(path("/package_list"))
+            .and(header("User-Agent", user_agent))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response.clone()))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+    }
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    assert!(config.user_agent.starts_with("rust-gov-data/"));
+    let client = create_http_client(&config).unwrap();
+    fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    config.user_agent = "gov-data-tests/2.0 (ops@example.gov.uk)".to_string();
+    let client = create_http_client(&config).unwrap();
+    fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_retry_after_delay() {
+    // Test Retry-After parsing: seconds, capping, HTTP dates in the past, and the default.
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use std::time::Duration;
+    let max = Duration::from_secs(30);
+    let with = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    };
+    assert_eq!(retry_after_delay(&with("5"), max), Duration::from_secs(5));
+    assert_eq!(retry_after_delay(&with("120"), max), max);
+    assert_eq!(
+        retry_after_delay(&with("Wed, 21 Oct 2015 07:28:00 GMT"), max),
+        Duration::from_secs(1)
+    );
+    assert_eq!(
+        retry_after_delay(&HeaderMap::new(), max),
+        Duration::from_secs(1)
+    );
+}
+
+#[tokio::test]
+async fn test_total_retry_budget_stops_retries() {
+    // Test that once TOTAL_RETRY_BUDGET is used up, rate-limited datasets fail without another request.
+    let budget = RetryBudget::new(Some(2));
+    assert!(budget.try_acquire());
+    assert!(budget.try_acquire());
+    assert!(!budget.try_acquire());
+    assert!(RetryBudget::new(None).try_acquire());
+
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def", "ghi"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("retry_budget");
+    config.dry_run = true;
+    config.concurrency_limit = 1;
+    config.total_retry_budget = Some(1);
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_failed, 3);
+    // Still rate limited once the budget is spent, so counted as HTTP failures.
+    assert_eq!(summary.http_failures, 3);
+    let shows = ckan
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path() == "/package_show")
+        .count();
+    // One request per dataset plus the single budgeted retry.
+    assert_eq!(shows, 4);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_metadata_retries_once_after_429() {
+    // Test that a 429 is retried once after Retry-After rather than dropping the dataset.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(package_show_json("abc")))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = Arc::new(reqwest::Client::new());
+    let (metadata, _) = fetch_dataset_metadata(
+        client,
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(metadata.id, "abc");
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_fetch_dataset_metadata_retries_once_after_503() {
+    // Test that a 5xx from package_show takes the transient retry, and fails as an HTTP error
+    // carrying the status once the retry budget is spent.
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(package_show_json("abc")))
+        .mount(&mock_server)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = mock_server.uri();
+    let client = Arc::new(reqwest::Client::new());
+    let err = fetch_dataset_metadata(
+        client.clone(),
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(Some(0)),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err.root(), AppError::Http(e) if e.status() == Some(reqwest::StatusCode::SERVICE_UNAVAILABLE))
+    );
+    let (metadata, _) = fetch_dataset_metadata(
+        client,
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(metadata.id, "abc");
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+}
+
+/// Builds a minimal CKAN package_show response body for the given dataset id.
+fn package_show_json(id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "success": true,
+        "result": {
+            "id": id,
+            "title": format!("Title {id}"),
+            "notes": "<p>desc</p>",
+            "license_title": "Open",
+            "organization": { "title": "Org" },
+            "metadata_created": "2020-01-01",
+            "metadata_modified": "2020-01-02",
+            "resources": [{ "format": "CSV", "url": format!("http://example.com/{id}.csv") }]
+        }
+    })
+}
+
+#[test]
+fn test_clean_html_preserves_block_breaks() {
+    // Test that paragraph and line-break boundaries survive HTML cleaning as newlines.
+    assert_eq!(
+        clean_html("<p>Para one.</p><p>Para two.</p>"),
+        "Para one.\nPara two."
+    );
+    assert_eq!(
+        clean_html("Line one<br/>Line two<BR>Line <b>three</b>"),
+        "Line one\nLine two\nLine three"
+    );
+    assert_eq!(
+        clean_html("<ul><li>First</li><li>Second</li></ul>"),
+        "First\nSecond"
+    );
+}
+
+#[test]
+fn test_description_modes() {
+    // Test that raw keeps HTML, strip removes tags, and plain also decodes entities and collapses whitespace.
+    let html = "<p>Road  traffic &amp; <b>accidents</b></p>\n<p>Source: DfT&#39;s &quot;STATS19&quot;&nbsp;data &copy;</p>";
+    assert_eq!(clean_description(html, DescriptionMode::Raw), html);
+    assert_eq!(
+        clean_description(html, DescriptionMode::Strip),
+        "Road  traffic &amp; accidents\n\nSource: DfT&#39;s &quot;STATS19&quot;&nbsp;data &copy;"
+    );
+    assert_eq!(
+        clean_description(html, DescriptionMode::Plain),
+        "Road traffic & accidents Source: DfT's \"STATS19\" data &copy;"
+    );
+    assert_eq!(
+        clean_description("&lt;b&gt; &#x41;", DescriptionMode::Plain),
+        "<b> A"
+    );
+
+    let mut config = Config::new();
+    assert_eq!(
+        config.description_mode.parse::<DescriptionMode>().unwrap(),
+        DescriptionMode::Strip
+    );
+    config.description_mode = "markdown".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[tokio::test]
+async fn test_max_description_chars_truncates_at_char_boundary() {
+    // Test that long multi-byte descriptions are cut at a character boundary with an ellipsis.
+    assert_eq!(truncate_chars("Über café ☕ data", 6), "Über c…");
+    assert_eq!(truncate_chars("日本語の説明", 3), "日本語…");
+    assert_eq!(truncate_chars("short", 5), "short");
+    assert_eq!(truncate_chars("", 0), "");
+
+    let ckan = MockServer::start().await;
+    let mut body = package_show_json("abc");
+    body["result"]["notes"] = serde_json::json!(format!("<p>{}</p>", "é☕".repeat(5000)));
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.max_description_chars = Some(7);
+    let client = Arc::new(reqwest::Client::new());
+    let (meta, _) = fetch_dataset_metadata(
+        client,
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(meta.description, "é☕é☕é☕é…");
+}
+
+#[tokio::test]
+async fn test_normalize_whitespace_cleans_title_organization_and_license() {
+    // Test that stray whitespace in short fields is trimmed and collapsed unless disabled.
+    assert_eq!(normalize_whitespace("  Foo\n\tBar  "), "Foo Bar");
+
+    let ckan = MockServer::start().await;
+    let mut body = package_show_json("abc");
+    body["result"]["title"] = serde_json::json!("  Foo\n\tBar  ");
+    body["result"]["organization"]["title"] = serde_json::json!("Dept\r\nfor  Data ");
+    body["result"]["license_title"] = serde_json::json!("\tOpen Government Licence\n");
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    let client = Arc::new(reqwest::Client::new());
+    let fetch = |config: Config| {
+        let client = Arc::clone(&client);
+        async move {
+            fetch_dataset_metadata(
+                client,
+                &config,
+                "abc".to_string(),
+                &AtomicU64::new(0),
+                &RetryBudget::new(None),
+            )
+            .await
+            .unwrap()
+            .unwrap()
+            .0
+        }
+    };
+    let meta = fetch(config.clone()).await;
+    assert_eq!(meta.title, "Foo Bar");
+    assert_eq!(meta.organization, "Dept for Data");
+    assert_eq!(meta.license, "Open Government Licence");
+
+    config.normalize_whitespace = false;
+    let meta = fetch(config).await;
+    assert_eq!(meta.title, "  Foo\n\tBar  ");
+}
+
+#[tokio::test]
+async fn test_dump_raw_json_captures_result_payload() {
+    // Test that DUMP_RAW_JSON keeps the unparsed result, including unknown fields, next to the parsed metadata.
+    let ckan = MockServer::start().await;
+    let mut body = package_show_json("abc");
+    body["result"]["x_custom_field"] = serde_json::json!({ "nested": [1, 2] });
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&body))
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("raw_json");
+    let client = Arc::new(reqwest::Client::new());
+    let fetch = |config: Config| {
+        let client = Arc::clone(&client);
+        async move {
+            fetch_dataset_metadata(
+                client,
+                &config,
+                "abc".to_string(),
+                &AtomicU64::new(0),
+                &RetryBudget::new(None),
+            )
+            .await
+            .unwrap()
+            .unwrap()
+        }
+    };
+    let (meta, _) = fetch(config.clone()).await;
+    assert!(meta.extras.raw_json.is_none());
+
+    config.dump_raw_json = true;
+    let (meta, urls) = fetch(config.clone()).await;
+    assert_eq!(meta.title, "Title abc");
+    assert_eq!(meta.extras.raw_json.as_ref(), Some(&body["result"]));
+
+    assert_eq!(write_raw_json(&config, &[(meta, urls)]).unwrap(), 1);
+    let contents = std::fs::read_to_string(config.raw_json_file()).unwrap();
+    let line: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+    assert_eq!(line["x_custom_field"]["nested"][1], 2);
+    std::fs::remove_file(config.raw_json_file()).unwrap();
+}
+
+#[test]
+fn test_clean_html_keeps_angle_brackets_that_are_not_tags() {
+    // Test that comparisons and unclosed brackets survive while real tags, comments and declarations are removed.
+    assert_eq!(clean_html("3 < 5 and x > 1"), "3 < 5 and x > 1");
+    assert_eq!(clean_html("a < b without close"), "a < b without close");
+    assert_eq!(clean_html("x<5 or y>=10"), "x<5 or y>=10");
+    assert_eq!(clean_html("<=3 years, >5 years"), "<=3 years, >5 years");
+    assert_eq!(
+        clean_html("<p>Cases where 3 < 5 and x > 1</p>"),
+        "Cases where 3 < 5 and x > 1"
+    );
+    assert_eq!(clean_html("<p>Where a<b</p>"), "Where a<b");
+    assert_eq!(
+        clean_html("<!DOCTYPE html><!-- note\n--><a href=\"http://x\" class='y'>Link</a><img src=\"i.png\"/>"),
+        "Link"
+    );
+}
+
+#[tokio::test]
+async fn test_dry_run_skips_s3_upload() {
+    // Test that dry-run mode writes the output locally without any S3 requests.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let s3 = MockServer::start().await;
+    Mock::given(wiremock::matchers::any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("dry_run");
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 2);
+    assert!(summary.output_key.is_empty());
+    assert!(std::path::Path::new(&config.csv_file).exists());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+/// Mounts CKAN package_list and package_show mocks serving the given dataset ids.
+async fn mount_ckan_datasets(server: &MockServer, ids: &[&str]) {
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": ids })),
+        )
+        .mount(server)
+        .await;
+    for id in ids {
+        Mock::given(method("GET"))
+            .and(path("/package_show"))
+            .and(query_param("id", *id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(package_show_json(id)))
+            .mount(server)
+            .await;
+    }
+}
+
+/// Builds an S3 client that sends requests to a mock server with static test credentials.
+fn mock_s3_client(uri: &str) -> aws_sdk_s3::Client {
+    use aws_sdk_s3::config::retry::RetryConfig;
+    use aws_sdk_s3::config::{Credentials, Region, RequestChecksumCalculation};
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version_latest()
+        .retry_config(RetryConfig::disabled())
+        .region(Region::new("eu-west-2"))
+        .endpoint_url(uri)
+        .force_path_style(true)
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+        .build();
+    aws_sdk_s3::Client::from_conf(config)
+}
+
+/// Builds a DynamoDB client that sends requests to the given mock server.
+fn mock_dynamodb_client(uri: &str) -> aws_sdk_dynamodb::Client {
+    use aws_sdk_dynamodb::config::retry::RetryConfig;
+    use aws_sdk_dynamodb::config::{Credentials, Region};
+    let config = aws_sdk_dynamodb::Config::builder()
+        .behavior_version_latest()
+        .retry_config(RetryConfig::disabled())
+        .region(Region::new("eu-west-2"))
+        .endpoint_url(uri)
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .build();
+    aws_sdk_dynamodb::Client::from_conf(config)
+}
+
+#[test]
+fn test_dataset_tags_joined() {
+    // Test that tag names are joined into a comma-separated column, and missing/null tags are empty.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["tags"] = serde_json::json!([{ "name": "health" }, { "name": "transport" }]);
+    let dataset: CkanDataset = serde_json::from_value(data.clone()).unwrap();
+    let (metadata, _) = build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip);
+    assert_eq!(metadata.tags, "health, transport");
+
+    data["tags"] = serde_json::Value::Null;
+    let dataset: CkanDataset = serde_json::from_value(data.clone()).unwrap();
+    assert_eq!(
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip)
+            .0
+            .tags,
+        ""
+    );
+
+    data.as_object_mut().unwrap().remove("tags");
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    assert_eq!(
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip)
+            .0
+            .tags,
+        ""
+    );
+}
+
+#[test]
+fn test_dataset_groups_joined() {
+    // Test that group titles are joined into a comma-separated column, and missing/empty groups are empty.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["groups"] = serde_json::json!([
+        { "title": "Health", "name": "health" },
+        { "title": "Transport", "name": "transport" }
+    ]);
+    let dataset: CkanDataset = serde_json::from_value(data.clone()).unwrap();
+    assert_eq!(dataset.groups.len(), 2);
+    let (metadata, _) = build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip);
+    assert_eq!(metadata.groups, "Health, Transport");
+
+    data["groups"] = serde_json::json!([]);
+    let dataset: CkanDataset = serde_json::from_value(data.clone()).unwrap();
+    assert_eq!(
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip)
+            .0
+            .groups,
+        ""
+    );
+
+    data.as_object_mut().unwrap().remove("groups");
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    assert_eq!(
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip)
+            .0
+            .groups,
+        ""
+    );
+}
+
+#[tokio::test]
+async fn test_landing_page_built_from_dataset_name() {
+    // Test that the landing page is {portal}/dataset/{name}, derived from the API base URL or CKAN_PORTAL_BASE_URL.
+    let mut config = Config::new();
+    config.ckan_api_base_url = "https://ckan.publishing.service.gov.uk/api/action/".to_string();
+    assert_eq!(
+        config.landing_page_url("road-accidents"),
+        "https://ckan.publishing.service.gov.uk/dataset/road-accidents"
+    );
+    config.ckan_api_base_url = "https://data.example.org/api/3/action".to_string();
+    assert_eq!(config.portal_base_url(), "https://data.example.org");
+    config.ckan_portal_base_url = Some("https://www.data.gov.uk/".to_string());
+    assert_eq!(
+        config.landing_page_url("road-accidents"),
+        "https://www.data.gov.uk/dataset/road-accidents"
+    );
+
+    let ckan = MockServer::start().await;
+    let mut body = package_show_json("abc");
+    body["result"]["name"] = serde_json::json!("road-safety-data");
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    let (meta, _) = fetch_dataset_metadata(
+        Arc::new(reqwest::Client::new()),
+        &config,
+        "abc".to_string(),
+        &AtomicU64::new(0),
+        &RetryBudget::new(None),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        meta.landing_page,
+        format!("{}/dataset/road-safety-data", ckan.uri())
+    );
+}
+
+/// Notifier that records published messages instead of sending them.
+#[derive(Default)]
+struct RecordingNotifier {
+    messages: std::sync::Mutex<Vec<(String, String)>>,
+}
+
+impl Notifier for RecordingNotifier {
+    async fn publish(&self, subject: &str, message: &str) -> Result<(), AppError> {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((subject.to_string(), message.to_string()));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_notify_run_result_success_and_failure() {
+    // Test that the success notification carries the run summary and failures carry the error.
+    let notifier = RecordingNotifier::default();
+    let summary = RunSummary {
+        datasets_fetched: 5,
+        total_duration_ms: 1234,
+        output_key: "DataGovUK_Datasets.csv".to_string(),
+        ..Default::default()
+    };
+    notify_run_result(&notifier, &Ok(summary)).await;
+    notify_run_result(&notifier, &Err(AppError::Other("boom".to_string()))).await;
+    let messages = notifier.messages.lock().unwrap();
+    assert_eq!(messages[0].0, "gov-data run succeeded");
+    let body: serde_json::Value = serde_json::from_str(&messages[0].1).unwrap();
+    assert_eq!(body["datasets_fetched"], 5);
+    assert_eq!(body["total_duration_ms"], 1234);
+    assert_eq!(body["output_key"], "DataGovUK_Datasets.csv");
+    assert_eq!(messages[1].0, "gov-data run failed");
+    assert!(messages[1].1.contains("boom"));
+}
+
+#[tokio::test]
+async fn test_streaming_csv_fixed_url_columns() {
+    // Test that streaming mode writes every row with a fixed number of URL columns, and
+    // rejects the settings it can't apply.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def", "ghi"]).await;
+    let s3 = MockServer::start().await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("streaming");
+    config.dry_run = true;
+    config.streaming_csv = true;
+    config.streaming_url_columns = 2;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 3);
+    let header = read_csv_header(&config.csv_file);
+    assert!(header.ends_with(&[
+        "download_url_2".to_string(),
+        "url_size_2".to_string(),
+        "url_modified_2".to_string(),
+    ]));
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let ids: std::collections::HashSet<String> =
+        rdr.records().map(|r| r.unwrap()[0].to_string()).collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids.contains("ghi"));
+    std::fs::remove_file(&config.csv_file).unwrap();
+
+    // Settings the streaming writer can't honour fail validation instead of being dropped.
+    assert!(config.validate().is_ok());
+    for invalid in [
+        |c: &mut Config| c.validate_urls = true,
+        |c: &mut Config| c.normalize_tags = true,
+        |c: &mut Config| c.write_resources = true,
+        |c: &mut Config| c.split_by_org = true,
+        |c: &mut Config| c.incremental = true,
+        |c: &mut Config| c.dump_raw_json = true,
+        |c: &mut Config| c.url_column_mode = "json".to_string(),
+        |c: &mut Config| c.max_url_columns = Some(3),
+    ] {
+        let mut invalid_config = config.clone();
+        invalid(&mut invalid_config);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(AppError::Config(msg)) if msg.contains("STREAMING_CSV")
+        ));
+    }
+}
+
+#[test]
+fn test_write_csv_tab_delimiter() {
+    // Test that a tab delimiter produces tab-separated output with no comma separators.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("tab_delimiter");
+    config.csv_delimiter = "\\t".to_string();
+    assert!(config.validate().is_ok());
+    let urls = ResourceUrls {
+        urls: vec!["http://a".to_string()],
+        ..Default::default()
+    };
+    write_csv(&config, &[(sample_metadata("abc"), urls)]).unwrap();
+    let contents = std::fs::read_to_string(&config.csv_file).unwrap();
+    let header = contents.lines().next().unwrap();
+    assert_eq!(
+        header.split('\t').count(),
+        DatasetMetadata::COLUMNS.len() + 1
+    );
+    assert!(!contents.contains(','));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_config_validation_multi_byte_delimiter() {
+    // Test that a delimiter longer than one byte is rejected.
+    let mut config = Config::new();
+    config.csv_delimiter = "||".to_string();
+    let error_msg = config.validate().unwrap_err().to_string();
+    assert!(error_msg.contains("CSV delimiter must be exactly one byte"));
+}
+
+#[test]
+fn test_author_and_maintainer_columns() {
+    // Test that a record with an author email but null maintainer fields populates the right columns.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["author"] = serde_json::Value::Null;
+    data["author_email"] = serde_json::json!("author@example.gov.uk");
+    data["maintainer"] = serde_json::Value::Null;
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (metadata, urls) =
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip);
+    assert_eq!(metadata.author, "");
+    assert_eq!(metadata.author_email, "author@example.gov.uk");
+    assert_eq!(metadata.maintainer, "");
+    assert_eq!(metadata.maintainer_email, "");
+
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("contacts");
+    write_csv(&config, &[(metadata, urls)]).unwrap();
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let headers = rdr.headers().unwrap().clone();
+    let record = rdr.records().next().unwrap().unwrap();
+    let column = |name: &str| &record[headers.iter().position(|h| h == name).unwrap()];
+    assert_eq!(column("author_email"), "author@example.gov.uk");
+    assert_eq!(column("maintainer_email"), "");
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_retries_transient_s3_errors() {
+    // Test that a PUT failing twice with 503 is retried and ultimately succeeds.
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&s3)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("s3_retry");
+    config.s3_retry_base_delay_ms = 1;
+    std::fs::write(&config.csv_file, "id\nabc\n").unwrap();
+    let uploaded = upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+        .await
+        .unwrap();
+    assert_eq!(uploaded.key, config.s3_key());
+    assert_eq!(s3.received_requests().await.unwrap().len(), 3);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_backs_off_on_slowdown() {
+    // Test that SlowDown responses are retried with their own capped exponential backoff,
+    // without using up the generic S3 retries.
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(503).set_body_string(
+            "<Error><Code>SlowDown</Code><Message>Please reduce your request rate.</Message></Error>",
+        ))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&s3)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("s3_slowdown");
+    config.s3_max_retries = 0;
+    config.s3_slowdown_base_delay_ms = 100;
+    config.s3_slowdown_max_delay_ms = 150;
+    assert_eq!(slowdown_delay(&config, 1).as_millis(), 100);
+    assert_eq!(slowdown_delay(&config, 2).as_millis(), 150);
+    assert_eq!(slowdown_delay(&config, 40).as_millis(), 150);
+    std::fs::write(&config.csv_file, "id\nabc\n").unwrap();
+    let started = std::time::Instant::now();
+    let uploaded = upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+        .await
+        .unwrap();
+    assert!(started.elapsed() >= std::time::Duration::from_millis(250));
+    assert_eq!(uploaded.key, config.s3_key());
+    assert_eq!(s3.received_requests().await.unwrap().len(), 3);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_multipart_upload_reassembles_parts() {
+    // Test that a file split into several parts is uploaded part by part and completes in order.
+    let s3 = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(query_param("uploads", ""))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>k</Key>\
+             <UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+        ))
+        .mount(&s3)
+        .await;
+    Mock::given(method("PUT"))
+        .and(query_param("uploadId", "upload-1"))
+        .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"part\""))
+        .mount(&s3)
+        .await;
+    Mock::given(method("POST"))
+        .and(query_param("uploadId", "upload-1"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "<CompleteMultipartUploadResult><Key>k</Key></CompleteMultipartUploadResult>",
+        ))
+        .expect(1)
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("multipart");
+    config.multipart_threshold_bytes = 1024;
+    config.s3_upload_concurrency = 2;
+    // Three 8MB parts, the last one short.
+    let contents: Vec<u8> = (0..20 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&config.csv_file, &contents).unwrap();
+    upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+        .await
+        .unwrap();
+
+    let requests = s3.received_requests().await.unwrap();
+    let mut parts: Vec<(u32, Vec<u8>)> = requests
+        .iter()
+        .filter(|r| r.method == wiremock::http::Method::PUT)
+        .map(|r| {
+            let part_number = r
+                .url
+                .query_pairs()
+                .find(|(k, _)| k == "partNumber")
+                .unwrap()
+                .1
+                .parse()
+                .unwrap();
+            (part_number, r.body.clone())
+        })
+        .collect();
+    parts.sort_by_key(|(number, _)| *number);
+    assert_eq!(
+        parts.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    let reassembled: Vec<u8> = parts.into_iter().flat_map(|(_, body)| body).collect();
+    assert_eq!(reassembled, contents);
+    let complete = requests
+        .iter()
+        .find(|r| {
+            r.method == wiremock::http::Method::POST
+                && r.url.query().unwrap_or("").contains("uploadId")
+        })
+        .unwrap();
+    let body = String::from_utf8_lossy(&complete.body);
+    let position = |part: &str| {
+        body.find(&format!("<PartNumber>{part}</PartNumber>"))
+            .unwrap()
+    };
+    assert!(position("1") < position("2") && position("2") < position("3"));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_skip_unchanged_upload() {
+    // Test that the PUT is skipped when the stored digest matches and made when it differs or is missing.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("skip_unchanged");
+    config.skip_unchanged_upload = true;
+    std::fs::write(&config.csv_file, "id\nabc\n").unwrap();
+    let digest = file_sha256(&config.csv_file).unwrap();
+    for (head, expect_skip) in [
+        (
+            ResponseTemplate::new(200).insert_header("x-amz-meta-sha256", digest.as_str()),
+            true,
+        ),
+        (
+            ResponseTemplate::new(200).insert_header("x-amz-meta-sha256", "0000"),
+            false,
+        ),
+        (ResponseTemplate::new(404), false),
+    ] {
+        let s3 = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .respond_with(head)
+            .expect(1)
+            .mount(&s3)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(if expect_skip { 0 } else { 1 })
+            .mount(&s3)
+            .await;
+        let uploaded = upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+            .await
+            .unwrap();
+        assert_eq!(uploaded.skipped, expect_skip);
+        assert_eq!(uploaded.key, config.s3_key());
+        assert_eq!(uploaded.sha256, digest);
+    }
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_does_not_retry_forbidden() {
+    // Test that a 403 from S3 fails immediately without retrying.
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(403))
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("s3_forbidden");
+    config.s3_retry_base_delay_ms = 1;
+    std::fs::write(&config.csv_file, "id\nabc\n").unwrap();
+    let result = upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file).await;
+    assert!(result.is_err());
+    assert_eq!(s3.received_requests().await.unwrap().len(), 1);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_sort_dataset_metadata() {
+    // Test that shuffled rows come out sorted by id, and by modified descending.
+    let mut rows: Vec<(DatasetMetadata, ResourceUrls)> = ["ghi", "abc", "def"]
+        .iter()
+        .map(|id| (sample_metadata(id), ResourceUrls::default()))
+        .collect();
+    rows[0].0.modified = "2021-01-01".to_string();
+    rows[1].0.modified = "2019-01-01".to_string();
+    rows[2].0.modified = "2023-01-01".to_string();
+    let ids = |rows: &[(DatasetMetadata, ResourceUrls)]| {
+        rows.iter().map(|(m, _)| m.id.clone()).collect::<Vec<_>>()
+    };
+    sort_dataset_metadata(&mut rows, SortBy::Id);
+    assert_eq!(ids(&rows), vec!["abc", "def", "ghi"]);
+    sort_dataset_metadata(&mut rows, SortBy::Modified);
+    assert_eq!(ids(&rows), vec!["def", "ghi", "abc"]);
+    assert!("random".parse::<SortBy>().is_err());
+}
+
+#[test]
+fn test_config_from_toml_file() {
+    // Test that values in a TOML config file are picked up, with defaults for missing keys.
+    let path = temp_csv_path("config_file").replace(".csv", ".toml");
+    std::fs::write(
+        &path,
+        "concurrency_limit = 25\noutput_format = \"parquet\"\n",
+    )
+    .unwrap();
+    let config = Config::from_toml_file(&path);
+    assert_eq!(config.concurrency_limit, 25);
+    assert_eq!(config.output_format, "parquet");
+    assert_eq!(config.http_timeout_secs, 15);
+    assert!(config.validate().is_ok());
+    std::fs::remove_file(&path).unwrap();
+
+    let missing = Config::from_toml_file("does_not_exist.toml");
+    assert!(matches!(missing.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_config_env_overrides_toml_file() {
+    // Test that an environment variable takes precedence over the config file value. The
+    // environment is injected rather than set, since other tests read it in parallel.
+    let path = temp_csv_path("config_override").replace(".csv", ".toml");
+    std::fs::write(&path, "metrics_namespace = \"FromFile\"\n").unwrap();
+    let from_file = ConfigSource::from_file(&path).with_env(std::collections::HashMap::new());
+    assert_eq!(
+        Config::from_source(&from_file).metrics_namespace,
+        "FromFile"
+    );
+    let env =
+        std::collections::HashMap::from([("METRICS_NAMESPACE".to_string(), "FromEnv".to_string())]);
+    let config = Config::from_source(&ConfigSource::from_file(&path).with_env(env));
+    assert_eq!(config.metrics_namespace, "FromEnv");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_modified_since_filter() {
+    // Test that MODIFIED_SINCE keeps only datasets modified at or after the threshold, reading
+    // CKAN's offset-less timestamps as UTC and keeping unparseable ones.
+    let mut config = Config::new();
+    config.modified_since = Some("2024-06-01T00:00:00+01:00".to_string());
+    assert!(config.validate().is_ok());
+    let threshold = config.modified_since_threshold();
+    let dated = |id: &str, modified: &str| DatasetMetadata {
+        modified: modified.to_string(),
+        ..sample_metadata(id)
+    };
+    let mut datasets = vec![
+        dated("old", "2024-05-31T22:59:59.999999"),
+        dated("boundary", "2024-05-31T23:00:00"),
+        dated("new", "2024-07-15T09:30:00.123456"),
+        dated("garbled", "last Tuesday"),
+    ];
+    datasets.retain(|meta| is_modified_since(meta, threshold));
+    let kept: Vec<&str> = datasets.iter().map(|meta| meta.id.as_str()).collect();
+    assert_eq!(kept, vec!["boundary", "new", "garbled"]);
+    assert!(is_modified_since(&dated("old", "2020-01-01"), None));
+
+    config.modified_since = Some("2024-06-01".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_config_from_json() {
+    // Test that a JSON config is read with typed values and defaults for missing keys.
+    let config = Config::from_json(
+        r#"{"concurrency_limit": 25, "columns": ["id", "modified"], "incremental": true, "batch_size": 50}"#,
+    )
+    .unwrap();
+    assert_eq!(config.concurrency_limit, 25);
+    assert_eq!(config.columns, vec!["id", "modified"]);
+    assert!(config.incremental);
+    assert_eq!(config.batch_size, Some(50));
+    assert_eq!(config.http_timeout_secs, 15);
+    assert_eq!(config.output_format, "csv");
+    assert!(config.validate().is_ok());
+    assert!(matches!(
+        Config::from_json(r#"{"batch_size": 0}"#)
+            .unwrap()
+            .validate(),
+        Err(AppError::Config(_))
+    ));
+}
+
+#[test]
+fn test_config_from_json_rejects_unknown_key() {
+    // Test that a misspelt key in a JSON config is rejected with the key and its position.
+    let error = Config::from_json("{\n  \"concurrency_limit\": 5,\n  \"concurency_limit\": 20\n}")
+        .unwrap_err();
+    let AppError::Config(message) = error else {
+        panic!("expected a config error, got {error:?}");
+    };
+    assert_eq!(
+        message,
+        "Invalid JSON config: unknown field `concurency_limit` at line 3 column 20"
+    );
+
+    let path = temp_csv_path("config_typo").replace(".csv", ".json");
+    std::fs::write(&path, r#"{"bucket": "typo"}"#).unwrap();
+    let config = Config::from_json_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(config.validate(), Err(AppError::Config(m)) if m.contains("`bucket`")));
+}
+
+#[test]
+fn test_run_manifest_serialization() {
+    // Test that the run manifest serializes the expected fields and lives next to the output key.
+    let summary = RunSummary {
+        datasets_listed: 5,
+        datasets_fetched: 4,
+        datasets_failed: 1,
+        rows_written: 4,
+        output_key: "exports/DataGovUK_Datasets.csv".to_string(),
+        ..RunSummary::default()
+    };
+    let run_timestamp = chrono::Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap();
+    let manifest = RunManifest::new(&summary, 2048, run_timestamp, "https://ckan.example/api");
+    let json = serde_json::to_value(&manifest).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "row_count": 4,
+            "output_key": "exports/DataGovUK_Datasets.csv",
+            "byte_size": 2048,
+            "run_timestamp": "2024-01-31T12:00:00Z",
+            "datasets_failed": 1,
+            "ckan_api_base_url": "https://ckan.example/api",
+        })
+    );
+    assert_eq!(
+        manifest_key(&manifest.output_key),
+        "exports/DataGovUK_Datasets.manifest.json"
+    );
+}
+
+#[test]
+fn test_dataset_diff_against_previous_run() {
+    // Test that the previous/current ID comparison reports sorted added and removed IDs, and
+    // that DIFF_PREVIOUS needs a CSV baseline with id and modified columns.
+    let previous: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+    let current: Vec<String> = ["e", "b", "a", "f"].iter().map(|s| s.to_string()).collect();
+    let diff = DatasetDiff::between(&previous, &current);
+    assert_eq!(diff.added, vec!["e", "f"]);
+    assert_eq!(diff.removed, vec!["c", "d"]);
+
+    let mut summary = RunSummary::default();
+    summary.record_diff(diff.clone(), false);
+    assert_eq!((summary.added_ids, summary.removed_ids), (Some(2), Some(2)));
+    assert!(summary.added_id_list.is_empty());
+    summary.record_diff(diff, true);
+    assert_eq!(summary.removed_id_list, vec!["c", "d"]);
+    let run_timestamp = chrono::Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap();
+    let manifest = serde_json::to_value(RunManifest::new(&summary, 0, run_timestamp, "")).unwrap();
+    assert_eq!(manifest["added_ids"], 2);
+    assert_eq!(manifest["removed_ids"], 2);
+
+    // The previous upload is read back as a CSV, so other formats and layouts are rejected.
+    let mut config = Config::new();
+    config.diff_previous = true;
+    assert!(config.validate().is_ok());
+    config.output_format = "xlsx".to_string();
+    assert!(matches!(
+        config.validate(),
+        Err(AppError::Config(msg)) if msg.contains("DIFF_PREVIOUS")
+    ));
+    config.output_format = "csv".to_string();
+    config.columns = vec!["id".to_string(), "title".to_string()];
+    assert!(matches!(
+        config.validate(),
+        Err(AppError::Config(msg)) if msg.contains("DIFF_PREVIOUS")
+    ));
+}
+
+#[test]
+fn test_check_resource_count() {
+    // Test that a num_resources count disagreeing with the parsed resources is flagged.
+    let mut json = package_show_json("abc");
+    let parse = |json: &serde_json::Value| {
+        serde_json::from_value::<PackageShowResponse>(json.clone())
+            .unwrap()
+            .result
+            .unwrap()
+    };
+    assert!(check_resource_count(&parse(&json)));
+    json["result"]["num_resources"] = serde_json::json!(1);
+    assert!(check_resource_count(&parse(&json)));
+    json["result"]["num_resources"] = serde_json::json!(3);
+    let dataset = parse(&json);
+    assert_eq!(dataset.num_resources, Some(3));
+    assert!(!check_resource_count(&dataset));
+}
+
+#[tokio::test]
+async fn test_concurrency_limit_per_source() {
+    // Test that each CKAN portal is fetched with its own concurrency limit, falling back to
+    // CONCURRENCY_LIMIT for unlisted hosts.
+    async fn mount_slow_ckan(server: &MockServer, ids: &[&str]) {
+        Mock::given(method("GET"))
+            .and(path("/package_list"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": ids })),
+            )
+            .mount(server)
+            .await;
+        for id in ids {
+            Mock::given(method("GET"))
+                .and(path("/package_show"))
+                .and(query_param("id", *id))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(package_show_json(id))
+                        .set_delay(std::time::Duration::from_millis(300)),
+                )
+                .mount(server)
+                .await;
+        }
+    }
+    let slow = MockServer::start().await;
+    mount_slow_ckan(&slow, &["a1", "a2"]).await;
+    let fast = MockServer::start().await;
+    mount_slow_ckan(&fast, &["b1", "b2", "b3"]).await;
+    let host = |server: &MockServer| server.uri().trim_start_matches("http://").to_string();
+    let mut config = Config::new();
+    config.ckan_api_base_urls = vec![slow.uri(), fast.uri()];
+    config.csv_file = temp_csv_path("per_source_concurrency");
+    config.dry_run = true;
+    config.concurrency_limit = 1;
+    config.concurrency_limit_per_source = Some(format!(r#"{{"{}": 3}}"#, host(&fast)));
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        config
+            .for_ckan_source(&slow.uri())
+            .source_concurrency_limit(),
+        1
+    );
+    assert_eq!(
+        config
+            .for_ckan_source(&fast.uri())
+            .source_concurrency_limit(),
+        3
+    );
+
+    // The slow portal's two datasets run one after the other while the fast portal's three
+    // overlap them, so the run takes two request delays rather than five.
+    let started = std::time::Instant::now();
+    let summary = process_datasets(&config, false, &mock_s3_client(&slow.uri()))
+        .await
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(summary.rows_written, 5);
+    assert!(
+        elapsed >= std::time::Duration::from_millis(600),
+        "{elapsed:?}"
+    );
+    assert!(
+        elapsed < std::time::Duration::from_millis(1200),
+        "{elapsed:?}"
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+
+    config.concurrency_limit_per_source = Some(r#"{"data.gov.uk": 0}"#.to_string());
+    assert!(config.validate().is_err());
+    config.concurrency_limit_per_source = Some("data.gov.uk=3".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_incremental_uploads_full_output_and_delta() {
+    // Test that an incremental run uploads the full catalogue to the main key (the next run's
+    // baseline) and only the new or changed datasets to the delta key.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def", "ghi"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("incremental");
+    config.incremental = true;
+    // "abc" is unchanged, "def" was modified since and "ghi" is new.
+    let previous = "id,modified\nabc,2020-01-02\ndef,2019-12-31\n";
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/{}", config.bucket_name, config.s3_key())))
+        .respond_with(ResponseTemplate::new(200).set_body_string(previous))
+        .mount(&s3)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&s3)
+        .await;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 3);
+    assert_eq!(summary.delta_rows, Some(2));
+    assert_eq!(
+        summary.delta_output_key.as_deref(),
+        Some("delta/changed_datasets.csv")
+    );
+
+    let requests = s3.received_requests().await.unwrap();
+    let uploaded_ids = |key: &str| -> Vec<String> {
+        let upload = requests
+            .iter()
+            .find(|r| r.method.as_str() == "PUT" && r.url.path().ends_with(key))
+            .unwrap();
+        csv::Reader::from_reader(upload.body.as_slice())
+            .records()
+            .map(|r| r.unwrap()[0].to_string())
+            .collect()
+    };
+    assert_eq!(uploaded_ids(&config.s3_key()), vec!["abc", "def", "ghi"]);
+    assert_eq!(
+        uploaded_ids("/delta/changed_datasets.csv"),
+        vec!["def", "ghi"]
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+    std::fs::remove_file(config.delta_file()).unwrap();
+
+    // The previous upload must be a single CSV with id and modified columns to compare against.
+    assert!(config.validate().is_ok());
+    for invalid in [
+        |c: &mut Config| c.output_format = "parquet".to_string(),
+        |c: &mut Config| c.split_by_org = true,
+        |c: &mut Config| c.columns = vec!["id".to_string(), "title".to_string()],
+    ] {
+        let mut invalid_config = config.clone();
+        invalid(&mut invalid_config);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(AppError::Config(msg)) if msg.contains("INCREMENTAL")
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_multiple_ckan_sources() {
+    // Test that datasets from several portals are tagged with their source and deduped by id.
+    let first = MockServer::start().await;
+    mount_ckan_datasets(&first, &["abc", "def"]).await;
+    let second = MockServer::start().await;
+    mount_ckan_datasets(&second, &["def", "ghi"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_urls = vec![first.uri(), second.uri()];
+    config.csv_file = temp_csv_path("multi_source");
+    config.dry_run = true;
+    assert!(config.validate().is_ok());
+    let summary = process_datasets(&config, false, &mock_s3_client(&first.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_listed, 3);
+    assert_eq!(summary.rows_written, 3);
+
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let source_idx = rdr.headers().unwrap().iter().position(|h| h == "source");
+    let sources: Vec<(String, String)> = rdr
+        .records()
+        .map(|r| {
+            let r = r.unwrap();
+            (r[0].to_string(), r[source_idx.unwrap()].to_string())
+        })
+        .collect();
+    let host = |server: &MockServer| server.uri().trim_start_matches("http://").to_string();
+    assert_eq!(
+        sources,
+        vec![
+            ("abc".to_string(), host(&first)),
+            ("def".to_string(), host(&first)),
+            ("ghi".to_string(), host(&second)),
+        ]
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_fetch_failures_are_categorised() {
+    // Test that timeouts count as HTTP failures and malformed JSON as parse failures.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "bad", "slow"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "bad"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("{\"result\": {\"id\": 1"))
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "slow"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(package_show_json("slow"))
+                .set_delay(std::time::Duration::from_millis(1500)),
+        )
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("failure_categories");
+    config.http_timeout_secs = 1;
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 1);
+    assert_eq!(summary.datasets_failed, 2);
+    assert_eq!(summary.http_failures, 1);
+    assert_eq!(summary.parse_failures, 1);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_resolve_csv_file_template() {
+    // Test that {date} and {datetime} expand, literal names are unchanged, and Lambda uses /tmp/.
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 31, 12, 5, 9).unwrap();
+    assert_eq!(
+        Config::resolve_csv_file("DataGovUK_{date}.csv", now, false),
+        "DataGovUK_20240131.csv"
+    );
+    assert_eq!(
+        Config::resolve_csv_file("DataGovUK_{date}.csv", now, true),
+        "/tmp/DataGovUK_20240131.csv"
+    );
+    assert_eq!(
+        Config::resolve_csv_file("run_{datetime}.csv", now, false),
+        "run_20240131T120509Z.csv"
+    );
+    assert_eq!(
+        Config::resolve_csv_file("DataGovUK_Datasets.csv", now, false),
+        "DataGovUK_Datasets.csv"
+    );
+}
+
+#[tokio::test]
+async fn test_check_download_urls() {
+    // Test that URL statuses are recorded, unreachable URLs are flagged, and no rows are dropped.
+    let server = MockServer::start().await;
+    Mock::given(method("HEAD"))
+        .and(path("/ok.csv"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    Mock::given(method("HEAD"))
+        .and(path("/missing.csv"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("url_status");
+    let urls = ResourceUrls {
+        urls: vec![
+            format!("{}/ok.csv", server.uri()),
+            format!("{}/missing.csv", server.uri()),
+        ],
+        ..Default::default()
+    };
+    let unreachable = ResourceUrls {
+        urls: vec!["http://127.0.0.1:1/gone.csv".to_string()],
+        ..Default::default()
+    };
+    let mut rows = vec![
+        (sample_metadata("abc"), urls),
+        (sample_metadata("def"), unreachable),
+    ];
+    let client = create_url_check_client(&config).unwrap();
+    let broken = check_download_urls(&client, &config, &mut rows).await;
+    assert_eq!(broken, 2);
+    assert_eq!(rows[0].1.statuses, vec!["200", "404"]);
+    assert_eq!(rows[1].1.statuses, vec!["error"]);
+
+    write_csv(&config, &rows).unwrap();
+    let header = read_csv_header(&config.csv_file);
+    assert!(header.ends_with(&["download_url_2".to_string(), "url_status_2".to_string()]));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_write_sqlite() {
+    // Test that SQLite output has one datasets row per dataset and one resources row per URL.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("sqlite");
+    config.output_format = "sqlite".to_string();
+    assert!(config.output_file().ends_with(".db"));
+    let urls = ResourceUrls {
+        urls: vec!["http://a".to_string(), "http://b".to_string()],
+        sizes: vec!["10".to_string(), String::new()],
+        ..Default::default()
+    };
+    let rows = [
+        (sample_metadata("abc"), urls),
+        (sample_metadata("def"), ResourceUrls::default()),
+    ];
+    write_sqlite(&config, &rows).unwrap();
+    // Writing twice replaces the previous database rather than failing on duplicate ids.
+    write_sqlite(&config, &rows).unwrap();
+
+    let conn = rusqlite::Connection::open(config.output_file()).unwrap();
+    let count = |sql: &str| conn.query_row(sql, [], |r| r.get::<_, i64>(0)).unwrap();
+    assert_eq!(count("SELECT COUNT(*) FROM datasets"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM resources"), 2);
+    let (url, size): (String, Option<String>) = conn
+        .query_row(
+            "SELECT r.url, r.size FROM resources r JOIN datasets d ON d.id = r.dataset_id \
+             WHERE d.id = 'abc' AND r.position = 1",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((url.as_str(), size.as_deref()), ("http://a", Some("10")));
+    std::fs::remove_file(config.output_file()).unwrap();
+}
+
+#[test]
+fn test_write_xlsx() {
+    // Test that an xlsx workbook reopens with the header row, dataset values and URL columns.
+    use calamine::{open_workbook, Data, Reader, Xlsx};
+
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("xlsx");
+    config.output_format = "xlsx".to_string();
+    assert!(config.output_file().ends_with(".xlsx"));
+    let urls = ResourceUrls {
+        urls: vec!["http://a".to_string(), "http://b".to_string()],
+        ..Default::default()
+    };
+    let rows = [
+        (sample_metadata("abc"), urls),
+        (sample_metadata("def"), ResourceUrls::default()),
+    ];
+    write_xlsx(&config, &rows).unwrap();
+
+    let mut workbook: Xlsx<_> = open_workbook(config.output_file()).unwrap();
+    let sheet = workbook.worksheet_range("Datasets").unwrap();
+    let cell = |row: u32, col: u32| sheet.get_value((row, col)).cloned();
+    assert_eq!(cell(0, 0), Some(Data::String("id".to_string())));
+    let first_url = DatasetMetadata::COLUMNS.len() as u32;
+    assert_eq!(
+        cell(0, first_url + 1),
+        Some(Data::String("download_url_2".to_string()))
+    );
+    assert_eq!(cell(1, 1), Some(Data::String("Title abc".to_string())));
+    assert_eq!(
+        cell(1, first_url),
+        Some(Data::String("http://a".to_string()))
+    );
+    assert_eq!(cell(2, 0), Some(Data::String("def".to_string())));
+    std::fs::remove_file(config.output_file()).unwrap();
+}
+
+#[test]
+fn test_log_format_validation() {
+    // Test that plain and json log formats are accepted and anything else is a config error.
+    let mut config = Config::new();
+    assert_eq!(
+        config.log_format.parse::<LogFormat>().unwrap(),
+        LogFormat::Plain
+    );
+    config.log_format = "JSON".to_string();
+    assert!(config.validate().is_ok());
+    config.log_format = "xml".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[tokio::test]
+async fn test_run_deadline_writes_partial_output() {
+    // Test that hitting the run deadline stops fetching but still writes what was collected.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "slow"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "slow"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(package_show_json("slow"))
+                .set_delay(std::time::Duration::from_secs(5)),
+        )
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("deadline");
+    config.run_deadline_secs = Some(1);
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert!(summary.deadline_reached);
+    assert_eq!(summary.rows_written, 1);
+    assert_eq!(summary.datasets_not_fetched, 1);
+    assert_eq!(summary.datasets_failed, 0);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_sets_content_type_and_cache_control() {
+    // Test that each output format is uploaded with its Content-Type and the Cache-Control value.
+    for (format, content_type) in [
+        ("csv", "text/csv"),
+        ("parquet", "application/vnd.apache.parquet"),
+        ("sqlite", "application/vnd.sqlite3"),
+    ] {
+        let s3 = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(header("content-type", content_type))
+            .and(header("cache-control", "max-age=3600"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&s3)
+            .await;
+        let mut config = Config::new();
+        config.csv_file = temp_csv_path(&format!("content_type_{format}"));
+        config.output_format = format.to_string();
+        config.cache_control = Some("max-age=3600".to_string());
+        let output_file = config.output_file();
+        std::fs::write(&output_file, "data").unwrap();
+        upload_to_s3(&mock_s3_client(&s3.uri()), &config, &output_file)
+            .await
+            .unwrap();
+        std::fs::remove_file(&output_file).unwrap();
+    }
+}
+
+#[test]
+fn test_s3_sse_validation() {
+    // Test that aws:kms requires a KMS key id and unknown encryption values are rejected.
+    let mut config = Config::new();
+    assert!(config.validate().is_ok());
+    config.s3_sse = Some("AES256".to_string());
+    assert!(config.validate().is_ok());
+    config.s3_sse = Some("aws:kms".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    config.s3_kms_key_id = Some("alias/gov-data".to_string());
+    assert!(config.validate().is_ok());
+    config.s3_sse = Some("rot13".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_s3_storage_class_validation() {
+    // Test that known S3 storage classes are accepted and unknown ones are a config error.
+    let mut config = Config::new();
+    assert_eq!(config.s3_storage_class, "STANDARD");
+    config.s3_storage_class = "STANDARD_IA".to_string();
+    assert!(config.validate().is_ok());
+    config.s3_storage_class = "GLACIER".to_string();
+    assert!(config.validate().is_ok());
+    config.s3_storage_class = "CHEAPEST".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[tokio::test]
+async fn test_upload_attaches_sha256_metadata() {
+    // Test that the SHA-256 of a known input is returned and sent as x-amz-meta-sha256.
+    let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(header("x-amz-meta-sha256", digest))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("sha256");
+    std::fs::write(&config.csv_file, "abc").unwrap();
+    assert_eq!(file_sha256(&config.csv_file).unwrap(), digest);
+    let uploaded = upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+        .await
+        .unwrap();
+    assert_eq!(uploaded.sha256, digest);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_org_filter_keeps_matching_rows() {
+    // Test that ORG_FILTER keeps only datasets from the listed organisations, case-insensitively.
+    let mut meta = sample_metadata("abc");
+    meta.organization = "Department for Transport".to_string();
+    let filter = vec![
+        "department-for-transport".to_string(),
+        "Met Office".to_string(),
+    ];
+    assert!(matches_organization(&meta, &filter));
+    assert!(matches_organization(
+        &meta,
+        &["DEPARTMENT FOR TRANSPORT".to_string()]
+    ));
+    assert!(matches_organization(&meta, &[]));
+    meta.organization = "Home Office".to_string();
+    assert!(!matches_organization(&meta, &filter));
+
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("org_filter");
+    config.dry_run = true;
+    config.org_filter = vec!["nobody".to_string()];
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 0);
+    config.org_filter = vec!["org".to_string()];
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 2);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_duplicate_dataset_ids_are_fetched_once() {
+    // Test that duplicate ids in the package list are removed, preserving order, before fetching.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def", "abc", "ghi", "def"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("duplicate_ids");
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_listed, 3);
+    assert_eq!(summary.rows_written, 3);
+    let requests = ckan.received_requests().await.unwrap();
+    let shows = requests
+        .iter()
+        .filter(|r| r.url.path() == "/package_show")
+        .count();
+    assert_eq!(shows, 3);
+    let ids: Vec<String> = csv::Reader::from_path(&config.csv_file)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap()[0].to_string())
+        .collect();
+    assert_eq!(ids, vec!["abc", "def", "ghi"]);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_preflight_failure_aborts_run() {
+    // Test that a failing preflight aborts the run before any metadata fetch, unless disabled.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .and(query_param("limit", "1"))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("preflight");
+    config.dry_run = true;
+    let err = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap_err();
+    assert!(matches!(&err, AppError::Config(msg) if msg.contains("preflight")));
+    let requests = ckan.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+
+    config.preflight = false;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 1);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_streaming_csv_channel_writes_every_row() {
+    // Test that every fetched row reaches the streamed CSV when the writer channel is smaller than the catalogue.
+    let ids: Vec<String> = (0..25).map(|i| format!("dataset{i:02}")).collect();
+    let id_refs: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &id_refs).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("streaming_channel");
+    config.dry_run = true;
+    config.streaming_csv = true;
+    config.concurrency_limit = 2;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_listed, 25);
+    assert_eq!(summary.datasets_fetched, 25);
+    assert_eq!(summary.datasets_failed, 0);
+    assert_eq!(summary.rows_written, 25);
+    let written: std::collections::HashSet<String> = csv::Reader::from_path(&config.csv_file)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap()[0].to_string())
+        .collect();
+    assert_eq!(written, ids.into_iter().collect());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_resource_formats_histogram() {
+    // Test that resource formats are counted case-insensitively across datasets and written to formats_summary.csv.
+    let ckan = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "def"] })),
+        )
+        .mount(&ckan)
+        .await;
+    let resources = [
+        ("abc", serde_json::json!(["csv", " CSV ", "PDF"])),
+        ("def", serde_json::json!(["Csv", null])),
+    ];
+    for (id, formats) in resources {
+        let mut body = package_show_json(id);
+        body["result"]["resources"] = formats
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(i, format)| serde_json::json!({ "format": format, "url": format!("http://example.com/{id}/{i}") }))
+            .collect();
+        Mock::given(method("GET"))
+            .and(path("/package_show"))
+            .and(query_param("id", id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&ckan)
+            .await;
+    }
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("formats_histogram");
+    config.dry_run = true;
+    config.write_formats_summary = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.resource_formats.len(), 2);
+    assert_eq!(summary.resource_formats["CSV"], 3);
+    assert_eq!(summary.resource_formats["PDF"], 1);
+    let json = serde_json::to_value(&summary).unwrap();
+    assert_eq!(json["resource_formats"]["CSV"], 3);
+
+    let rows: Vec<Vec<String>> = csv::Reader::from_path(config.formats_summary_file())
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap().iter().map(String::from).collect())
+        .collect();
+    assert_eq!(rows, vec![vec!["CSV", "3"], vec!["PDF", "1"]]);
+
+    // The summary file follows the main CSV's delimiter, BOM and line ending settings.
+    config.csv_delimiter = "|".to_string();
+    config.add_bom = true;
+    config.csv_terminator = "crlf".to_string();
+    write_formats_csv(&config, &summary.resource_formats).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(config.formats_summary_file()).unwrap(),
+        "\u{feff}format|count\r\nCSV|3\r\nPDF|1\r\n"
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+    std::fs::remove_file(config.formats_summary_file()).unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrency_ramp_starts_with_one_request() {
+    // Test that early requests run one at a time and the ramp later allows more in flight, never exceeding the limit.
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+    let ramp = Arc::new(ConcurrencyRamp::start(4, Duration::from_millis(150)));
+    let started = Instant::now();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_early = Arc::new(AtomicUsize::new(0));
+    let max_overall = Arc::new(AtomicUsize::new(0));
+    let tasks: Vec<_> = (0..10)
+        .map(|_| {
+            let (ramp, in_flight) = (Arc::clone(&ramp), Arc::clone(&in_flight));
+            let (max_early, max_overall) = (Arc::clone(&max_early), Arc::clone(&max_overall));
+            tokio::spawn(async move {
+                let _permit = ramp.acquire().await;
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                if started.elapsed() < Duration::from_millis(100) {
+                    max_early.fetch_max(current, Ordering::SeqCst);
+                }
+                max_overall.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(max_early.load(Ordering::SeqCst), 1);
+    let max_overall = max_overall.load(Ordering::SeqCst);
+    assert!(
+        max_overall > 1 && max_overall <= 4,
+        "max in flight {max_overall}"
+    );
+}
+
+#[tokio::test]
+async fn test_failed_ids_uploaded_as_dead_letter_file() {
+    // Test that datasets that fail or are missing are uploaded to failed_ids.json with their error
+    // category, with only a 404 counted as not_found.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "def", "ghi", "jkl"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "jkl"))
+        .respond_with(ResponseTemplate::new(403))
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "def"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "ghi"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&ckan)
+        .await;
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("failed_ids");
+    config.key_prefix = "runs".to_string();
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_failed, 3);
+    assert_eq!(summary.http_failures, 1);
+    assert_eq!(
+        summary.failed_ids_key.as_deref(),
+        Some("runs/failed_ids.json")
+    );
+    let requests = s3.received_requests().await.unwrap();
+    let upload = requests
+        .iter()
+        .find(|r| r.url.path().ends_with("/runs/failed_ids.json"))
+        .unwrap();
+    let mut failed: Vec<FailedDataset> = serde_json::from_slice(&upload.body).unwrap();
+    failed.sort_by(|a, b| a.id.cmp(&b.id));
+    let expected = |id: &str, category: &str| FailedDataset {
+        id: id.to_string(),
+        category: category.to_string(),
+        ckan_api_base_url: ckan.uri(),
+    };
+    assert_eq!(
+        failed,
+        vec![
+            expected("def", "parse"),
+            expected("ghi", "not_found"),
+            expected("jkl", "http")
+        ]
+    );
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_retry_failed_from_processes_only_failed_ids() {
+    // Test that RETRY_FAILED_FROM loads the dead-letter file as the input set instead of listing CKAN.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def", "ghi"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("retry_failed");
+    config.dry_run = true;
+    config.retry_failed_from = Some("runs/failed_ids.json".to_string());
+    let failed = vec![
+        FailedDataset {
+            id: "def".to_string(),
+            category: "http".to_string(),
+            ckan_api_base_url: ckan.uri(),
+        },
+        FailedDataset {
+            id: "ghi".to_string(),
+            category: "not_found".to_string(),
+            ckan_api_base_url: ckan.uri(),
+        },
+    ];
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!(
+            "/{}/runs/failed_ids.json",
+            config.bucket_name
+        )))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&failed))
+        .expect(1)
+        .mount(&s3)
+        .await;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_listed, 2);
+    assert_eq!(summary.rows_written, 2);
+    let requests = ckan.received_requests().await.unwrap();
+    assert!(requests.iter().all(|r| r.url.path() == "/package_show"));
+    let ids: Vec<String> = csv::Reader::from_path(&config.csv_file)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap()[0].to_string())
+        .collect();
+    assert_eq!(ids, vec!["def", "ghi"]);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_output_dest_local_skips_s3() {
+    // Test that OUTPUT_DEST=local writes the output file without any S3 request, even with failures to report.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "missing"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    let s3 = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("output_dest_local");
+    config.output_dest = "local".to_string();
+    config.write_manifest = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 1);
+    assert_eq!(summary.output_key, "");
+    assert_eq!(summary.failed_ids_key, None);
+    assert_eq!(read_csv_header(&config.csv_file)[0], "id");
+
+    // DynamoDB is an external write too, so local mode skips it rather than writing the table.
+    let mut dynamodb_config = config.clone();
+    dynamodb_config.output_format = "dynamodb".to_string();
+    dynamodb_config.dynamodb_table = Some("datasets".to_string());
+    let summary = process_datasets(&dynamodb_config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 1);
+
+    config.output_dest = "cloud".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_write_tags_csv() {
+    // Test that a dataset with three tags produces three (dataset_id, tag) rows, written with
+    // the configured CSV format.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("tags");
+    let mut meta = sample_metadata("abc");
+    meta.tags = "transport, roads, traffic".to_string();
+    let rows = [
+        (meta, ResourceUrls::default()),
+        (sample_metadata("def"), ResourceUrls::default()),
+    ];
+    let tags_file = config.tags_file();
+    assert!(tags_file.ends_with("/dataset_tags.csv"));
+    assert_eq!(write_tags_csv(&config, &rows).unwrap(), 3);
+    let contents = std::fs::read_to_string(&tags_file).unwrap();
+    assert_eq!(
+        contents,
+        "dataset_id,tag\nabc,transport\nabc,roads\nabc,traffic\n"
+    );
+
+    // The tags file follows the main CSV's delimiter, BOM and line ending settings.
+    config.csv_delimiter = ";".to_string();
+    config.add_bom = true;
+    config.csv_terminator = "crlf".to_string();
+    write_tags_csv(&config, &rows).unwrap();
+    let contents = std::fs::read_to_string(&tags_file).unwrap();
+    assert_eq!(
+        contents,
+        "\u{feff}dataset_id;tag\r\nabc;transport\r\nabc;roads\r\nabc;traffic\r\n"
+    );
+    std::fs::remove_file(&tags_file).unwrap();
+}
+
+#[test]
+fn test_write_resources_csv() {
+    // Test that a dataset with two resources produces two fully detailed resource rows.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("resources");
+    let data = serde_json::json!({
+        "id": "abc",
+        "title": "Test",
+        "notes": "desc",
+        "license_title": "Open",
+        "organization": { "title": "Org" },
+        "metadata_created": "2020-01-01",
+        "metadata_modified": "2020-01-02",
+        "resources": [
+            {
+                "format": "CSV",
+                "url": "http://example.com/a.csv",
+                "size": 1024,
+                "last_modified": "2024-01-01T00:00:00",
+                "mimetype": "text/csv"
+            },
+            {
+                "format": "PDF",
+                "url": "http://example.com/b.pdf",
+                "mimetype": "application/pdf"
+            },
+            { "format": "HTML" }
+        ]
+    });
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (_, urls) = extract_resource_formats_and_urls(&dataset, &["CSV".to_string()], true, true);
+    assert_eq!(urls.urls, vec!["http://example.com/a.csv"]);
+    let mut meta = sample_metadata("abc");
+    meta.extras.resources = resource_details(&dataset);
+    let rows = [(meta, urls)];
+    let resources_file = config.resources_file();
+    assert!(resources_file.ends_with("/resources.csv"));
+    assert_eq!(write_resources_csv(&config, &rows).unwrap(), 2);
+    let contents = std::fs::read_to_string(&resources_file).unwrap();
+    assert_eq!(
+        contents,
+        "dataset_id,resource_url,format,size,last_modified,mimetype\n\
+         abc,http://example.com/a.csv,CSV,1024,2024-01-01T00:00:00,text/csv\n\
+         abc,http://example.com/b.pdf,PDF,,,application/pdf\n"
+    );
+
+    // The resources file follows the main CSV's delimiter and BOM settings.
+    config.csv_delimiter = "\t".to_string();
+    config.add_bom = true;
+    write_resources_csv(&config, &rows).unwrap();
+    let contents = std::fs::read_to_string(&resources_file).unwrap();
+    assert!(contents.starts_with("\u{feff}dataset_id\tresource_url\tformat\t"));
+    assert!(contents.contains("abc\thttp://example.com/b.pdf\tPDF\t\t\tapplication/pdf\n"));
+    std::fs::remove_file(&resources_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_http_pool_settings() {
+    // Test that overridden pool settings are carried through and a zero connect timeout is rejected.
+    let mut config = Config::new();
+    assert_eq!(config.http_pool_max_idle, 10);
+    assert_eq!(config.http_pool_idle_timeout_secs, 90);
+    assert_eq!(config.http_connect_timeout_secs, 10);
+    config.http_pool_max_idle = 2;
+    config.http_pool_idle_timeout_secs = 5;
+    config.http_connect_timeout_secs = 3;
+    assert!(config.validate().is_ok());
+
+    let server = MockServer::start().await;
+    mount_ckan_datasets(&server, &["abc"]).await;
+    config.ckan_api_base_url = server.uri();
+    let client = create_http_client(&config).unwrap();
+    assert_eq!(
+        fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+            .await
+            .unwrap(),
+        vec!["abc"]
+    );
+
+    config.http_connect_timeout_secs = 0;
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[tokio::test]
+async fn test_resume_skips_completed_datasets() {
+    // Test that a resumed run skips ids in the checkpoint, writes their saved rows, and removes
+    // the checkpoint when the run completes.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("resume");
+    config.checkpoint_file = temp_csv_path("checkpoint").replace(".csv", ".json");
+    config.resume = true;
+    config.checkpoint_interval = 1;
+    config.dry_run = true;
+    let mut saved_row = sample_metadata("abc");
+    saved_row.title = "Saved before the interruption".to_string();
+    save_checkpoint(
+        &config.checkpoint_file,
+        &["abc".to_string()].into_iter().collect(),
+        &[(saved_row, ResourceUrls::default())],
+    )
+    .unwrap();
+    assert!(load_checkpoint(&config.checkpoint_file)
+        .unwrap()
+        .completed
+        .contains("abc"));
+
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.datasets_resumed, 1);
+    assert_eq!(summary.datasets_fetched, 1);
+    assert_eq!(summary.rows_written, 2);
+    let output = std::fs::read_to_string(&config.csv_file).unwrap();
+    assert!(output.contains("Saved before the interruption"));
+    assert!(output.lines().any(|line| line.starts_with("def,")));
+    let requested: Vec<String> = ckan
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path() == "/package_show")
+        .filter_map(|r| r.url.query().map(String::from))
+        .collect();
+    assert_eq!(requested, vec!["id=def"]);
+    assert!(!std::path::Path::new(&config.checkpoint_file).exists());
+    assert!(!std::path::Path::new(&rows_path(&config.checkpoint_file)).exists());
+    assert!(load_checkpoint(&config.checkpoint_file)
+        .unwrap()
+        .completed
+        .is_empty());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_checkpoint_save_flushes_rows_below_interval() {
+    // Test that a final save writes rows recorded since the last interval save, and that RESUME
+    // without a checkpoint interval is rejected.
+    let path = temp_csv_path("checkpoint_flush").replace(".csv", ".json");
+    let checkpoint = Checkpoint::new(&path, 10, std::collections::HashSet::new());
+    checkpoint.record(&(sample_metadata("abc"), ResourceUrls::default()));
+    assert!(!std::path::Path::new(&path).exists());
+    checkpoint.save().await;
+    let saved = load_checkpoint(&path).unwrap();
+    assert!(saved.completed.contains("abc"));
+    assert_eq!(saved.rows.len(), 1);
+    assert_eq!(saved.rows[0].0.id, "abc");
+    checkpoint.clear();
+    assert!(!std::path::Path::new(&rows_path(&path)).exists());
+
+    let mut config = Config::new();
+    config.resume = true;
+    let error_msg = config.validate().unwrap_err().to_string();
+    assert!(error_msg.contains("CHECKPOINT_INTERVAL"), "{error_msg}");
+    config.checkpoint_interval = 5;
+    config.validate().unwrap();
+}
+
+#[tokio::test]
+async fn test_write_dynamodb_batches_and_retries_unprocessed() {
+    // Test that 30 datasets go out in batches of 25 and 5, unprocessed items are resent, and
+    // duplicate download URLs are collapsed in the string set.
+    let dynamodb = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("x-amz-target", "DynamoDB_20120810.BatchWriteItem"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "UnprocessedItems": {
+                "datasets": [{ "PutRequest": { "Item": { "id": { "S": "abc0" } } } }]
+            }
+        })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&dynamodb)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "UnprocessedItems": {} })),
+        )
+        .mount(&dynamodb)
+        .await;
+    let mut config = Config::new();
+    config.output_format = "dynamodb".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    config.dynamodb_table = Some("datasets".to_string());
+    assert!(config.validate().is_ok());
+    let rows: Vec<(DatasetMetadata, ResourceUrls)> = (0..30)
+        .map(|i| {
+            // Two resources sharing a URL must still make a valid string set.
+            let url = format!("http://example.com/{i}.csv");
+            let urls = ResourceUrls {
+                urls: vec![url.clone(), url],
+                ..Default::default()
+            };
+            (sample_metadata(&format!("abc{i}")), urls)
+        })
+        .collect();
+    write_dynamodb(&mock_dynamodb_client(&dynamodb.uri()), &config, &rows)
+        .await
+        .unwrap();
+
+    let requests = dynamodb.received_requests().await.unwrap();
+    let item_counts: Vec<usize> = requests
+        .iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["RequestItems"]["datasets"].as_array().unwrap().len()
+        })
+        .collect();
+    assert_eq!(item_counts, vec![25, 1, 5]);
+    let first: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+    let item = &first["RequestItems"]["datasets"][0]["PutRequest"]["Item"];
+    assert_eq!(item["id"]["S"], "abc0");
+    assert_eq!(
+        item["download_urls"]["SS"],
+        serde_json::json!(["http://example.com/0.csv"])
+    );
+}
+
+#[tokio::test]
+async fn test_slow_requests_are_flagged_and_recorded() {
+    // Test that a delayed response crosses the slow threshold and tops the slowest datasets.
+    let mut config = Config::new();
+    config.slow_request_threshold_ms = 200;
+    assert!(!warn_if_slow(
+        &config,
+        "abc",
+        std::time::Duration::from_millis(50)
+    ));
+    assert!(warn_if_slow(
+        &config,
+        "abc",
+        std::time::Duration::from_millis(250)
+    ));
+
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "slow", "def"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "slow"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(package_show_json("slow"))
+                .set_delay(std::time::Duration::from_millis(400)),
+        )
+        .mount(&ckan)
+        .await;
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("slow_requests");
+    config.slowest_datasets_count = 2;
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.slowest_datasets.len(), 2);
+    assert_eq!(summary.slowest_datasets[0].dataset_id, "slow");
+    assert!(summary.slowest_datasets[0].duration_ms >= 400);
+    assert!(warn_if_slow(
+        &config,
+        "slow",
+        std::time::Duration::from_millis(summary.slowest_datasets[0].duration_ms)
+    ));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_url_format_filter() {
+    // Test that only CSV URLs are kept when the filter is CSV, while formats list everything.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["resources"] = serde_json::json!([
+        { "format": "csv", "url": "http://example.com/a.csv" },
+        { "format": "PDF", "url": "http://example.com/a.pdf" },
+        { "format": "HTML", "url": "http://example.com/page" },
+        { "url": "http://example.com/unknown" }
+    ]);
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (formats, urls) =
+        extract_resource_formats_and_urls(&dataset, &["CSV".to_string()], true, false);
+    assert_eq!(formats, "csv, PDF, HTML");
+    assert_eq!(urls.urls, vec!["http://example.com/a.csv"]);
+    let (_, all) = extract_resource_formats_and_urls(&dataset, &[], true, false);
+    assert_eq!(all.urls.len(), 4);
+}
+
+/// Tracing layer that records the name and `phase` field of every span that closes.
+#[derive(Clone, Default)]
+struct PhaseSpanRecorder {
+    phases: Arc<std::sync::Mutex<std::collections::HashMap<tracing::span::Id, String>>>,
+    closed: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+}
+
+/// Field visitor that picks out a span's `phase` value.
+struct PhaseVisitor(Option<String>);
+
+impl tracing::field::Visit for PhaseVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "phase" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "phase" {
+            self.0 = Some(format!("{value:?}").trim_matches('"').to_string());
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for PhaseSpanRecorder
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut visitor = PhaseVisitor(None);
+        attrs.record(&mut visitor);
+        if let Some(phase) = visitor.0 {
+            self.phases.lock().unwrap().insert(id.clone(), phase);
+        }
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let phase = self.phases.lock().unwrap().remove(&id);
+        if let (Some(phase), Some(span)) = (phase, ctx.span(&id)) {
+            self.closed
+                .lock()
+                .unwrap()
+                .push((span.name().to_string(), phase));
+        }
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_workflow_phase_spans() {
+    // Test that each workflow phase runs in its own span, closed once the phase is done.
+    use tracing_subscriber::layer::SubscriberExt;
+    let recorder = PhaseSpanRecorder::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let s3 = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("phase_spans");
+    process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    let closed = recorder.closed.lock().unwrap().clone();
+    for expected in [
+        ("fetch_dataset_list", "list"),
+        ("fetch_metadata", "fetch"),
+        ("write_csv", "write"),
+        ("upload_to_s3", "upload"),
+    ] {
+        assert!(
+            closed.contains(&(expected.0.to_string(), expected.1.to_string())),
+            "missing span {expected:?} in {closed:?}"
+        );
+    }
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_fetch_last_run_timestamps_gzipped() {
+    // Test that a gzipped previous CSV is decompressed whether flagged by encoding or a .gz key.
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("gzipped_previous");
+    let mut newer = sample_metadata("def");
+    newer.modified = "2021-05-05".to_string();
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), ResourceUrls::default()),
+            (newer, ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+        .write_all(&std::fs::read(&config.csv_file).unwrap())
+        .unwrap();
+    let gzipped = encoder.finish().unwrap();
+    std::fs::remove_file(&config.csv_file).unwrap();
+
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/previous.csv", config.bucket_name)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("Content-Encoding", "gzip")
+                .set_body_bytes(gzipped.clone()),
+        )
+        .mount(&s3)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/previous.csv.gz", config.bucket_name)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(gzipped))
+        .mount(&s3)
+        .await;
+    let client = mock_s3_client(&s3.uri());
+    for csv_file in ["previous.csv", "previous.csv.gz"] {
+        config.csv_file = csv_file.to_string();
+        let timestamps = fetch_last_run_timestamps(&client, &config).await.unwrap();
+        assert_eq!(timestamps.len(), 2, "{csv_file}");
+        assert_eq!(timestamps["abc"], sample_metadata("abc").modified);
+        assert_eq!(timestamps["def"], "2021-05-05");
+    }
+}
+
+#[tokio::test]
+async fn test_s3_reads_retry_transient_errors() {
+    // Test that S3 reads retry a 503 (the client's own retries are off) and still treat a
+    // missing object as no previous run.
+    let mut config = Config::new();
+    config.s3_retry_base_delay_ms = 1;
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/{}", config.bucket_name, config.s3_key())))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&s3)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/{}", config.bucket_name, config.s3_key())))
+        .respond_with(ResponseTemplate::new(200).set_body_string("id,modified\nabc,2021-01-01\n"))
+        .mount(&s3)
+        .await;
+    let client = mock_s3_client(&s3.uri());
+    let timestamps = fetch_last_run_timestamps(&client, &config).await.unwrap();
+    assert_eq!(timestamps["abc"], "2021-01-01");
+    assert_eq!(s3.received_requests().await.unwrap().len(), 2);
+
+    config.csv_file = "missing.csv".to_string();
+    let missing = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(404).set_body_string(
+            "<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>",
+        ))
+        .mount(&missing)
+        .await;
+    let client = mock_s3_client(&missing.uri());
+    assert!(fetch_last_run_timestamps(&client, &config)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(missing.received_requests().await.unwrap().len(), 1);
+}
+
+#[test]
+fn test_include_urls_disabled() {
+    // Test that INCLUDE_URLS=false skips URL collection and leaves no download_url columns.
+    let dataset: CkanDataset =
+        serde_json::from_value(package_show_json("abc")["result"].clone()).unwrap();
+    let (formats, urls) = extract_resource_formats_and_urls(&dataset, &[], false, false);
+    assert_eq!(formats, "CSV");
+    assert!(urls.urls.is_empty());
+
+    let mut config = Config::new();
+    config.include_urls = false;
+    config.csv_file = temp_csv_path("include_urls_disabled");
+    let (_, urls) = extract_resource_formats_and_urls(&dataset, &[], true, false);
+    write_csv(&config, &[(sample_metadata("abc"), urls)]).unwrap();
+    let header = read_csv_header(&config.csv_file);
+    assert!(header.contains(&"format".to_string()));
+    assert!(!header.iter().any(|h| h.starts_with("download_url_")));
+    assert_eq!(header.len(), DatasetMetadata::COLUMNS.len());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_skip_inactive_excludes_deleted_and_private() {
+    // Test that deleted and private datasets are dropped with SKIP_INACTIVE and written,
+    // with their state and private columns, when it is off.
+    let ckan = MockServer::start().await;
+    let ids = ["live", "gone", "hidden", "unreported"];
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": ids })),
+        )
+        .mount(&ckan)
+        .await;
+    for (id, state, private) in [
+        ("live", Some("active"), Some(false)),
+        ("gone", Some("deleted"), Some(false)),
+        ("hidden", Some("active"), Some(true)),
+        ("unreported", None, None),
+    ] {
+        let mut json = package_show_json(id);
+        json["result"]["state"] = serde_json::json!(state);
+        json["result"]["private"] = serde_json::json!(private);
+        Mock::given(method("GET"))
+            .and(path("/package_show"))
+            .and(query_param("id", id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json))
+            .mount(&ckan)
+            .await;
+    }
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("skip_inactive");
+    config.dry_run = true;
+    let s3 = mock_s3_client(&ckan.uri());
+    let rows = |config: &Config| -> Vec<(String, String, String)> {
+        let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let (state, private) = (column("state"), column("private"));
+        rdr.records()
+            .map(|r| {
+                let r = r.unwrap();
+                (
+                    r[0].to_string(),
+                    r[state].to_string(),
+                    r[private].to_string(),
+                )
+            })
+            .collect()
+    };
+
+    assert!(config.skip_inactive);
+    let summary = process_datasets(&config, false, &s3).await.unwrap();
+    assert_eq!(summary.datasets_fetched, 4);
+    let kept: Vec<String> = rows(&config).into_iter().map(|(id, _, _)| id).collect();
+    assert_eq!(kept, vec!["live", "unreported"]);
+
+    config.skip_inactive = false;
+    process_datasets(&config, false, &s3).await.unwrap();
+    let written = rows(&config);
+    assert_eq!(written.len(), 4);
+    assert!(written.contains(&(
+        "gone".to_string(),
+        "deleted".to_string(),
+        "false".to_string()
+    )));
+    assert!(written.contains(&(
+        "hidden".to_string(),
+        "active".to_string(),
+        "true".to_string()
+    )));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_resource_count_column() {
+    // Test that resource_count counts every resource, including one without a URL.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["resources"] = serde_json::json!([
+        { "format": "CSV", "url": "http://example.com/a.csv" },
+        { "format": "PDF", "url": "http://example.com/a.pdf" },
+        { "format": "HTML" }
+    ]);
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (metadata, urls) =
+        build_dataset_metadata(&dataset, &[], true, true, DescriptionMode::Strip);
+    assert_eq!(metadata.resource_count, 3);
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("resource_count");
+    write_csv(&config, &[(metadata, urls)]).unwrap();
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let column = rdr
+        .headers()
+        .unwrap()
+        .iter()
+        .position(|h| h == "resource_count")
+        .unwrap();
+    let record = rdr.records().next().unwrap().unwrap();
+    assert_eq!(&record[column], "3");
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_after_run() {
+    // Test that /metrics serves the run counters in Prometheus format after a run, counting
+    // runs as they start, and that only GET /metrics is served.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("metrics_endpoint");
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    let metrics = Arc::new(PrometheusMetrics::default());
+    metrics.record_run_started();
+    metrics.record_run(&summary);
+    // A second run that crashed before finishing still counts as started.
+    metrics.record_run_started();
+    let addr = start_metrics_server("127.0.0.1:0".parse().unwrap(), Arc::clone(&metrics))
+        .await
+        .unwrap();
+    let response = reqwest::get(format!("http://{addr}/metrics"))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let body = response.text().await.unwrap();
+    assert!(body.contains("gov_data_runs_total 2\n"));
+    assert!(body.contains("gov_data_runs_failed_total 0\n"));
+    assert!(body.contains("gov_data_datasets_fetched_total 2\n"));
+    assert!(body.contains("gov_data_datasets_failed_total 0\n"));
+    assert!(body.contains("# TYPE gov_data_last_run_duration_seconds gauge"));
+    let missing = reqwest::get(format!("http://{addr}/other")).await.unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+    let post = reqwest::Client::new()
+        .post(format!("http://{addr}/metrics"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(post.status(), reqwest::StatusCode::NOT_FOUND);
+    assert_eq!(
+        parse_request_line("GET /metrics?name=x HTTP/1.1\r\nHost: a\r\n\r\n"),
+        Some(("GET", "/metrics"))
+    );
+    assert_eq!(parse_request_line("GET /metrics HTTP/1.1"), None);
+    assert_eq!(parse_request_line("GET /metrics\r\n"), None);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_ckan_proxy_url() {
+    // Test that CKAN requests are sent through CKAN_PROXY_URL and an invalid proxy is rejected.
+    let proxy = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/3/action/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": ["abc"] })),
+        )
+        .expect(1)
+        .mount(&proxy)
+        .await;
+    let mut config = Config::new();
+    // The portal host does not resolve, so the request only succeeds via the proxy.
+    config.ckan_api_base_url = "http://ckan.invalid/api/3/action".to_string();
+    config.ckan_proxy_url = Some(proxy.uri());
+    config.validate().unwrap();
+    let client = create_http_client(&config).unwrap();
+    let ids = fetch_dataset_list(&client, &config, false, &AtomicU64::new(0))
+        .await
+        .unwrap();
+    assert_eq!(ids, vec!["abc"]);
+
+    config.ckan_proxy_url = Some("http://bad host:8080".to_string());
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    assert!(matches!(
+        create_http_client(&config),
+        Err(AppError::Config(_))
+    ));
+}
+
+#[test]
+fn test_csv_quote_style_always() {
+    // Test that CSV_QUOTE_STYLE=always quotes every field and unknown styles are rejected.
+    let mut config = Config::new();
+    assert_eq!(
+        config.csv_quote_style.parse::<CsvQuoteStyle>().unwrap(),
+        CsvQuoteStyle::Necessary
+    );
+    config.csv_quote_style = "always".to_string();
+    config.csv_file = temp_csv_path("quote_always");
+    write_csv(
+        &config,
+        &[(sample_metadata("abc"), ResourceUrls::default())],
+    )
+    .unwrap();
+    let output = std::fs::read_to_string(&config.csv_file).unwrap();
+    for line in output.lines() {
+        assert!(line
+            .split(',')
+            .all(|field| field.starts_with('"') && field.ends_with('"')));
+    }
+    assert!(output.contains("\"abc\",\"Title abc\""));
+    config.csv_quote_style = "sometimes".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_csv_quote_style_never_skips_validation() {
+    // Test that a run with CSV_QUOTE_STYLE=never succeeds when a field holds the delimiter,
+    // since the unquoted output can't be re-read for validation.
+    let ckan = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": ["abc"] })),
+        )
+        .mount(&ckan)
+        .await;
+    let mut show = package_show_json("abc");
+    show["result"]["title"] = serde_json::json!("Roads, traffic and parking");
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(show))
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("quote_never");
+    config.csv_quote_style = "never".to_string();
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&ckan.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 1);
+    let output = std::fs::read_to_string(&config.csv_file).unwrap();
+    assert!(output.contains("abc,Roads, traffic and parking,"));
+    assert!(validate_csv(&config, &config.csv_file).is_err());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_csv_terminator_crlf() {
+    // Test that CSV_TERMINATOR=crlf ends every record with CRLF and unknown values are rejected.
+    let mut config = Config::new();
+    assert_eq!(
+        config.csv_terminator.parse::<CsvTerminator>().unwrap(),
+        CsvTerminator::Lf
+    );
+    config.csv_terminator = "crlf".to_string();
+    config.csv_file = temp_csv_path("terminator_crlf");
+    let rows = [
+        (sample_metadata("abc"), ResourceUrls::default()),
+        (sample_metadata("def"), ResourceUrls::default()),
+    ];
+    write_csv(&config, &rows).unwrap();
+    let output = std::fs::read_to_string(&config.csv_file).unwrap();
+    assert_eq!(output.matches("\r\n").count(), 3);
+    assert_eq!(output.matches('\n').count(), 3);
+    assert!(output.contains("\r\nabc,") && output.contains("\r\ndef,"));
+    assert_eq!(validate_csv(&config, &config.csv_file).unwrap(), 2);
+    config.csv_terminator = "cr".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_write_org_csvs() {
+    // Test that SPLIT_BY_ORG writes one CSV per organisation holding only its datasets.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("split_by_org");
+    let mut dft = sample_metadata("abc");
+    dft.organization = "Department for Transport".to_string();
+    let mut dft_second = sample_metadata("def");
+    dft_second.organization = "Department for Transport".to_string();
+    let mut ons = sample_metadata("ghi");
+    ons.organization = "Office for National Statistics".to_string();
+    let files = write_org_csvs(
+        &config,
+        &[
+            (dft, ResourceUrls::default()),
+            (ons, ResourceUrls::default()),
+            (dft_second, ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+    let slugs: Vec<&str> = files.iter().map(|f| f.slug.as_str()).collect();
+    assert_eq!(
+        slugs,
+        vec!["department-for-transport", "office-for-national-statistics"]
+    );
+    assert_eq!(files[0].path, config.org_file("department-for-transport"));
+    for (file, expected_ids) in files.iter().zip([vec!["abc", "def"], vec!["ghi"]]) {
+        assert_eq!(file.rows, expected_ids.len());
+        let mut rdr = csv::Reader::from_path(&file.path).unwrap();
+        assert_eq!(&rdr.headers().unwrap()[0], "id");
+        let ids: Vec<String> = rdr.records().map(|r| r.unwrap()[0].to_string()).collect();
+        assert_eq!(ids, expected_ids);
+        std::fs::remove_file(&file.path).unwrap();
+    }
+    assert_eq!(
+        config.org_file_key("department-for-transport"),
+        format!(
+            "org=department-for-transport/{}",
+            config.csv_file.rsplit('/').next().unwrap()
+        )
+    );
+}
+
+#[test]
+fn test_row_count_mismatch_aborts() {
+    // Test that a streaming writer which receives fewer rows than the fetch kept fails the
+    // row-count check, and only warns without ABORT_ON_ROW_MISMATCH.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("row_count");
+    config.abort_on_row_mismatch = true;
+    let writer = StreamingCsvWriter::create(&config).unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let expected = 3;
+    // The third row is lost before it reaches the writer, as with a dropped send.
+    for id in ["abc", "def"] {
+        tx.try_send((sample_metadata(id), ResourceUrls::default()))
+            .unwrap();
+    }
+    drop(tx);
+    let written = writer.write_from_channel(rx).unwrap();
+    assert_eq!(written, 2);
+    assert_eq!(validate_csv(&config, &config.csv_file).unwrap(), written);
+    assert!(matches!(
+        check_rows_written(&config, expected, written),
+        Err(AppError::Other(msg)) if msg.contains("wrote 2 rows for 3 datasets")
+    ));
+    config.abort_on_row_mismatch = false;
+    assert!(check_rows_written(&config, expected, written).is_ok());
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_failure_ratio_below_threshold_succeeds() {
+    // Test that a run whose failure ratio is at or below MAX_FAILURE_RATIO passes the check.
+    let mut config = Config::new();
+    assert_eq!(config.max_failure_ratio, 1.0);
+    let summary = RunSummary {
+        datasets_fetched: 8,
+        datasets_failed: 2,
+        ..Default::default()
+    };
+    assert!(check_failure_ratio(&config, &summary).is_ok());
+    config.max_failure_ratio = 0.2;
+    assert!(config.validate().is_ok());
+    assert!(check_failure_ratio(&config, &summary).is_ok());
+    assert!(check_failure_ratio(&config, &RunSummary::default()).is_ok());
+}
+
+#[test]
+fn test_failure_ratio_above_threshold_fails() {
+    // Test that a run whose failure ratio exceeds MAX_FAILURE_RATIO is turned into an error.
+    let mut config = Config::new();
+    config.max_failure_ratio = 0.2;
+    let summary = RunSummary {
+        datasets_fetched: 7,
+        datasets_failed: 3,
+        ..Default::default()
+    };
+    let err = check_failure_ratio(&config, &summary).unwrap_err();
+    assert!(matches!(err, AppError::Other(_)));
+    assert!(err.to_string().contains("3 of 10 datasets failed"));
+
+    config.max_failure_ratio = 1.5;
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_columns_projection() {
+    // Test that COLUMNS selects and orders the metadata columns and rejects unknown names.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("columns");
+    config.columns = vec![
+        "id".to_string(),
+        "title".to_string(),
+        "modified".to_string(),
+    ];
+    assert!(config.validate().is_ok());
+    let mut meta = sample_metadata("abc");
+    meta.title = "Road traffic".to_string();
+    meta.modified = "2024-03-01".to_string();
+    write_csv(&config, &[(meta, ResourceUrls::default())]).unwrap();
+    assert_eq!(
+        read_csv_header(&config.csv_file),
+        vec!["id", "title", "modified"]
+    );
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let row = rdr.records().next().unwrap().unwrap();
+    assert_eq!(
+        row.iter().collect::<Vec<_>>(),
+        vec!["abc", "Road traffic", "2024-03-01"]
+    );
+    assert_eq!(validate_csv(&config, &config.csv_file).unwrap(), 1);
+    std::fs::remove_file(&config.csv_file).unwrap();
+
+    config.columns = vec!["id".to_string(), "publisher".to_string()];
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("Unknown column 'publisher'"));
+    config.columns = vec!["id".to_string(), "id".to_string()];
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
+
+#[test]
+fn test_add_bom() {
+    // Test that ADD_BOM starts the CSV with a UTF-8 BOM, and that the file still validates.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("bom");
+    let mut meta = sample_metadata("abc");
+    meta.organization = "Département de Données".to_string();
+    let rows = [(meta, ResourceUrls::default())];
+    write_csv(&config, &rows).unwrap();
+    let bytes = std::fs::read(&config.csv_file).unwrap();
+    assert!(bytes.starts_with(b"id,"));
+
+    config.add_bom = true;
+    write_csv(&config, &rows).unwrap();
+    let bytes = std::fs::read(&config.csv_file).unwrap();
+    assert_eq!(&bytes[..3], &[0xEF, 0xBB, 0xBF]);
+    assert!(bytes[3..].starts_with(b"id,"));
+    assert_eq!(validate_csv(&config, &config.csv_file).unwrap(), 1);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_validate_csv() {
+    // Test that post-write validation passes a good CSV and rejects an empty id or a drifted header.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("validate_csv");
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), ResourceUrls::default()),
+            (sample_metadata("def"), ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+    assert_eq!(validate_csv(&config, &config.csv_file).unwrap(), 2);
+
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), ResourceUrls::default()),
+            (sample_metadata(""), ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+    let err = validate_csv(&config, &config.csv_file).unwrap_err();
+    assert!(matches!(err, AppError::Other(_)));
+    assert!(err.to_string().contains("row 2 has an empty id"));
+
+    std::fs::write(&config.csv_file, "title,id\nA,abc\n").unwrap();
+    let err = validate_csv(&config, &config.csv_file).unwrap_err();
+    assert!(err.to_string().contains("header does not start"));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_dedupe_formats() {
+    // Test that DEDUPE_FORMATS collapses formats to unique uppercased values and off keeps them raw.
+    let mut data = package_show_json("abc")["result"].clone();
+    data["resources"] = serde_json::json!([
+        { "format": "CSV", "url": "http://example.com/a.csv" },
+        { "format": "csv", "url": "http://example.com/b.csv" },
+        { "format": "JSON", "url": "http://example.com/a.json" }
+    ]);
+    let dataset: CkanDataset = serde_json::from_value(data).unwrap();
+    let (formats, urls) = extract_resource_formats_and_urls(&dataset, &[], true, true);
+    assert_eq!(formats, "CSV, JSON");
+    assert_eq!(urls.urls.len(), 3);
+    let (raw, _) = extract_resource_formats_and_urls(&dataset, &[], true, false);
+    assert_eq!(raw, "CSV, csv, JSON");
+    assert!(Config::new().dedupe_formats);
+}
+
+#[test]
+fn test_url_column_mode_json() {
+    // Test that URL_COLUMN_MODE=json writes the URLs as one JSON array column.
+    let mut config = Config::new();
+    config.url_column_mode = "json".to_string();
+    config.csv_file = temp_csv_path("url_column_json");
+    let urls = ResourceUrls {
+        urls: vec!["http://a".to_string(), "http://b".to_string()],
+        ..Default::default()
+    };
+    write_csv(
+        &config,
+        &[
+            (sample_metadata("abc"), urls),
+            (sample_metadata("def"), ResourceUrls::default()),
+        ],
+    )
+    .unwrap();
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    let header = rdr.headers().unwrap().clone();
+    assert_eq!(header.len(), DatasetMetadata::COLUMNS.len() + 1);
+    assert_eq!(&header[header.len() - 1], "download_urls");
+    assert!(!header.iter().any(|h| h.starts_with("download_url_")));
+    let records: Vec<csv::StringRecord> = rdr.records().map(|r| r.unwrap()).collect();
+    let parsed: Vec<String> = serde_json::from_str(&records[0][header.len() - 1]).unwrap();
+    assert_eq!(parsed, vec!["http://a", "http://b"]);
+    assert_eq!(&records[1][header.len() - 1], "[]");
+    config.url_column_mode = "tall".to_string();
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_writes_partial_output() {
+    // Test that a shutdown signal mid-fetch stops fetching but still writes what was collected.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({ "result": ["abc", "def", "slow"] })),
+        )
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .and(query_param("id", "slow"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(package_show_json("slow"))
+                .set_delay(std::time::Duration::from_secs(30)),
+        )
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("shutdown");
+    config.dry_run = true;
+    let (trigger, shutdown) = shutdown_channel();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        trigger.trigger();
+    });
+    let started = std::time::Instant::now();
+    let summary = process_datasets_until(&config, false, &mock_s3_client(&ckan.uri()), shutdown)
+        .await
+        .unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert!(summary.deadline_reached);
+    assert_eq!(summary.rows_written, 2);
+    assert_eq!(summary.datasets_not_fetched, 1);
+    let mut rdr = csv::Reader::from_path(&config.csv_file).unwrap();
+    assert_eq!(rdr.records().count(), 2);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[test]
+fn test_copy_source_percent_encodes_key() {
+    // Test that the S3 CopySource encodes spaces, `+` and non-ASCII characters but keeps `/`.
+    assert_eq!(
+        copy_source("bucket", "year=2024/month=01/gov data+été.csv"),
+        "bucket/year%3D2024/month%3D01/gov%20data%2B%C3%A9t%C3%A9.csv"
+    );
+    assert_eq!(copy_source("bucket", "gov-data.csv"), "bucket/gov-data.csv");
+}
+
+#[tokio::test]
+async fn test_tag_objects() {
+    // Test that TAG_OBJECTS sends the run id and CKAN source as URL-encoded S3 object tags.
+    let mut config = Config::new();
+    config.csv_file = temp_csv_path("tag_objects");
+    config.ckan_api_base_url = "https://data.gov.uk/api/action".to_string();
+    config.run_id = "run-123".to_string();
+    assert_eq!(object_tagging(&config), None);
+    config.tag_objects = true;
+    let expected = "run_id=run-123&source=https%3A%2F%2Fdata.gov.uk%2Fapi%2Faction";
+    assert_eq!(object_tagging(&config).as_deref(), Some(expected));
+
+    let s3 = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(header("x-amz-tagging", expected))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&s3)
+        .await;
+    std::fs::write(&config.csv_file, "id\nabc\n").unwrap();
+    upload_to_s3(&mock_s3_client(&s3.uri()), &config, &config.csv_file)
+        .await
+        .unwrap();
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_batch_delay_between_batches() {
+    // Test that BATCH_SIZE splits the fetch into batches with BATCH_DELAY_MS between each.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["a", "b", "c", "d", "e"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("batch_delay");
+    config.dry_run = true;
+    config.batch_size = Some(2);
+    config.batch_delay_ms = 300;
+    let s3 = mock_s3_client(&ckan.uri());
+    // Five datasets in batches of two make three batches, so two pauses.
+    let started = std::time::Instant::now();
+    let summary = process_datasets(&config, false, &s3).await.unwrap();
+    assert!(started.elapsed() >= std::time::Duration::from_millis(600));
+    assert_eq!(summary.rows_written, 5);
+
+    config.batch_size = None;
+    let summary = process_datasets(&config, false, &s3).await.unwrap();
+    assert_eq!(summary.rows_written, 5);
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_conditional_get_skips_unchanged_catalogue() {
+    // Test that a 304 for the stored ETag ends the run as unchanged without fetching metadata.
+    let ckan = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .and(header("If-None-Match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .with_priority(1)
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_list"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("ETag", "\"v2\"")
+                .set_body_json(serde_json::json!({ "result": ["abc"] })),
+        )
+        .mount(&ckan)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/package_show"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(package_show_json("abc")))
+        .expect(0)
+        .mount(&ckan)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.use_conditional_get = true;
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/{}/package_list.etag", config.bucket_name)))
+        .respond_with(ResponseTemplate::new(200).set_body_string("\"v1\"\n"))
+        .mount(&s3)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&s3)
+        .await;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert!(summary.catalogue_unchanged);
+    assert_eq!(summary.datasets_listed, 0);
+    assert_eq!(summary.rows_written, 0);
+
+    // Without a matching ETag the list comes back with the new ETag to store.
+    let client = reqwest::Client::new();
+    let list = fetch_dataset_list_if_changed(&client, &config, false, &AtomicU64::new(0), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        list,
+        DatasetList::Changed {
+            ids: vec!["abc".to_string()],
+            etag: Some("\"v2\"".to_string()),
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_startup_jitter_delays_first_request() {
+    // Test that startup jitter stays within its bound and runs before the first CKAN request.
+    assert_eq!(startup_jitter(0), std::time::Duration::ZERO);
+    let samples: Vec<_> = (0..200).map(|_| startup_jitter(50)).collect();
+    assert!(samples
+        .iter()
+        .all(|delay| *delay <= std::time::Duration::from_millis(50)));
+    assert!(samples.iter().any(|delay| *delay != samples[0]));
+
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc"]).await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("startup_jitter");
+    config.dry_run = true;
+    config.startup_jitter_max_ms = 400;
+    let s3 = mock_s3_client(&ckan.uri());
+    // The first request has to arrive within the jitter bound (plus slack for the run itself).
+    let started = std::time::Instant::now();
+    let first_request = async {
+        while ckan.received_requests().await.unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        started.elapsed()
+    };
+    let (result, first_request) =
+        tokio::join!(process_datasets(&config, false, &s3), first_request);
+    result.unwrap();
+    assert!(first_request < std::time::Duration::from_millis(1400));
+    std::fs::remove_file(&config.csv_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_min_expected_datasets_aborts_before_upload() {
+    // Test that a package list below MIN_EXPECTED_DATASETS fails the run without touching S3.
+    let ckan = MockServer::start().await;
+    mount_ckan_datasets(&ckan, &["abc", "def"]).await;
+    let s3 = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&s3)
+        .await;
+    let mut config = Config::new();
+    config.ckan_api_base_url = ckan.uri();
+    config.csv_file = temp_csv_path("min_expected");
+    config.min_expected_datasets = 3;
+    let err = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("below MIN_EXPECTED_DATASETS = 3"));
+    assert!(!std::path::Path::new(&config.csv_file).exists());
+
+    config.min_expected_datasets = 2;
+    config.dry_run = true;
+    let summary = process_datasets(&config, false, &mock_s3_client(&s3.uri()))
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 2);
+    std::fs::remove_file(&config.csv_file).unwrap();
+
+    config.max_datasets = Some(1);
+    assert!(matches!(config.validate(), Err(AppError::Config(_))));
+}
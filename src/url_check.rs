@@ -0,0 +1,78 @@
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use futures::stream::StreamExt;
+use reqwest::header::HeaderValue;
+use reqwest::Client;
+use tracing::{info, warn};
+
+/// Status recorded for a URL whose HEAD request could not be completed.
+const CHECK_FAILED_STATUS: &str = "error";
+
+/// Builds the HTTP client for URL checks. Download URLs point at third-party hosts, so unlike
+/// the CKAN client it sends no `Authorization` header (and doesn't use `CKAN_PROXY_URL`):
+/// only the User-Agent and the configured timeouts are shared.
+pub fn create_url_check_client(config: &Config) -> Result<Client, AppError> {
+    let user_agent = HeaderValue::from_str(&config.user_agent).map_err(|_| {
+        AppError::Config("USER_AGENT contains invalid header characters".to_string())
+    })?;
+    Ok(Client::builder()
+        .user_agent(user_agent)
+        .timeout(std::time::Duration::from_secs(config.http_timeout_secs))
+        .connect_timeout(std::time::Duration::from_secs(
+            config.http_connect_timeout_secs,
+        ))
+        .build()?)
+}
+
+/// Sends a HEAD request to every download URL, up to `url_check_concurrency` at a time,
+/// and records each HTTP status (or `error`) in the dataset's `statuses`.
+/// A failed check never drops the dataset. Returns the number of broken URLs: those that
+/// could not be reached or did not return a success status after redirects.
+pub async fn check_download_urls(
+    client: &Client,
+    config: &Config,
+    dataset_metadata: &mut [(DatasetMetadata, ResourceUrls)],
+) -> usize {
+    let checks: Vec<(usize, usize, String)> = dataset_metadata
+        .iter()
+        .enumerate()
+        .flat_map(|(row, (_, urls))| {
+            urls.urls
+                .iter()
+                .enumerate()
+                .map(move |(i, url)| (row, i, url.clone()))
+        })
+        .collect();
+    info!("Checking {} download URLs...", checks.len());
+    let results = futures::stream::iter(checks)
+        .map(|(row, i, url)| async move {
+            let status = match client.head(&url).send().await {
+                Ok(response) => Some(response.status()),
+                Err(e) => {
+                    warn!("URL check failed for {}: {}", url, e);
+                    None
+                }
+            };
+            (row, i, status)
+        })
+        .buffer_unordered(config.url_check_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (_, urls) in dataset_metadata.iter_mut() {
+        urls.statuses = vec![String::new(); urls.urls.len()];
+    }
+    let mut broken = 0;
+    for (row, i, status) in results {
+        if !status.is_some_and(|s| s.is_success()) {
+            broken += 1;
+        }
+        dataset_metadata[row].1.statuses[i] = status
+            .map(|s| s.as_u16().to_string())
+            .unwrap_or_else(|| CHECK_FAILED_STATUS.to_string());
+    }
+    info!("URL check complete: {} broken URLs", broken);
+    broken
+}
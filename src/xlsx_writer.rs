@@ -0,0 +1,83 @@
+use crate::ckan::ResourceUrls;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::DatasetMetadata;
+use rust_xlsxwriter::{Color, Format, FormatAlign, Url, Workbook, XlsxError};
+
+/// Widest a column is allowed to grow (in characters), so long descriptions stay readable.
+const MAX_COLUMN_WIDTH: usize = 60;
+
+/// Writes the dataset metadata to an Excel workbook at `config.output_file()`.
+/// The header row is bold, shaded and frozen, columns are sized to their contents (capped at
+/// `MAX_COLUMN_WIDTH`), and each download URL is written as a clickable hyperlink in its own
+/// download_url_N column. Multi-line descriptions are wrapped instead of breaking the row.
+pub fn write_xlsx(
+    config: &Config,
+    dataset_metadata: &[(DatasetMetadata, ResourceUrls)],
+) -> Result<(), AppError> {
+    let xlsx_error = |e: XlsxError| AppError::Other(format!("XLSX write failed: {e}"));
+    let max_urls = dataset_metadata
+        .iter()
+        .map(|(_, urls)| urls.urls.len())
+        .max()
+        .unwrap_or(0);
+    let header_format = Format::new()
+        .set_bold()
+        .set_background_color(Color::RGB(0xD9E1F2));
+    let wrap_format = Format::new().set_text_wrap().set_align(FormatAlign::Top);
+
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    worksheet.set_name("Datasets").map_err(xlsx_error)?;
+    let header: Vec<String> = DatasetMetadata::COLUMNS
+        .iter()
+        .map(|c| c.to_string())
+        .chain((1..=max_urls).map(|i| format!("download_url_{i}")))
+        .collect();
+    // Widest cell seen per column, measured on the longest line of each value.
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    let mut fit = |col: usize, value: &str| {
+        let len = value.lines().map(|l| l.chars().count()).max().unwrap_or(0);
+        widths[col] = widths[col].max(len);
+    };
+    for (col, name) in header.iter().enumerate() {
+        worksheet
+            .write_string_with_format(0, col as u16, name, &header_format)
+            .map_err(xlsx_error)?;
+    }
+    worksheet.set_freeze_panes(1, 0).map_err(xlsx_error)?;
+
+    for (i, (meta, urls)) in dataset_metadata.iter().enumerate() {
+        let row = i as u32 + 1;
+        for (col, value) in meta.values().iter().enumerate() {
+            fit(col, value);
+            if value.contains('\n') {
+                worksheet
+                    .write_string_with_format(row, col as u16, value, &wrap_format)
+                    .map_err(xlsx_error)?;
+            } else {
+                worksheet
+                    .write_string(row, col as u16, value)
+                    .map_err(xlsx_error)?;
+            }
+        }
+        for (j, url) in urls.urls.iter().enumerate() {
+            fit(DatasetMetadata::COLUMNS.len() + j, url);
+            let col = (DatasetMetadata::COLUMNS.len() + j) as u16;
+            // Excel rejects some URLs (too long, too many links); fall back to plain text.
+            if worksheet.write_url(row, col, Url::new(url)).is_err() {
+                worksheet.write_string(row, col, url).map_err(xlsx_error)?;
+            }
+        }
+    }
+
+    for (col, width) in widths.iter().enumerate() {
+        // A little padding so text does not touch the cell border.
+        let width = (*width).min(MAX_COLUMN_WIDTH) + 2;
+        worksheet
+            .set_column_width(col as u16, width as f64)
+            .map_err(xlsx_error)?;
+    }
+    workbook.save(config.output_file()).map_err(xlsx_error)?;
+    Ok(())
+}